@@ -0,0 +1,1091 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+
+use crate::display::image::{ImageFormat, Snapshotter};
+use crate::debugger::debugger::Debugger;
+use crate::display::terminal::{Viewer, ViewerMode};
+use crate::error::error::{format_errors, Error};
+use crate::hardware::cpu::{Cpu, HALT};
+use crate::hardware::fast::FastCpu;
+use crate::hardware::input::ScriptedKeyboard;
+use crate::hardware::machine::{Machine, RunOptions, RunOutcome, StopReason};
+use crate::hardware::state::MachineState;
+use crate::hardware::trace::{
+    first_divergence,
+    read_binary_trace,
+    read_json_trace,
+    BinaryTracer,
+    JsonLinesTracer,
+    TraceRecord,
+};
+use crate::jack::compiler::compile_program;
+use crate::jack::os;
+use crate::jack::parser::Parser;
+use crate::jack::tokenizer::Tokenizer;
+use crate::parser::assembly::{Assembler, SourceLine};
+use crate::parser::disassembler;
+use crate::parser::symbols::Symbols;
+use crate::stack::stack::{Stack, VmFile};
+
+const DEFAULT_CYCLES: u64 = 100_000;
+// Sys.init takes about a million cycles to set the OS up before Main.main runs
+const OS_DEFAULT_CYCLES: u64 = 10_000_000;
+
+pub const USAGE: &str = "\
+Usage:
+    rust2tetris asm <file.asm> [-o <file.hack>] [--listing <file.lst>] [--symbols <file.sym>]
+    rust2tetris vm <file.vm | file.jack | directory> [-o <file.asm>]
+    rust2tetris run <file.hack | file.asm | file.vm | file.jack | directory> [--cycles <n>] [--timeout <ms>]
+        [--no-loop-detection] [--keys <script>] [--trace <file.trace | file.jsonl>] [--reference]
+        [--save-state <file.state>]
+        [--snapshot-dir <dir>] [--snapshot-every <n>] [--snapshot-format png|pgm|pbm]
+    rust2tetris view <file.hack | file.asm | file.vm | file.jack | directory> [--cycles <n>]
+        [--fps <n>] [--half-blocks [--scale <n>]] [--reference]
+    rust2tetris debug <file.hack | file.asm | file.vm | file.jack | directory> [--symbols <file.sym>]
+        [--reference]
+    rust2tetris disasm <file.hack> [-o <file.asm>] [--symbols <file.sym>]
+    rust2tetris tracediff <trace> <trace>
+    rust2tetris analyze <file.jack | directory> [-o <directory>]
+    rust2tetris jack <file.jack | directory> [-o <directory>]
+
+    run stops at the HALT sentinel or at an `(END) @END 0;JMP` style loop, unless
+    --no-loop-detection is given. --reference runs the gate-level Cpu instead of the fast core.
+    --trace records every instruction, as JSON Lines for .jsonl files and compact binary otherwise.
+    --save-state writes the machine where it stopped, and run, view and debug resume .state files.
+    Without --cycles, run stops after 100000 cycles, or 10000000 for programs linked with the OS.
+
+    asm --symbols writes the labels and variables of the program, disasm and debug read them.
+    analyze writes the tokens of every Jack file as <Name>T.xml and its parse tree as <Name>.xml,
+    next to it unless -o is given. jack compiles every Jack file to <Name>.vm the same way.
+    vm, run, view and debug compile Jack programs and link in the OS classes a program calls";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Asm { input: PathBuf, output: Option<PathBuf>, listing: Option<PathBuf>, symbols: Option<PathBuf> },
+    Vm { input: PathBuf, output: Option<PathBuf> },
+    Run {
+        input: PathBuf,
+        limits: RunOptions,
+        keys: Option<PathBuf>,
+        trace: Option<PathBuf>,
+        save_state: Option<PathBuf>,
+        snapshots: Option<SnapshotOptions>,
+        reference: bool,
+    },
+    View { input: PathBuf, cycles: u64, fps: u32, mode: ViewerMode, scale: usize, reference: bool },
+    Debug { input: PathBuf, symbols: Option<PathBuf>, reference: bool },
+    Disasm { input: PathBuf, output: Option<PathBuf>, symbols: Option<PathBuf> },
+    TraceDiff { left: PathBuf, right: PathBuf },
+    Analyze { input: PathBuf, output: Option<PathBuf> },
+    Jack { input: PathBuf, output: Option<PathBuf> },
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SnapshotOptions {
+    pub dir: PathBuf,
+    pub every: Option<u64>,
+    pub format: ImageFormat,
+}
+
+#[derive(Debug)]
+pub enum CliError {
+    Usage(String),
+    Io(PathBuf, io::Error),
+    Input(String),
+    Diagnostics(Vec<Error>),
+}
+
+impl CliError {
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            CliError::Usage(_) => ExitCode::from(2),
+            _ => ExitCode::FAILURE,
+        }
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Usage(message) => write!(f, "{}\n\n{}", message, USAGE),
+            CliError::Io(path, err) => write!(f, "{}: {}", path.display(), err),
+            CliError::Input(message) => write!(f, "{}", message),
+            CliError::Diagnostics(errors) => write!(f, "{}", format_errors(errors)),
+        }
+    }
+}
+
+pub fn parse_args(args: &[String]) -> Result<Command, CliError> {
+    let (subcommand, rest) = args.split_first()
+        .ok_or_else(|| CliError::Usage("Missing subcommand".to_string()))?;
+
+    if !["asm", "vm", "run", "view", "debug", "disasm", "tracediff", "analyze", "jack"].contains(&subcommand.as_str()) {
+        return Err(CliError::Usage(format!("Unknown subcommand: {}", subcommand)));
+    }
+
+    let mut inputs = vec![];
+    let mut output = None;
+    let mut cycles = None;
+    let mut timeout = None;
+    let mut detect_loops = true;
+    let mut keys = None;
+    let mut trace = None;
+    let mut save_state = None;
+    let mut listing = None;
+    let mut symbols = None;
+    let mut reference = false;
+    let mut fps = 20;
+    let mut mode = ViewerMode::Braille;
+    let mut scale = 2;
+    let mut snapshot_dir = None;
+    let mut snapshot_every = None;
+    let mut snapshot_format = ImageFormat::Png;
+
+    let mut iter = rest.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-o" | "--output" => {
+                let value = iter.next()
+                    .ok_or_else(|| CliError::Usage(format!("{} expects a path", arg)))?;
+                output = Some(PathBuf::from(value));
+            }
+            "--cycles" => {
+                cycles = Some(parse_number(arg, iter.next())?);
+            }
+            "--timeout" => {
+                timeout = Some(Duration::from_millis(parse_number(arg, iter.next())?));
+            }
+            "--no-loop-detection" => {
+                detect_loops = false;
+            }
+            "--keys" => {
+                let value = iter.next()
+                    .ok_or_else(|| CliError::Usage(format!("{} expects a path", arg)))?;
+                keys = Some(PathBuf::from(value));
+            }
+            "--trace" => {
+                let value = iter.next()
+                    .ok_or_else(|| CliError::Usage(format!("{} expects a path", arg)))?;
+                trace = Some(PathBuf::from(value));
+            }
+            "--listing" => {
+                let value = iter.next()
+                    .ok_or_else(|| CliError::Usage(format!("{} expects a path", arg)))?;
+                listing = Some(PathBuf::from(value));
+            }
+            "--symbols" => {
+                let value = iter.next()
+                    .ok_or_else(|| CliError::Usage(format!("{} expects a path", arg)))?;
+                symbols = Some(PathBuf::from(value));
+            }
+            "--save-state" => {
+                let value = iter.next()
+                    .ok_or_else(|| CliError::Usage(format!("{} expects a path", arg)))?;
+                save_state = Some(PathBuf::from(value));
+            }
+            "--fps" => {
+                fps = parse_number(arg, iter.next())? as u32;
+            }
+            "--reference" => {
+                reference = true;
+            }
+            "--half-blocks" => {
+                mode = ViewerMode::HalfBlock;
+            }
+            "--scale" => {
+                scale = parse_number(arg, iter.next())? as usize;
+            }
+            "--snapshot-dir" => {
+                let value = iter.next()
+                    .ok_or_else(|| CliError::Usage(format!("{} expects a path", arg)))?;
+                snapshot_dir = Some(PathBuf::from(value));
+            }
+            "--snapshot-every" => {
+                snapshot_every = Some(parse_number(arg, iter.next())?);
+            }
+            "--snapshot-format" => {
+                let value = iter.next()
+                    .ok_or_else(|| CliError::Usage(format!("{} expects a format", arg)))?;
+                snapshot_format = ImageFormat::from_extension(value)
+                    .ok_or_else(|| CliError::Usage(format!("Unknown image format: {}", value)))?;
+            }
+            flag if flag.starts_with('-') => {
+                return Err(CliError::Usage(format!("Unknown option: {}", flag)));
+            }
+            path => {
+                // Only tracediff takes two inputs
+                let expected = if subcommand == "tracediff" { 2 } else { 1 };
+                if inputs.len() == expected {
+                    return Err(CliError::Usage(format!("Unexpected argument: {}", path)));
+                }
+                inputs.push(PathBuf::from(path));
+            }
+        }
+    }
+
+    if subcommand == "tracediff" {
+        let [left, right]: [PathBuf; 2] = inputs.try_into()
+            .map_err(|_| CliError::Usage("tracediff expects two trace files".to_string()))?;
+        return Ok(Command::TraceDiff { left, right });
+    }
+    let input = inputs.pop()
+        .ok_or_else(|| CliError::Usage(format!("{} expects an input file", subcommand)))?;
+
+    match subcommand.as_str() {
+        "asm" => Ok(Command::Asm { input, output, listing, symbols }),
+        "vm" => Ok(Command::Vm { input, output }),
+        "run" => {
+            let snapshots = snapshot_dir.map(|dir| SnapshotOptions {
+                dir,
+                every: snapshot_every,
+                format: snapshot_format,
+            });
+            // Without --cycles, the budget depends on the program run_program loads
+            let limits = RunOptions {
+                max_cycles: cycles,
+                timeout,
+                detect_loops,
+            };
+            Ok(Command::Run { input, limits, keys, trace, save_state, snapshots, reference })
+        }
+        // Interactive programs run until the user quits
+        "view" => Ok(Command::View { input, cycles: cycles.unwrap_or(u64::MAX), fps, mode, scale, reference }),
+        "debug" => Ok(Command::Debug { input, symbols, reference }),
+        "analyze" => Ok(Command::Analyze { input, output }),
+        "jack" => Ok(Command::Jack { input, output }),
+        _ => Ok(Command::Disasm { input, output, symbols }),
+    }
+}
+
+fn parse_number(flag: &str, value: Option<&String>) -> Result<u64, CliError> {
+    let value = value.ok_or_else(|| CliError::Usage(format!("{} expects a number", flag)))?;
+    value.parse()
+        .map_err(|_| CliError::Usage(format!("Invalid number for {}: {}", flag, value)))
+}
+
+pub fn run(args: &[String]) -> Result<(), CliError> {
+    match parse_args(args)? {
+        Command::Asm { input, output, listing, symbols } => {
+            let asm = assemble_file(&input)?;
+            if let Some(listing) = listing {
+                write_lines(&listing, &asm.listing())?;
+            }
+            if let Some(symbols) = symbols {
+                fs::write(&symbols, asm.symbols().to_string()).map_err(|err| CliError::Io(symbols, err))?;
+            }
+            let output = output.unwrap_or_else(|| input.with_extension("hack"));
+            write_lines(&output, &asm.binaries)
+        }
+
+        Command::Vm { input, output } => {
+            let assembly = translate_path(&input)?;
+            let output = output.unwrap_or_else(|| default_vm_output(&input));
+            write_lines(&output, &assembly)
+        }
+
+        Command::Disasm { input, output, symbols } => {
+            let contents = read_file(&input)?;
+            let symbols = match symbols {
+                Some(path) => read_symbols(&path)?,
+                None => Symbols::new(),
+            };
+            let assembly = disassemble(&contents, &symbols)?;
+            let output = output.unwrap_or_else(|| input.with_extension("asm"));
+            write_lines(&output, &assembly)
+        }
+
+        Command::Run { input, limits, keys, trace, save_state, snapshots, reference } => {
+            let outputs = RunOutputs { trace, save_state, snapshots };
+            if reference {
+                run_program(&mut Cpu::new(), &input, &limits, keys, outputs)?;
+            } else {
+                run_program(&mut FastCpu::new(), &input, &limits, keys, outputs)?;
+            }
+            Ok(())
+        }
+
+        Command::Analyze { input, output } => analyze_path(&input, output.as_deref()),
+
+        Command::Jack { input, output } => compile_path(&input, output.as_deref()),
+
+        Command::TraceDiff { left, right } => {
+            let (left_records, right_records) = (read_trace(&left)?, read_trace(&right)?);
+            match first_divergence(&left_records, &right_records) {
+                None => println!("Traces match ({} instructions)", left_records.len()),
+                Some(i) => {
+                    println!("Traces diverge at record {}", i);
+                    for (path, records) in [(&left, &left_records), (&right, &right_records)] {
+                        match records.get(i) {
+                            Some(record) => println!("{}: {}", path.display(), record.to_json()),
+                            None => println!("{}: ended after {} records", path.display(), records.len()),
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        Command::Debug { input, symbols, reference } => {
+            if reference {
+                debug_program(Cpu::new(), &input, symbols)
+            } else {
+                debug_program(FastCpu::new(), &input, symbols)
+            }
+        }
+
+        Command::View { input, cycles, fps, mode, scale, reference } => {
+            let mut viewer = Viewer::new();
+            viewer.fps = fps;
+            viewer.mode = mode;
+            viewer.scale = scale;
+            if reference {
+                view_program(&mut Cpu::new(), &input, cycles, &viewer)
+            } else {
+                view_program(&mut FastCpu::new(), &input, cycles, &viewer)
+            }
+        }
+    }
+}
+
+// Saved machine states resume where they were left, without labels
+fn load_machine<M: Machine>(cpu: &mut M, input: &Path) -> Result<Program, CliError> {
+    if extension(input) == "state" {
+        cpu.restore_state(&read_state(input)?);
+        return Ok(Program { binaries: vec![], symbols: Symbols::new(), source_map: vec![], linked: vec![] });
+    }
+    let program = load_program(input)?;
+    cpu.load_from_string(&program.binaries.join("\n"))
+        .map_err(|errors| with_file(errors, input))?;
+    Ok(program)
+}
+
+// Files `run` writes besides its report
+struct RunOutputs {
+    trace: Option<PathBuf>,
+    save_state: Option<PathBuf>,
+    snapshots: Option<SnapshotOptions>,
+}
+
+fn run_program<M: Machine>(
+    cpu: &mut M,
+    input: &Path,
+    run_options: &RunOptions,
+    keys: Option<PathBuf>,
+    outputs: RunOutputs,
+) -> Result<RunOutcome, CliError> {
+    let RunOutputs { trace, save_state, snapshots } = outputs;
+    let program = load_machine(cpu, input)?;
+    // When --cycles isn't given
+    let default_cycles = if program.linked.is_empty() { DEFAULT_CYCLES } else { OS_DEFAULT_CYCLES };
+    let run_options = &RunOptions { max_cycles: run_options.max_cycles.or(Some(default_cycles)), ..run_options.clone() };
+    if let Some(keys) = keys {
+        let script = ScriptedKeyboard::parse(&read_file(&keys)?)
+            .map_err(|errors| with_file(errors, &keys))?;
+        cpu.set_keyboard_source(Box::new(script));
+    }
+    if let Some(path) = &trace {
+        let file = fs::File::create(path).map_err(|err| CliError::Io(path.clone(), err))?;
+        let out = io::BufWriter::new(file);
+        if extension(path) == "jsonl" {
+            cpu.set_tracer(Box::new(JsonLinesTracer::new(out)));
+        } else {
+            cpu.set_tracer(Box::new(BinaryTracer::new(out)));
+        }
+    }
+
+    let outcome = match snapshots {
+        Some(options) => {
+            let mut snapshotter = Snapshotter::new(&options.dir, options.format);
+            snapshotter.every = options.every;
+            snapshotter.run(cpu, run_options)
+                .map_err(|err| CliError::Io(options.dir.clone(), err))?
+        }
+        None => cpu.run(run_options),
+    };
+    if let (Some(path), Some(mut tracer)) = (trace, cpu.take_tracer()) {
+        tracer.finish().map_err(|err| CliError::Io(path, err))?;
+    }
+    if let Some(path) = save_state {
+        fs::write(&path, cpu.save_state().to_bytes()).map_err(|err| CliError::Io(path, err))?;
+    }
+    match (&outcome.reason, program.source_map.get(cpu.get_pc() as usize)) {
+        (StopReason::Faulted(_), Some(source)) => println!("{} (at PC {}, {})", outcome, cpu.get_pc(), source),
+        (StopReason::Faulted(_), None) => println!("{} (at PC {})", outcome, cpu.get_pc()),
+        _ => println!("{}", outcome),
+    }
+    print_state(cpu);
+    Ok(outcome)
+}
+
+// A symbol file replaces the symbols of a program assembled from source
+fn debug_program<M: Machine>(mut cpu: M, input: &Path, symbols: Option<PathBuf>) -> Result<(), CliError> {
+    let program = load_machine(&mut cpu, input)?;
+    let symbols = match symbols {
+        Some(path) => read_symbols(&path)?,
+        None => program.symbols,
+    };
+    let mut debugger = Debugger::new(cpu, symbols);
+    debugger.source_map = program.source_map;
+    println!("Debugging {}, type help for commands", input.display());
+    debugger.repl(&mut io::stdin().lock(), &mut io::stdout())
+        .map_err(|err| CliError::Io(input.to_path_buf(), err))
+}
+
+fn view_program<M: Machine>(cpu: &mut M, input: &Path, cycles: u64, viewer: &Viewer) -> Result<(), CliError> {
+    let program = load_machine(cpu, input)?;
+    viewer.run(cpu, cycles)
+        .map_err(|err| CliError::Io(input.to_path_buf(), err))?;
+
+    if let Some(fault) = cpu.fault() {
+        match program.source_map.get(cpu.get_pc() as usize) {
+            Some(source) => println!("Faulted at PC {} ({}): {}", cpu.get_pc(), source, fault),
+            None => println!("Faulted at PC {}: {}", cpu.get_pc(), fault),
+        }
+    }
+    Ok(())
+}
+
+fn read_symbols(path: &Path) -> Result<Symbols, CliError> {
+    Symbols::parse(&read_file(path)?).map_err(|errors| with_file(errors, path))
+}
+
+fn read_state(path: &Path) -> Result<MachineState, CliError> {
+    let bytes = fs::read(path).map_err(|err| CliError::Io(path.to_path_buf(), err))?;
+    MachineState::from_bytes(&bytes)
+        .map_err(|message| CliError::Input(format!("{}: {}", path.display(), message)))
+}
+
+fn read_trace(path: &Path) -> Result<Vec<TraceRecord>, CliError> {
+    let records = if extension(path) == "jsonl" {
+        read_json_trace(&read_file(path)?)
+    } else {
+        read_binary_trace(&fs::read(path).map_err(|err| CliError::Io(path.to_path_buf(), err))?)
+    };
+    records.map_err(|message| CliError::Input(format!("{}: {}", path.display(), message)))
+}
+
+fn read_file(path: &Path) -> Result<String, CliError> {
+    fs::read_to_string(path).map_err(|err| CliError::Io(path.to_path_buf(), err))
+}
+
+fn write_lines(path: &Path, lines: &[String]) -> Result<(), CliError> {
+    let mut contents = lines.join("\n");
+    contents.push('\n');
+    fs::write(path, contents).map_err(|err| CliError::Io(path.to_path_buf(), err))
+}
+
+fn extension(path: &Path) -> &str {
+    path.extension().and_then(|ext| ext.to_str()).unwrap_or("")
+}
+
+fn file_stem(path: &Path) -> String {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("Static")
+        .to_string()
+}
+
+fn default_vm_output(input: &Path) -> PathBuf {
+    if input.is_dir() {
+        input.join(format!("{}.asm", file_stem(input)))
+    } else {
+        input.with_extension("asm")
+    }
+}
+
+// Errors from stages that don't know which file they are reading
+fn with_file(mut errors: Vec<Error>, path: &Path) -> CliError {
+    for error in errors.iter_mut() {
+        if error.location.file.is_empty() {
+            error.location.file = path.display().to_string();
+        }
+    }
+    CliError::Diagnostics(errors)
+}
+
+// Without the trailing HALT sentinel the assembler appends for the Cpu
+fn assemble_source(contents: &str, file_name: &str) -> Result<Assembler, CliError> {
+    let mut asm = Assembler::new();
+    asm.file_name = file_name.to_string();
+    asm.assemble_all(contents).map_err(CliError::Diagnostics)?;
+    asm.binaries.pop();
+    Ok(asm)
+}
+
+fn assemble_file(path: &Path) -> Result<Assembler, CliError> {
+    let contents = read_file(path)?;
+    assemble_source(&contents, &path.display().to_string())
+}
+
+// The file itself, or the files in the directory with the extension, sorted
+fn source_paths(path: &Path, wanted: &str) -> Result<Vec<PathBuf>, CliError> {
+    let paths = if path.is_dir() {
+        let entries = fs::read_dir(path).map_err(|err| CliError::Io(path.to_path_buf(), err))?;
+        let mut paths = vec![];
+        for entry in entries {
+            let entry = entry.map_err(|err| CliError::Io(path.to_path_buf(), err))?;
+            let entry_path = entry.path();
+            if extension(&entry_path) == wanted {
+                paths.push(entry_path);
+            }
+        }
+        paths.sort();
+        paths
+    } else {
+        vec![path.to_path_buf()]
+    };
+
+    if paths.is_empty() {
+        return Err(CliError::Input(format!("No .{} files found in {}", wanted, path.display())));
+    }
+    Ok(paths)
+}
+
+// Jack files, or directories of them without any .vm files, are compiled first
+pub fn read_vm_files(path: &Path) -> Result<Vec<VmFile>, CliError> {
+    let is_jack = match extension(path) {
+        "jack" => true,
+        _ => path.is_dir() && source_paths(path, "vm").is_err() && source_paths(path, "jack").is_ok(),
+    };
+    if is_jack {
+        return compile_program(&read_jack_files(path)?).map_err(CliError::Diagnostics);
+    }
+
+    source_paths(path, "vm")?.iter()
+        .map(|vm_path| Ok(VmFile::from_source(&file_stem(vm_path), &read_file(vm_path)?)))
+        .collect()
+}
+
+// Reports the errors of every file before giving up
+fn analyze_path(input: &Path, output: Option<&Path>) -> Result<(), CliError> {
+    let mut errors = vec![];
+    for path in source_paths(input, "jack")? {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.file_name = path.display().to_string();
+        if let Err(file_errors) = tokenizer.tokenize(&read_file(&path)?) {
+            errors.extend(file_errors);
+            continue;
+        }
+        let mut parser = Parser::new();
+        parser.file_name = tokenizer.file_name.clone();
+        let class = match parser.parse_tokens(tokenizer.tokens.clone()) {
+            Ok(class) => class,
+            Err(file_errors) => {
+                errors.extend(file_errors);
+                continue;
+            }
+        };
+
+        let dir = output.or(path.parent()).unwrap_or(Path::new(""));
+        for (name, xml) in [(format!("{}T.xml", file_stem(&path)), tokenizer.to_xml()), (format!("{}.xml", file_stem(&path)), class.to_xml())] {
+            let xml_path = dir.join(name);
+            fs::write(&xml_path, xml).map_err(|err| CliError::Io(xml_path, err))?;
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(CliError::Diagnostics(errors))
+    }
+}
+
+fn read_jack_files(path: &Path) -> Result<Vec<(String, String)>, CliError> {
+    source_paths(path, "jack")?.iter()
+        .map(|jack_path| Ok((jack_path.display().to_string(), read_file(jack_path)?)))
+        .collect()
+}
+
+// The files are compiled together, so calls between them are checked
+fn compile_path(input: &Path, output: Option<&Path>) -> Result<(), CliError> {
+    let paths = source_paths(input, "jack")?;
+    let files = compile_program(&read_jack_files(input)?).map_err(CliError::Diagnostics)?;
+
+    for (path, file) in paths.iter().zip(files) {
+        let dir = output.or(path.parent()).unwrap_or(Path::new(""));
+        write_lines(&dir.join(format!("{}.vm", file_stem(path))), &file.commands)?;
+    }
+    Ok(())
+}
+
+// Programs that define Sys.init, or a Main.main for the OS's Sys.init to
+// start, get the bootstrap code, anything else is translated as is. The OS
+// classes a program uses are linked in, sharing one copy of the call and
+// return code with the program so it fits in the ROM
+pub fn translate_path(path: &Path) -> Result<Vec<String>, CliError> {
+    translate_and_link(path).map(|(assembly, _)| assembly)
+}
+
+// The assembly of the program and the OS classes linked into it
+fn translate_and_link(path: &Path) -> Result<(Vec<String>, Vec<String>), CliError> {
+    let mut files = read_vm_files(path)?;
+    let bootstrap = os::defines(&files, "Sys.init") || os::defines(&files, "Main.main");
+    let linked = os::link(&mut files);
+
+    let mut stack = Stack::new();
+    stack.shared_routines = !linked.is_empty();
+    let result = if bootstrap {
+        stack.assemble_program(&files)
+    } else {
+        stack.assemble_files(&files)
+    };
+    result.map_err(CliError::Diagnostics)?;
+    Ok((stack.assembly, linked))
+}
+
+pub fn disassemble(contents: &str, symbols: &Symbols) -> Result<Vec<String>, CliError> {
+    let rom = contents.lines()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(i, line)| {
+            u16::from_str_radix(line, 2)
+                .map_err(|_| CliError::Input(format!("Invalid binary '{}' on line {}", line, i + 1)))
+        })
+        .collect::<Result<Vec<u16>, CliError>>()?;
+    Ok(disassembler::disassemble(&rom, symbols))
+}
+
+// Machine code for the Cpu, with its labels and variables, where each
+// instruction came from when built from source, and the OS classes linked in
+struct Program {
+    binaries: Vec<String>,
+    symbols: Symbols,
+    source_map: Vec<SourceLine>,
+    linked: Vec<String>,
+}
+
+fn load_program(path: &Path) -> Result<Program, CliError> {
+    let (asm, linked) = match extension(path) {
+        "hack" => {
+            let mut binaries: Vec<String> = read_file(path)?.lines().map(String::from).collect();
+            binaries.push(format!("{:016b}", HALT));
+            return Ok(Program { binaries, symbols: Symbols::new(), source_map: vec![], linked: vec![] });
+        }
+        "asm" => (assemble_file(path)?, vec![]),
+        "vm" | "jack" => {
+            let (assembly, linked) = translate_and_link(path)?;
+            (assemble_source(&assembly.join("\n"), "")?, linked)
+        }
+        _ if path.is_dir() => {
+            let (assembly, linked) = translate_and_link(path)?;
+            (assemble_source(&assembly.join("\n"), "")?, linked)
+        }
+        _ => {
+            return Err(CliError::Input(format!("Don't know how to run {}", path.display())));
+        }
+    };
+
+    let mut binaries = asm.binaries.clone();
+    binaries.push(format!("{:016b}", HALT));
+    Ok(Program { binaries, symbols: asm.symbols(), source_map: asm.source_map, linked })
+}
+
+fn print_state<M: Machine>(cpu: &M) {
+    println!("A:  {}", cpu.get_a());
+    println!("D:  {}", cpu.get_d());
+    println!("PC: {}", cpu.get_pc());
+    for address in 0..16 {
+        println!("RAM[{}]: {}", address, cpu.read_data(address).unwrap());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::symbols::SymbolKind;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rust2tetris_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_parse_args_subcommands() {
+        assert_eq!(
+            parse_args(&args(&["asm", "Add.asm"])).unwrap(),
+            Command::Asm { input: "Add.asm".into(), output: None, listing: None, symbols: None },
+        );
+        assert_eq!(
+            parse_args(&args(&["vm", "dir", "-o", "out.asm"])).unwrap(),
+            Command::Vm { input: "dir".into(), output: Some("out.asm".into()) },
+        );
+        assert_eq!(
+            parse_args(&args(&["run", "Prog.vm", "--cycles", "500", "--timeout", "2000", "--keys", "keys.txt"])).unwrap(),
+            Command::Run {
+                input: "Prog.vm".into(),
+                limits: RunOptions {
+                    max_cycles: Some(500),
+                    timeout: Some(Duration::from_secs(2)),
+                    detect_loops: true,
+                },
+                keys: Some("keys.txt".into()),
+                trace: None,
+                save_state: None,
+                snapshots: None,
+                reference: false,
+            },
+        );
+        assert_eq!(
+            parse_args(&args(&["run", "Pong.asm", "--snapshot-dir", "frames", "--snapshot-every", "1000", "--snapshot-format", "pgm"])).unwrap(),
+            Command::Run {
+                input: "Pong.asm".into(),
+                limits: RunOptions::new(),
+                keys: None,
+                trace: None,
+                save_state: None,
+                snapshots: Some(SnapshotOptions { dir: "frames".into(), every: Some(1000), format: ImageFormat::Pgm }),
+                reference: false,
+            },
+        );
+        assert_eq!(
+            parse_args(&args(&["view", "Pong.asm", "--fps", "30", "--half-blocks", "--scale", "1", "--reference"])).unwrap(),
+            Command::View {
+                input: "Pong.asm".into(),
+                cycles: u64::MAX,
+                fps: 30,
+                mode: ViewerMode::HalfBlock,
+                scale: 1,
+                reference: true,
+            },
+        );
+        assert_eq!(
+            parse_args(&args(&["disasm", "Add.hack"])).unwrap(),
+            Command::Disasm { input: "Add.hack".into(), output: None, symbols: None },
+        );
+        assert_eq!(
+            parse_args(&args(&["debug", "Prog.hack", "--symbols", "Prog.sym"])).unwrap(),
+            Command::Debug { input: "Prog.hack".into(), symbols: Some("Prog.sym".into()), reference: false },
+        );
+        assert_eq!(
+            parse_args(&args(&["tracediff", "a.trace", "b.jsonl"])).unwrap(),
+            Command::TraceDiff { left: "a.trace".into(), right: "b.jsonl".into() },
+        );
+        assert_eq!(
+            parse_args(&args(&["jack", "Pong", "-o", "build"])).unwrap(),
+            Command::Jack { input: "Pong".into(), output: Some("build".into()) },
+        );
+    }
+
+    #[test]
+    fn test_parse_args_errors() {
+        assert!(matches!(parse_args(&args(&[])), Err(CliError::Usage(_))));
+        assert!(matches!(parse_args(&args(&["asm"])), Err(CliError::Usage(_))));
+        assert!(matches!(parse_args(&args(&["frob", "x"])), Err(CliError::Usage(_))));
+        assert!(matches!(parse_args(&args(&["run", "x", "--cycles", "many"])), Err(CliError::Usage(_))));
+        assert!(matches!(parse_args(&args(&["run", "x", "--snapshot-format", "gif"])), Err(CliError::Usage(_))));
+        assert!(matches!(parse_args(&args(&["run", "x", "y"])), Err(CliError::Usage(_))));
+        assert!(matches!(parse_args(&args(&["tracediff", "a.trace"])), Err(CliError::Usage(_))));
+    }
+
+    #[test]
+    fn test_analyze_writes_xml() {
+        let dir = temp_dir("analyze");
+        fs::write(dir.join("Main.jack"), "class Main {\n  function void main() { return; }\n}\n").unwrap();
+        fs::write(dir.join("Bad.jack"), "class Bad { field int x$; }\n").unwrap();
+        fs::write(dir.join("Worse.jack"), "class Worse { field int; }\n").unwrap();
+        fs::write(dir.join("Notes.txt"), "not Jack").unwrap();
+
+        let err = run(&args(&["analyze", dir.to_str().unwrap()])).unwrap_err();
+        assert!(err.to_string().contains("Bad.jack:1:24: Invalid token: $\n"), "{}", err);
+        assert!(err.to_string().ends_with("Worse.jack:1:24: Expected a variable name but found ';'"), "{}", err);
+
+        fs::remove_file(dir.join("Bad.jack")).unwrap();
+        fs::remove_file(dir.join("Worse.jack")).unwrap();
+        let out = dir.join("out");
+        fs::create_dir_all(&out).unwrap();
+        run(&args(&["analyze", dir.to_str().unwrap(), "-o", out.to_str().unwrap()])).unwrap();
+        let xml = fs::read_to_string(out.join("MainT.xml")).unwrap();
+        assert!(xml.starts_with("<tokens>\n<keyword> class </keyword>\n<identifier> Main </identifier>\n"));
+        assert_eq!(xml.lines().count(), 15);
+        let tree = fs::read_to_string(out.join("Main.xml")).unwrap();
+        assert!(tree.starts_with("<class>\n  <keyword> class </keyword>\n"));
+        assert!(tree.contains("    <parameterList>\n    </parameterList>\n"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_jack_writes_vm() {
+        let dir = temp_dir("jack");
+        fs::write(dir.join("Main.jack"), "class Main {\n  function int main() { return 1 + 2; }\n}\n").unwrap();
+        fs::write(dir.join("Calls.jack"), "class Calls {\n  function int f() { return Main.main(3); }\n}\n").unwrap();
+        fs::write(dir.join("Bad.jack"), "class Bad {\n  function void f() { let x = 1; return; }\n}\n").unwrap();
+
+        let err = run(&args(&["jack", dir.to_str().unwrap()])).unwrap_err();
+        assert!(err.to_string().contains("Bad.jack:2:27: Undeclared variable: x\n"), "{}", err);
+        assert!(err.to_string().ends_with("Calls.jack:2:29: Main.main takes 0 arguments but was given 1"), "{}", err);
+
+        fs::remove_file(dir.join("Bad.jack")).unwrap();
+        fs::remove_file(dir.join("Calls.jack")).unwrap();
+        run(&args(&["jack", dir.join("Main.jack").to_str().unwrap()])).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("Main.vm")).unwrap(),
+            "function Main.main 0\npush constant 1\npush constant 2\nadd\nreturn\n",
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_bad_key_script_reports_diagnostics() {
+        let dir = temp_dir("keys");
+        let program = dir.join("Prog.asm");
+        let keys = dir.join("keys.txt");
+        fs::write(&program, "@KBD\nD=M\n").unwrap();
+        fs::write(&keys, "10 press A\nlater release\n").unwrap();
+
+        let result = run(&args(&["run", program.to_str().unwrap(), "--keys", keys.to_str().unwrap()]));
+        match result {
+            Err(CliError::Diagnostics(errors)) => {
+                assert_eq!(errors.len(), 1);
+                assert_eq!(errors[0].location.file, keys.display().to_string());
+                assert_eq!(errors[0].location.line, 2);
+            }
+            other => panic!("expected diagnostics, got {:?}", other),
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_traces_from_both_cores_match() {
+        let dir = temp_dir("trace");
+        let program = dir.join("Prog.asm");
+        let (fast, reference) = (dir.join("fast.trace"), dir.join("reference.jsonl"));
+        fs::write(&program, "@5\nD=A\n@R13\nM=D\n(LOOP)\n@R13\nM=M-1\nD=M\n@LOOP\nD;JGT\n").unwrap();
+
+        let program = program.to_str().unwrap();
+        run(&args(&["run", program, "--trace", fast.to_str().unwrap()])).unwrap();
+        run(&args(&["run", program, "--reference", "--trace", reference.to_str().unwrap()])).unwrap();
+
+        let records = read_trace(&fast).unwrap();
+        assert_eq!(records.len(), 4 + 5 * 5);
+        assert_eq!(records[3].write, Some((13, 5)));
+        assert!(records[8].jumped);
+        assert!(!records.last().unwrap().jumped);
+        assert_eq!(first_divergence(&records, &read_trace(&reference).unwrap()), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_save_and_resume_state() {
+        let dir = temp_dir("state");
+        let program = dir.join("Count.asm");
+        let state = dir.join("Count.state");
+        fs::write(&program, "(LOOP)\n@R13\nM=M+1\n@LOOP\n0;JMP\n").unwrap();
+
+        let program = program.to_str().unwrap();
+        run(&args(&["run", program, "--cycles", "40", "--save-state", state.to_str().unwrap()])).unwrap();
+        let mut cpu = Cpu::new();
+        load_machine(&mut cpu, &state).unwrap();
+        assert_eq!((cpu.cycles(), cpu.read_data(13)), (40, Ok(10)));
+
+        cpu.run_cycles(40);
+        assert_eq!(cpu.read_data(13), Ok(20));
+
+        fs::write(&state, "not a state").unwrap();
+        let err = run(&args(&["run", state.to_str().unwrap()])).unwrap_err();
+        assert!(err.to_string().ends_with("Count.state: Not a machine state file"), "{}", err);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_missing_file_is_an_error() {
+        let result = run(&args(&["asm", "/nonexistent/Missing.asm"]));
+        assert!(matches!(result, Err(CliError::Io(_, _))));
+    }
+
+    #[test]
+    fn test_bad_sources_report_diagnostics() {
+        let dir = temp_dir("diagnostics");
+        let source = dir.join("Bad.asm");
+        fs::write(&source, "@1\nD=FOO\n0;JMP\nX=A\n").unwrap();
+
+        let err = run(&args(&["asm", source.to_str().unwrap()])).unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("Bad.asm:2:1: Invalid comp field: FOO"), "{}", message);
+        assert!(message.contains("Bad.asm:4:1: Invalid dest field: X"), "{}", message);
+
+        let vm = dir.join("Bad.vm");
+        fs::write(&vm, "push constant 1\npop nowhere 0\n").unwrap();
+        let err = run(&args(&["vm", vm.to_str().unwrap()])).unwrap_err();
+        assert_eq!(err.to_string(), "Bad.vm:2:5: Invalid segment: nowhere");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_asm_and_disasm_files() {
+        let dir = temp_dir("asm");
+        let source = dir.join("Add.asm");
+        fs::write(&source, "@2\nD=A\n@3\nD=D+A\n@0\nM=D\n").unwrap();
+
+        let listing = dir.join("Add.lst");
+        run(&args(&["asm", source.to_str().unwrap(), "--listing", listing.to_str().unwrap()])).unwrap();
+        let hack = fs::read_to_string(dir.join("Add.hack")).unwrap();
+        assert_eq!(hack.lines().count(), 6);
+        assert_eq!(hack.lines().next(), Some("0000000000000010"));
+        let listing = fs::read_to_string(listing).unwrap();
+        assert_eq!(listing.lines().nth(3), Some("    3  1110000010010000  E090      4  D=D+A"));
+
+        let listing = dir.join("Listing.asm");
+        run(&args(&["disasm", dir.join("Add.hack").to_str().unwrap(), "-o", listing.to_str().unwrap()])).unwrap();
+        let disassembled = fs::read_to_string(listing).unwrap();
+        assert_eq!(disassembled, "@2\nD=A\n@3\nD=D+A\n@0\nM=D\n");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_vm_directory_with_bootstrap() {
+        let dir = temp_dir("vm");
+        fs::write(dir.join("Sys.vm"), "function Sys.init 1\npush static 0\nlabel END\ngoto END\n").unwrap();
+        fs::write(dir.join("Main.vm"), "function Main.main 0\npush static 1\nreturn\n").unwrap();
+
+        let assembly = translate_path(&dir).unwrap();
+        assert_eq!(assembly[0], "@256");
+        assert!(assembly.contains(&"@Sys.init".to_string()));
+        assert!(assembly.contains(&"@Sys.0".to_string()));
+        assert!(assembly.contains(&"@Main.1".to_string()));
+        // Nothing from the OS, so calls are translated in place
+        assert!(!assembly.iter().any(|line| line.starts_with("@VM$")));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_vm_file_using_the_os_without_main() {
+        let dir = temp_dir("vm_os");
+        let source = dir.join("Prog.vm");
+        fs::write(&source, "function Prog.f 0
+push constant 6
+call Math.abs 1
+return
+").unwrap();
+
+        // Math comes in, but no Sys or bootstrap since nothing would start Main.main
+        let assembly = translate_path(&source).unwrap();
+        assert_eq!(assembly[0], "(Prog.f)");
+        assert!(assembly.contains(&"(Math.abs)".to_string()));
+        assert!(!assembly.contains(&"(Sys.init)".to_string()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_program_runs_vm_file() {
+        let dir = temp_dir("run");
+        let source = dir.join("Simple.vm");
+        fs::write(&source, "push constant 7\npush constant 8\nadd\n").unwrap();
+
+        let program = load_program(&source).unwrap();
+        let mut cpu = Cpu::new();
+        cpu.load_from_string(&program.binaries.join("\n")).unwrap();
+        cpu.run_cycles(DEFAULT_CYCLES);
+
+        assert_eq!(cpu.fetch(), HALT);
+        assert_eq!(cpu.get_data(256), 15);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_program_links_the_os() {
+        let dir = temp_dir("jack_os");
+        fs::write(dir.join("Main.jack"), "\
+class Main {
+    function void main() {
+        do Memory.poke(8000, Math.multiply(Main.square(), 3));
+        return;
+    }
+    function int square() { return 9 * 9; }
+}
+").unwrap();
+
+        let program = load_program(&dir).unwrap();
+        let mut cpu = FastCpu::new();
+        cpu.load_from_string(&program.binaries.join("\n")).unwrap();
+        let outcome = cpu.run(&RunOptions::cycles(5_000_000));
+
+        assert!(matches!(outcome.reason, StopReason::LoopDetected(_)), "{:?}", outcome.reason);
+        assert_eq!(cpu.read_data(8000), Ok(243));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_run_gives_the_os_time_to_start() {
+        let dir = temp_dir("jack_default_cycles");
+        fs::write(dir.join("Main.jack"), "\
+class Main {
+    function void main() {
+        do Memory.poke(8000, 7);
+        return;
+    }
+}
+").unwrap();
+
+        let limits = match parse_args(&args(&["run", dir.to_str().unwrap()])).unwrap() {
+            Command::Run { limits, .. } => limits,
+            other => panic!("expected run, got {:?}", other),
+        };
+        let outputs = RunOutputs { trace: None, save_state: None, snapshots: None };
+        let mut cpu = FastCpu::new();
+        let outcome = run_program(&mut cpu, &dir, &limits, None, outputs).unwrap();
+
+        assert!(matches!(outcome.reason, StopReason::LoopDetected(_)), "{:?}", outcome.reason);
+        assert_eq!(cpu.read_data(8000), Ok(7));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_program_keeps_labels() {
+        let dir = temp_dir("labels");
+        let source = dir.join("Loop.asm");
+        fs::write(&source, "@2\nD=A\n(LOOP)\n@LOOP\nD;JGT\n(END)\n").unwrap();
+
+        let program = load_program(&source).unwrap();
+        assert_eq!(program.binaries.len(), 5);
+        assert_eq!(program.symbols.names_at(2, SymbolKind::Label), ["LOOP"]);
+        assert_eq!(program.symbols.names_at(4, SymbolKind::Label), ["END"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_symbol_files() {
+        let dir = temp_dir("symbols");
+        let source = dir.join("Count.asm");
+        let (hack, symbols) = (dir.join("Count.hack"), dir.join("Count.sym"));
+        fs::write(&source, "@counter\nM=0\n(LOOP)\n@counter\nM=M+1\n@LOOP\n0;JMP\n").unwrap();
+
+        run(&args(&["asm", source.to_str().unwrap(), "--symbols", symbols.to_str().unwrap()])).unwrap();
+        assert_eq!(fs::read_to_string(&symbols).unwrap(), "LOOP label 2\ncounter variable 16\n");
+
+        let listing = dir.join("Listing.asm");
+        run(&args(&[
+            "disasm", hack.to_str().unwrap(), "-o", listing.to_str().unwrap(), "--symbols", symbols.to_str().unwrap(),
+        ])).unwrap();
+        let disassembled = fs::read_to_string(&listing).unwrap();
+        assert_eq!(disassembled, "@counter\nM=0\n(LOOP)\n@counter\nM=M+1\n@LOOP\n0;JMP\n");
+
+        fs::write(&symbols, "LOOP label two\n").unwrap();
+        let err = run(&args(&["disasm", hack.to_str().unwrap(), "--symbols", symbols.to_str().unwrap()])).unwrap_err();
+        assert!(err.to_string().ends_with("Count.sym:1:1: Invalid symbol file entry: LOOP label two"), "{}", err);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,677 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+use crate::debugger::history::History;
+use crate::hardware::cpu::HALT;
+use crate::hardware::machine::{Machine, Register, RunOptions, StopReason};
+use crate::hardware::state::MachineState;
+use crate::hardware::watch::{Access, Comparison, Operand, Watch, WatchHit};
+use crate::parser::assembly::SourceLine;
+use crate::parser::symbols::{SymbolKind, Symbols};
+use crate::parser::table::SymbolTable;
+
+const LIST_RADIUS: u16 = 5;
+// How far `continue` runs without a count, like `run` without --cycles
+const CONTINUE_CYCLES: u64 = 100_000;
+
+pub const HELP: &str = "\
+Commands:
+    s, step [n]                 execute n instructions (default 1)
+    c, continue [n]             run until a breakpoint, halt, fault or idle loop,
+                                or for at most n instructions (default 100000)
+    rs, reverse-step [n]        undo n instructions (default 1)
+    rc, reverse-continue        run backwards to a breakpoint or the start of the history
+    rc <address>                run backwards to the last write of a RAM word
+    goto <cycle>                go back or run forward to the start of a cycle
+    b, break <address|label>    set a breakpoint on a ROM address
+    d, delete <address|label>   remove a breakpoint
+    breakpoints                 list breakpoints
+    r, regs                     show A, D, PC and the cycle count
+    x <address> [count]         show RAM words, also x <start>..<end>
+    set <A|D|PC|address> <value>
+                                also forgets the history reverse commands use
+    watch <A|D|PC>              stop when a register changes
+    watch <address>             stop when RAM is written, rwatch for reads, awatch for both
+    watch <A|D|PC|address> <op> <value>
+                                stop when a condition turns true, op is one of == != < <= > >=
+    watches                     list watchpoints
+    unwatch <n>                 remove a watchpoint
+    l, list [n]                 disassemble n instructions around the PC
+    save <file>                 write the machine state to a file
+    load <file>                 resume a saved machine state, forgetting the history
+    h, help
+    q, quit
+Numbers can be decimal, negative, 0x hex or 0b binary, or a label or predefined symbol.
+An empty line repeats the last command.";
+
+// Interactive debugger over any emulator core, driven one command line at a time
+pub struct Debugger<M: Machine> {
+    pub cpu: M,
+    // Labels and variables, from the assembler or a .sym file
+    pub symbols: Symbols,
+    pub breakpoints: BTreeSet<u16>,
+    // Shown instead of disassembly when the program was assembled from source
+    pub source_map: Vec<SourceLine>,
+    // What the reverse commands undo
+    pub history: History,
+    last_command: String,
+}
+
+impl<M: Machine> Debugger<M> {
+    pub fn new(cpu: M, symbols: Symbols) -> Self {
+        let mut history = History::new();
+        history.record(&cpu);
+        Debugger {
+            cpu,
+            symbols,
+            breakpoints: BTreeSet::new(),
+            source_map: vec![],
+            history,
+            last_command: String::new(),
+        }
+    }
+
+    // Reads commands until `quit` or the end of the input
+    pub fn repl(&mut self, input: &mut impl BufRead, output: &mut impl Write) -> io::Result<()> {
+        writeln!(output, "{}", self.list(0))?;
+        loop {
+            write!(output, "(hack) ")?;
+            output.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                writeln!(output)?;
+                return Ok(());
+            }
+            if matches!(line.trim(), "q" | "quit") {
+                return Ok(());
+            }
+
+            match self.execute(&line) {
+                Ok(reply) if reply.is_empty() => {}
+                Ok(reply) => writeln!(output, "{}", reply)?,
+                Err(message) => writeln!(output, "error: {}", message)?,
+            }
+        }
+    }
+
+    // Runs one command line and returns what to show for it
+    pub fn execute(&mut self, line: &str) -> Result<String, String> {
+        let line = match line.trim() {
+            "" => self.last_command.clone(),
+            line => line.to_string(),
+        };
+        self.last_command = line.clone();
+
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        match parts.as_slice() {
+            [] => Ok(String::new()),
+            ["s" | "step"] => self.step(1),
+            ["s" | "step", count] => {
+                let count = self.value(count)?;
+                self.step(count as u64)
+            }
+            ["c" | "continue"] => self.resume(CONTINUE_CYCLES),
+            ["c" | "continue", count] => {
+                let count = count.parse().map_err(|_| format!("Not a cycle count: {}", count))?;
+                self.resume(count)
+            }
+            ["rs" | "reverse-step"] => self.reverse_step(1),
+            ["rs" | "reverse-step", count] => {
+                let count = self.value(count)?;
+                self.reverse_step(count as u64)
+            }
+            ["rc" | "reverse-continue"] => self.reverse_continue(None),
+            ["rc" | "reverse-continue", target] => {
+                let address = self.value(target)?;
+                self.reverse_continue(Some(address))
+            }
+            ["goto", cycle] => {
+                let cycle = cycle.parse().map_err(|_| format!("Not a cycle number: {}", cycle))?;
+                self.goto(cycle)
+            }
+            ["b" | "break", target] => {
+                let address = self.value(target)?;
+                self.breakpoints.insert(address);
+                Ok(format!("Breakpoint at {}", self.describe(address)))
+            }
+            ["d" | "delete", target] => {
+                let address = self.value(target)?;
+                if self.breakpoints.remove(&address) {
+                    Ok(format!("Deleted breakpoint at {}", self.describe(address)))
+                } else {
+                    Err(format!("No breakpoint at {}", self.describe(address)))
+                }
+            }
+            ["breakpoints"] if self.breakpoints.is_empty() => Ok("No breakpoints".to_string()),
+            ["breakpoints"] => Ok(self.breakpoints.iter()
+                .map(|address| self.describe(*address))
+                .collect::<Vec<_>>()
+                .join("\n")),
+            ["r" | "regs"] => Ok(self.registers()),
+            ["x", range] => match range.split_once("..") {
+                Some((start, end)) => {
+                    let (start, end) = (self.value(start)?, self.value(end)?);
+                    self.memory(start, end.saturating_sub(start))
+                }
+                None => self.memory(self.value(range)?, 1),
+            },
+            ["x", start, count] => {
+                let (start, count) = (self.value(start)?, self.value(count)?);
+                self.memory(start, count)
+            }
+            ["set", target, value] => self.set(target, value),
+            ["watch", target] => match register(target) {
+                Some(register) => self.watch(Watch::Value { operand: Operand::Register(register), condition: None }),
+                None => self.watch_access(target, Access::Write),
+            },
+            ["rwatch", target] => self.watch_access(target, Access::Read),
+            ["awatch", target] => self.watch_access(target, Access::ReadWrite),
+            ["watch", target, comparison, value] => {
+                let operand = match register(target) {
+                    Some(register) => Operand::Register(register),
+                    None => Operand::Memory(self.value(target)?),
+                };
+                let comparison = Comparison::from_symbol(comparison)
+                    .ok_or_else(|| format!("Unknown comparison: {}", comparison))?;
+                let limit = self.value(value)? as i16;
+                self.watch(Watch::Value { operand, condition: Some((comparison, limit)) })
+            }
+            ["watches"] if !self.cpu.watchpoints().is_active() => Ok("No watchpoints".to_string()),
+            ["watches"] => Ok(self.cpu.watchpoints().watches.iter()
+                .enumerate()
+                .map(|(i, watch)| format!("{}: {}", i, watch))
+                .collect::<Vec<_>>()
+                .join("\n")),
+            ["unwatch", index] => {
+                let index = self.value(index)? as usize;
+                match self.cpu.watchpoints_mut().remove(index) {
+                    Some(watch) => Ok(format!("Deleted watchpoint {}: {}", index, watch)),
+                    None => Err(format!("No watchpoint {}", index)),
+                }
+            }
+            ["l" | "list"] => Ok(self.list(LIST_RADIUS)),
+            ["l" | "list", radius] => {
+                let radius = self.value(radius)?;
+                Ok(self.list(radius))
+            }
+            ["save", path] => {
+                fs::write(path, self.cpu.save_state().to_bytes()).map_err(|err| format!("{}: {}", path, err))?;
+                Ok(format!("Saved cycle {} to {}", self.cpu.cycles(), path))
+            }
+            ["load", path] => {
+                let bytes = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+                let state = MachineState::from_bytes(&bytes).map_err(|message| format!("{}: {}", path, message))?;
+                self.cpu.restore_state(&state);
+                self.reset_history();
+                Ok(format!("Loaded cycle {}\n{}", self.cpu.cycles(), self.list(0)))
+            }
+            ["h" | "help"] => Ok(HELP.to_string()),
+            _ => Err(format!("Unknown command: {} (try help)", line)),
+        }
+    }
+
+    // Numbers, labels, variables and predefined symbols like SP or SCREEN
+    pub fn value(&self, text: &str) -> Result<u16, String> {
+        let number = if let Some(hex) = text.strip_prefix("0x") {
+            u16::from_str_radix(hex, 16).ok()
+        } else if let Some(binary) = text.strip_prefix("0b") {
+            u16::from_str_radix(binary, 2).ok()
+        } else if let Some(negative) = text.strip_prefix('-') {
+            negative.parse::<u16>().ok().filter(|n| *n <= 0x8000).map(u16::wrapping_neg)
+        } else {
+            text.parse().ok()
+        };
+
+        number
+            .or_else(|| self.symbols.address(text))
+            .or_else(|| SymbolTable::new().get_address(text))
+            .ok_or_else(|| format!("Not a number or known symbol: {}", text))
+    }
+
+    // `12 (LOOP)` when a label points at the address
+    fn describe(&self, address: u16) -> String {
+        let labels = self.symbols.names_at(address, SymbolKind::Label);
+        if labels.is_empty() {
+            address.to_string()
+        } else {
+            format!("{} ({})", address, labels.join(", "))
+        }
+    }
+
+    fn watch(&mut self, watch: Watch) -> Result<String, String> {
+        let description = watch.to_string();
+        let index = self.cpu.watchpoints_mut().add(watch);
+        Ok(format!("Watchpoint {}: {}", index, description))
+    }
+
+    fn watch_access(&mut self, target: &str, access: Access) -> Result<String, String> {
+        let address = self.value(target)?;
+        self.watch(Watch::Access { address, access })
+    }
+
+    // Stops early when a watchpoint triggers
+    fn step(&mut self, count: u64) -> Result<String, String> {
+        let mut executed = 0;
+        while executed < count {
+            if !self.cpu.clock() {
+                break;
+            }
+            self.history.record(&self.cpu);
+            executed += 1;
+            if !self.cpu.watchpoints().hits.is_empty() {
+                break;
+            }
+        }
+
+        let mut lines: Vec<String> = self.cpu.watchpoints().hits.iter().map(WatchHit::to_string).collect();
+        lines.extend(self.stopped());
+        lines.push(self.list(0));
+        Ok(lines.join("\n"))
+    }
+
+    // Like `continue` in gdb, the instruction under a breakpoint runs before checking for the next one
+    fn resume(&mut self, max_cycles: u64) -> Result<String, String> {
+        if let Some(reason) = self.stopped() {
+            return Err(reason);
+        }
+
+        let Debugger { cpu, breakpoints, history, .. } = self;
+        let outcome = cpu.run_observed(&RunOptions::cycles(max_cycles), |cpu| {
+            history.record(cpu);
+            !breakpoints.contains(&cpu.get_pc())
+        });
+        // A watchpoint stops the run before the observer sees the last instruction
+        self.history.record(&self.cpu);
+
+        let message = match outcome.reason {
+            StopReason::Interrupted => format!("Breakpoint at {}", self.describe(self.cpu.get_pc())),
+            _ => outcome.to_string(),
+        };
+        Ok(format!("{}\n{}", message, self.list(0)))
+    }
+
+    fn reverse_step(&mut self, count: u64) -> Result<String, String> {
+        let mut undone = 0;
+        while undone < count && self.history.step_back(&mut self.cpu).is_some() {
+            undone += 1;
+        }
+
+        let mut lines = vec![];
+        if undone < count {
+            lines.push(self.history_start());
+        }
+        lines.push(self.list(0));
+        Ok(lines.join("\n"))
+    }
+
+    // With an address, stops at the instruction that last wrote it, before it runs
+    fn reverse_continue(&mut self, address: Option<u16>) -> Result<String, String> {
+        loop {
+            let before = address.and_then(|address| self.cpu.read_data(address as usize).ok());
+            let Some(undo) = self.history.step_back(&mut self.cpu) else {
+                return Ok(format!("{}\n{}", self.history_start(), self.list(0)));
+            };
+            match (address, undo.write) {
+                (Some(address), Some((written, old))) if written == address => {
+                    return Ok(format!(
+                        "RAM[{}] written at PC {}: {} -> {}\n{}",
+                        address, self.describe(undo.pc), old as i16, before.unwrap_or(old) as i16, self.list(0),
+                    ));
+                }
+                (None, _) if self.breakpoints.contains(&undo.pc) => {
+                    return Ok(format!("Breakpoint at {}\n{}", self.describe(undo.pc), self.list(0)));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn goto(&mut self, cycle: u64) -> Result<String, String> {
+        let current = self.cpu.cycles();
+        if cycle > current {
+            return self.step(cycle - current);
+        }
+        self.history.rewind_to(&mut self.cpu, cycle)?;
+        Ok(format!("At cycle {}\n{}", self.cpu.cycles(), self.list(0)))
+    }
+
+    fn history_start(&self) -> String {
+        format!("At the start of the recorded history (cycle {})", self.cpu.cycles())
+    }
+
+    // Why the program can't run any further, if it can't
+    fn stopped(&self) -> Option<String> {
+        if let Some(fault) = self.cpu.fault() {
+            let pc = self.cpu.get_pc();
+            match self.source_map.get(pc as usize) {
+                Some(source) => Some(format!("Faulted at PC {} ({}): {}", pc, source, fault)),
+                None => Some(format!("Faulted at PC {}: {}", pc, fault)),
+            }
+        } else if self.cpu.fetch() == HALT {
+            Some(format!("Halted at PC {}", self.cpu.get_pc()))
+        } else {
+            None
+        }
+    }
+
+    fn registers(&self) -> String {
+        let (a, d) = (self.cpu.get_a(), self.cpu.get_d());
+        format!(
+            "A:  {} ({:#06x})\nD:  {} ({:#06x})\nPC: {}\ncycles: {}",
+            a as i16, a, d as i16, d, self.describe(self.cpu.get_pc()), self.cpu.cycles(),
+        )
+    }
+
+    fn memory(&self, start: u16, count: u16) -> Result<String, String> {
+        let mut lines = vec![];
+        for address in start as usize..start as usize + count as usize {
+            let value = self.cpu.read_data(address).map_err(|fault| fault.to_string())?;
+            let names = self.symbols.names_at(address as u16, SymbolKind::Variable);
+            let name = if names.is_empty() { String::new() } else { format!(" ({})", names.join(", ")) };
+            lines.push(format!("RAM[{}]{}: {} ({:#06x})", address, name, value as i16, value));
+        }
+        Ok(lines.join("\n"))
+    }
+
+    fn set(&mut self, target: &str, value: &str) -> Result<String, String> {
+        let value = self.value(value)?;
+        match register(target) {
+            Some(register) => {
+                self.cpu.write_register(register, value);
+                self.reset_history();
+                Ok(self.registers())
+            }
+            None => {
+                let address = self.value(target)?;
+                self.cpu.write_data(address as usize, value).map_err(|fault| fault.to_string())?;
+                self.reset_history();
+                self.memory(address, 1)
+            }
+        }
+    }
+
+    // Undoing instructions from before an edit would mix old and new state
+    fn reset_history(&mut self) {
+        self.history.clear();
+        self.history.record(&self.cpu);
+    }
+
+    // Source or disassembly from `radius` instructions before the PC to `radius`
+    // after it, `=>` marks the PC and `*` a breakpoint
+    pub fn list(&self, radius: u16) -> String {
+        let pc = self.cpu.get_pc();
+        let start = pc.saturating_sub(radius);
+        let end = pc.saturating_add(radius).min(0x7FFF);
+
+        let mut lines = vec![];
+        for address in start..=end {
+            for label in self.symbols.names_at(address, SymbolKind::Label) {
+                lines.push(format!("          ({})", label));
+            }
+            let marker = if address == pc { "=>" } else { "  " };
+            let breakpoint = if self.breakpoints.contains(&address) { "*" } else { " " };
+            let instruction = match (self.cpu.read_rom(address), self.source_map.get(address as usize)) {
+                (HALT, _) => "HALT".to_string(),
+                (_, Some(source)) => format!("{:<24}{}", source.text, source.position()),
+                (instruction, None) => self.symbols.decode(instruction, Some(self.cpu.read_rom(address.wrapping_add(1)))),
+            };
+            lines.push(format!("{}{}{:>5}  {}", marker, breakpoint, address, instruction));
+        }
+        lines.join("\n")
+    }
+}
+
+fn register(name: &str) -> Option<Register> {
+    match name.to_ascii_uppercase().as_str() {
+        "A" => Some(Register::A),
+        "D" => Some(Register::D),
+        "PC" => Some(Register::PC),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::fast::FastCpu;
+    use crate::parser::assembly::Assembler;
+
+    // Adds R0 into R1 until R0 is zero
+    fn debugger() -> Debugger<FastCpu> {
+        let mut asm = Assembler::new();
+        asm.assemble_all("\
+            @3
+            D=A
+            @R0
+            M=D
+            (LOOP)
+            @R0
+            D=M
+            @END
+            D;JEQ
+            @R1
+            M=D+M
+            @R0
+            M=M-1
+            @LOOP
+            0;JMP
+            (END)
+            @END
+            0;JMP
+        ").unwrap();
+
+        let mut cpu = FastCpu::new();
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
+        cpu.write_data(1, 0).unwrap();
+        Debugger::new(cpu, asm.symbols())
+    }
+
+    #[test]
+    fn test_step_and_repeat() {
+        let mut debugger = debugger();
+
+        let reply = debugger.execute("step 2").unwrap();
+        assert_eq!(debugger.cpu.get_pc(), 2);
+        assert!(reply.contains("=>     2  @0"));
+
+        debugger.execute("").unwrap();
+        assert_eq!(debugger.cpu.get_pc(), 4);
+    }
+
+    #[test]
+    fn test_breakpoints_by_label() {
+        let mut debugger = debugger();
+
+        assert_eq!(debugger.execute("break LOOP").unwrap(), "Breakpoint at 4 (LOOP)");
+        let reply = debugger.execute("continue").unwrap();
+        assert!(reply.starts_with("Breakpoint at 4 (LOOP)"));
+        assert_eq!(debugger.cpu.get_data(0), 3);
+
+        debugger.execute("c").unwrap();
+        assert_eq!(debugger.cpu.get_pc(), 4);
+        assert_eq!(debugger.cpu.get_data(0), 2);
+
+        debugger.execute("delete LOOP").unwrap();
+        let reply = debugger.execute("c").unwrap();
+        assert!(reply.starts_with("Halted in a loop at PC 14"));
+        assert_eq!(debugger.cpu.get_data(1), 6);
+        assert!(debugger.execute("delete 4").is_err());
+    }
+
+    #[test]
+    fn test_continue_budget() {
+        let mut debugger = debugger();
+
+        let reply = debugger.execute("continue 5").unwrap();
+        assert!(reply.starts_with("Stopped after 5 cycles (cycle limit reached)"));
+        assert_eq!(debugger.cpu.get_pc(), 5);
+
+        let reply = debugger.execute("c").unwrap();
+        assert!(reply.starts_with("Halted in a loop at PC 14"));
+        assert!(debugger.execute("continue x").is_err());
+    }
+
+    #[test]
+    fn test_inspect_and_modify() {
+        let mut debugger = debugger();
+
+        debugger.execute("set R0 -2").unwrap();
+        debugger.execute("set 0x11 0b101").unwrap();
+        assert_eq!(debugger.execute("x R0").unwrap(), "RAM[0]: -2 (0xfffe)");
+        assert_eq!(debugger.execute("x 16..18").unwrap(), "RAM[16]: 0 (0x0000)\nRAM[17]: 5 (0x0005)");
+        assert_eq!(debugger.execute("x SCREEN 1").unwrap(), "RAM[16384]: 0 (0x0000)");
+
+        let reply = debugger.execute("set pc END").unwrap();
+        assert!(reply.contains("PC: 14 (END)"));
+        debugger.execute("set d 7").unwrap();
+        assert_eq!(debugger.cpu.get_d(), 7);
+
+        assert!(debugger.execute("x 24577").is_err());
+        assert!(debugger.execute("set NOWHERE 1").is_err());
+        assert!(debugger.execute("frobnicate").is_err());
+    }
+
+    #[test]
+    fn test_list_marks_pc_labels_and_breakpoints() {
+        let mut debugger = debugger();
+        debugger.execute("b 5").unwrap();
+        debugger.execute("step 4").unwrap();
+
+        assert_eq!(debugger.list(1), [
+            "       3  M=D",
+            "          (LOOP)",
+            "=>     4  @0",
+            "  *    5  D=M",
+        ].join("\n"));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut debugger = debugger();
+
+        assert_eq!(debugger.execute("watch R1").unwrap(), "Watchpoint 0: write RAM[1]");
+        let reply = debugger.execute("c").unwrap();
+        assert!(reply.contains("Watchpoint 0 (write RAM[1]) at PC 9, M=D+M: 0 -> 3"), "{}", reply);
+
+        debugger.execute("unwatch 0").unwrap();
+        debugger.execute("watch R1 > 4").unwrap();
+        let reply = debugger.execute("c").unwrap();
+        assert!(reply.contains("Watchpoint 0 (RAM[1] > 4) at PC 9, M=D+M: 3 -> 5"), "{}", reply);
+
+        debugger.execute("unwatch 0").unwrap();
+        debugger.execute("rwatch R0").unwrap();
+        debugger.execute("watch D").unwrap();
+        assert_eq!(debugger.execute("watches").unwrap(), "0: read RAM[0]\n1: change D");
+
+        // M=M-1 reads R0 before D changes again
+        let reply = debugger.execute("step 10").unwrap();
+        assert!(reply.contains("Watchpoint 0 (read RAM[0]) at PC 11, M=M-1: 2 -> 2"), "{}", reply);
+        assert_eq!(debugger.cpu.get_pc(), 12);
+        assert!(debugger.execute("unwatch 5").is_err());
+    }
+
+    #[test]
+    fn test_reverse_execution() {
+        let mut debugger = debugger();
+        debugger.execute("c").unwrap();
+        assert_eq!(debugger.cpu.get_data(1), 6);
+
+        let reply = debugger.execute("rc R1").unwrap();
+        assert!(reply.starts_with("RAM[1] written at PC 9: 5 -> 6"), "{}", reply);
+        assert_eq!((debugger.cpu.get_pc(), debugger.cpu.get_data(1)), (9, 5));
+
+        debugger.execute("rs 2").unwrap();
+        assert_eq!(debugger.cpu.get_pc(), 7);
+        debugger.execute("b LOOP").unwrap();
+        let reply = debugger.execute("rc").unwrap();
+        assert!(reply.starts_with("Breakpoint at 4 (LOOP)"), "{}", reply);
+        assert_eq!(debugger.cpu.get_data(0), 1);
+
+        debugger.execute("goto 0").unwrap();
+        assert_eq!((debugger.cpu.cycles(), debugger.cpu.get_pc()), (0, 0));
+        assert_eq!((debugger.cpu.get_data(0), debugger.cpu.get_data(1)), (256, 0));
+        let reply = debugger.execute("rs").unwrap();
+        assert!(reply.starts_with("At the start of the recorded history (cycle 0)"), "{}", reply);
+
+        debugger.execute("goto 10").unwrap();
+        assert_eq!((debugger.cpu.cycles(), debugger.cpu.get_data(1)), (10, 3));
+
+        // Edits start a new history
+        debugger.execute("set R1 7").unwrap();
+        assert!(debugger.execute("goto 3").is_err());
+        debugger.execute("step").unwrap();
+        debugger.execute("rs").unwrap();
+        assert_eq!((debugger.cpu.cycles(), debugger.cpu.get_data(1)), (10, 7));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("rust2tetris_debugger_{}.state", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut debugger = debugger();
+        debugger.execute("step 6").unwrap();
+
+        assert_eq!(debugger.execute(&format!("save {}", path)).unwrap(), format!("Saved cycle 6 to {}", path));
+        debugger.execute("c").unwrap();
+        let reply = debugger.execute(&format!("load {}", path)).unwrap();
+        assert!(reply.starts_with("Loaded cycle 6"), "{}", reply);
+        assert_eq!((debugger.cpu.get_pc(), debugger.cpu.get_d()), (6, 3));
+        assert_eq!(debugger.history.start(), Some(6));
+
+        std::fs::remove_file(path).unwrap();
+        assert!(debugger.execute(&format!("load {}", path)).is_err());
+    }
+
+    #[test]
+    fn test_source_lines() {
+        let mut asm = Assembler::new();
+        asm.file_name = "Poke.asm".to_string();
+        asm.assemble_all("@7\nD=A\n(POKE)\n@32000\nM=D").unwrap();
+        let mut cpu = FastCpu::new();
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
+        let mut debugger = Debugger::new(cpu, asm.symbols());
+        debugger.source_map = asm.source_map;
+
+        assert_eq!(debugger.list(1), [
+            "=>     0  @7                      Poke.asm:1",
+            "       1  D=A                     Poke.asm:2",
+        ].join("\n"));
+        let reply = debugger.execute("step 4").unwrap();
+        assert!(reply.contains("Faulted at PC 3 (Poke.asm:5 in POKE: M=D): Address out of range: 32000"), "{}", reply);
+        assert!(reply.contains("=>     3  M=D                     Poke.asm:5 in POKE"), "{}", reply);
+    }
+
+    #[test]
+    fn test_symbols_without_source() {
+        let mut asm = Assembler::new();
+        asm.assemble_all("(LOOP)\n@counter\nM=M+1\n@LOOP\n0;JMP").unwrap();
+        let mut cpu = FastCpu::new();
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
+        let mut debugger = Debugger::new(cpu, Symbols::parse(&asm.symbols().to_string()).unwrap());
+
+        assert_eq!(debugger.list(3), [
+            "          (LOOP)",
+            "=>     0  @counter",
+            "       1  M=M+1",
+            "       2  @LOOP",
+            "       3  0;JMP",
+        ].join("\n"));
+        debugger.execute("step 2").unwrap();
+        assert_eq!(debugger.execute("x counter").unwrap(), "RAM[16] (counter): 1 (0x0001)");
+    }
+
+    #[test]
+    fn test_repl() {
+        let mut debugger = debugger();
+        let mut input = io::Cursor::new("b END\nc\nx R1\nquit\nstep\n");
+        let mut output = vec![];
+
+        debugger.repl(&mut input, &mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("Breakpoint at 14 (END)"));
+        assert!(output.contains("RAM[1]: 6 (0x0006)"));
+        assert_eq!(debugger.cpu.get_pc(), 14);
+    }
+}
@@ -0,0 +1,267 @@
+use std::collections::VecDeque;
+
+use crate::hardware::machine::{Machine, Register};
+use crate::hardware::memory::KBD;
+
+// Cycles between full snapshots of the machine
+const SNAPSHOT_INTERVAL: u64 = 10_000;
+// Instructions kept for undoing, older ones are dropped
+const HISTORY_LIMIT: usize = 1_000_000;
+
+// The state an instruction changes, as it was before the instruction ran
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Undo {
+    pub cycle: u64,
+    pub pc: u16,
+    pub a: u16,
+    pub d: u16,
+    // (address, old value) of the RAM word the instruction writes
+    pub write: Option<(u16, u16)>,
+}
+
+// Registers and all of data memory, including KBD, at the start of a cycle
+pub struct Snapshot {
+    pub cycle: u64,
+    pub pc: u16,
+    pub a: u16,
+    pub d: u16,
+    pub data: Vec<u16>,
+}
+
+// Undo log of the instructions a debugger ran, with periodic snapshots so
+// going far back doesn't have to undo every instruction on the way
+pub struct History {
+    undo: VecDeque<Undo>,
+    snapshots: VecDeque<Snapshot>,
+    // Captured before the instruction at the current cycle runs
+    pending: Option<Undo>,
+    pub snapshot_every: u64,
+    pub limit: usize,
+}
+
+impl History {
+    pub fn new() -> Self {
+        History {
+            undo: VecDeque::new(),
+            snapshots: VecDeque::new(),
+            pending: None,
+            snapshot_every: SNAPSHOT_INTERVAL,
+            limit: HISTORY_LIMIT,
+        }
+    }
+
+    // Forgets everything, for when the state was changed behind the history's back
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.snapshots.clear();
+        self.pending = None;
+    }
+
+    // The earliest cycle that can be gone back to
+    pub fn start(&self) -> Option<u64> {
+        self.undo.front().map(|undo| undo.cycle)
+            .or_else(|| self.pending.map(|undo| undo.cycle))
+    }
+
+    // Call before the first instruction and after every instruction
+    pub fn record<M: Machine>(&mut self, cpu: &M) {
+        match self.pending {
+            Some(undo) if undo.cycle == cpu.cycles() => return,
+            Some(undo) if undo.cycle + 1 == cpu.cycles() => self.push(undo),
+            // Instructions ran without being recorded
+            Some(_) => self.clear(),
+            None => {}
+        }
+
+        let cycle = cpu.cycles();
+        if self.snapshots.back().is_none_or(|snapshot| cycle >= snapshot.cycle + self.snapshot_every) {
+            self.snapshots.push_back(Snapshot {
+                cycle,
+                pc: cpu.get_pc(),
+                a: cpu.get_a(),
+                d: cpu.get_d(),
+                data: (0..=KBD).map(|address| cpu.read_data(address).unwrap()).collect(),
+            });
+        }
+
+        let (a, instruction) = (cpu.get_a(), cpu.fetch());
+        // C-instructions with M in the destination, unless the write is going to fault
+        let writes = instruction & 0x8008 == 0x8008;
+        self.pending = Some(Undo {
+            cycle,
+            pc: cpu.get_pc(),
+            a,
+            d: cpu.get_d(),
+            write: writes.then(|| cpu.read_data(a as usize).ok().map(|old| (a, old))).flatten(),
+        });
+    }
+
+    fn push(&mut self, undo: Undo) {
+        self.undo.push_back(undo);
+        if self.undo.len() > self.limit {
+            self.undo.pop_front();
+            let start = self.undo.front().map_or(0, |undo| undo.cycle);
+            while self.snapshots.front().is_some_and(|snapshot| snapshot.cycle < start) {
+                self.snapshots.pop_front();
+            }
+        }
+    }
+
+    // Undoes the last instruction, or the one that faulted, and returns what it was.
+    // None at the start of the history
+    pub fn step_back<M: Machine>(&mut self, cpu: &mut M) -> Option<Undo> {
+        let undo = match self.pending {
+            Some(undo) if cpu.fault().is_some() => undo,
+            _ => self.undo.pop_back()?,
+        };
+
+        cpu.write_register(Register::A, undo.a);
+        cpu.write_register(Register::D, undo.d);
+        cpu.write_register(Register::PC, undo.pc);
+        if let Some((address, old)) = undo.write {
+            cpu.write_data(address as usize, old).unwrap();
+        }
+        cpu.rewind(undo.cycle);
+
+        while self.snapshots.back().is_some_and(|snapshot| snapshot.cycle > undo.cycle) {
+            self.snapshots.pop_back();
+        }
+        self.pending = None;
+        self.record(cpu);
+        Some(undo)
+    }
+
+    // Goes back to an earlier cycle, from the closest snapshot when that is
+    // less work than undoing every instruction since
+    pub fn rewind_to<M: Machine>(&mut self, cpu: &mut M, cycle: u64) -> Result<(), String> {
+        match self.start() {
+            Some(start) if cycle >= start => {}
+            Some(start) => return Err(format!("Cycle {} is before the recorded history, which starts at {}", cycle, start)),
+            None => return Err("Nothing recorded yet".to_string()),
+        }
+
+        let current = cpu.cycles();
+        let snapshot = self.snapshots.iter().rev().find(|snapshot| snapshot.cycle <= cycle);
+        match snapshot {
+            Some(snapshot) if cycle - snapshot.cycle < current.saturating_sub(cycle) => {
+                restore(cpu, snapshot);
+                // The instructions from the snapshot on are already in the undo log
+                while cpu.cycles() < cycle && cpu.clock() {}
+                while self.undo.back().is_some_and(|undo| undo.cycle >= cycle) {
+                    self.undo.pop_back();
+                }
+                while self.snapshots.back().is_some_and(|snapshot| snapshot.cycle > cycle) {
+                    self.snapshots.pop_back();
+                }
+                self.pending = None;
+                self.record(cpu);
+            }
+            _ => {
+                while cpu.cycles() > cycle || cpu.fault().is_some() {
+                    if self.step_back(cpu).is_none() {
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Only writes the words that changed, each write ticks all of the gate-level memory
+fn restore<M: Machine>(cpu: &mut M, snapshot: &Snapshot) {
+    cpu.write_register(Register::A, snapshot.a);
+    cpu.write_register(Register::D, snapshot.d);
+    cpu.write_register(Register::PC, snapshot.pc);
+    for (address, value) in snapshot.data.iter().enumerate().take(KBD) {
+        if cpu.read_data(address) != Ok(*value) {
+            cpu.write_data(address, *value).unwrap();
+        }
+    }
+    match snapshot.data[KBD] {
+        0 => cpu.release_key(),
+        key => cpu.press_key(key),
+    }
+    cpu.rewind(snapshot.cycle);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::cpu::Cpu;
+    use crate::hardware::fast::FastCpu;
+    use crate::hardware::machine::tests::{load_program, COUNTDOWN};
+
+    fn state<M: Machine>(cpu: &M) -> (u64, u16, u16, u16, Vec<u16>) {
+        let data = (0..=KBD).map(|address| cpu.read_data(address).unwrap()).collect();
+        (cpu.cycles(), cpu.get_pc(), cpu.get_a(), cpu.get_d(), data)
+    }
+
+    // Runs to the fault, keeping the state at every cycle
+    fn run<M: Machine>(cpu: &mut M, history: &mut History) -> Vec<(u64, u16, u16, u16, Vec<u16>)> {
+        let mut states = vec![state(cpu)];
+        history.record(cpu);
+        while cpu.clock() {
+            history.record(cpu);
+            states.push(state(cpu));
+        }
+        history.record(cpu);
+        states
+    }
+
+    #[test]
+    fn test_step_back_to_the_start() {
+        let mut cpu = load_program(FastCpu::new(), COUNTDOWN);
+        let mut history = History::new();
+        let states = run(&mut cpu, &mut history);
+        assert!(cpu.fault().is_some());
+
+        // The faulting instruction is undone first
+        let undo = history.step_back(&mut cpu).unwrap();
+        assert_eq!(undo.pc, 13);
+        assert!(cpu.fault().is_none());
+        assert_eq!(&state(&cpu), states.last().unwrap());
+
+        for expected in states.iter().rev().skip(1) {
+            history.step_back(&mut cpu).unwrap();
+            assert_eq!(&state(&cpu), expected);
+        }
+        assert!(history.step_back(&mut cpu).is_none());
+        assert_eq!(history.start(), Some(0));
+    }
+
+    #[test]
+    fn test_rewind_from_snapshots() {
+        let mut cpu = load_program(Cpu::new(), COUNTDOWN);
+        let mut history = History::new();
+        history.snapshot_every = 16;
+        let states = run(&mut cpu, &mut history);
+
+        history.rewind_to(&mut cpu, 20).unwrap();
+        assert_eq!(state(&cpu), states[20]);
+        history.rewind_to(&mut cpu, 3).unwrap();
+        assert_eq!(state(&cpu), states[3]);
+
+        // Running forward again keeps recording
+        while cpu.cycles() < 40 && cpu.clock() {
+            history.record(&cpu);
+        }
+        history.rewind_to(&mut cpu, 33).unwrap();
+        assert_eq!(state(&cpu), states[33]);
+    }
+
+    #[test]
+    fn test_limit_drops_the_oldest() {
+        let mut cpu = load_program(FastCpu::new(), COUNTDOWN);
+        let mut history = History::new();
+        history.limit = 10;
+        history.snapshot_every = 4;
+        let states = run(&mut cpu, &mut history);
+
+        let end = states.last().unwrap().0;
+        assert_eq!(history.start(), Some(end - 10));
+        assert!(history.rewind_to(&mut cpu, end - 11).is_err());
+        history.rewind_to(&mut cpu, end - 10).unwrap();
+        assert_eq!(state(&cpu), states[(end - 10) as usize]);
+    }
+}
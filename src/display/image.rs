@@ -0,0 +1,318 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::hardware::machine::{Machine, RunOptions, RunOutcome};
+
+pub const WIDTH: usize = 512;
+pub const HEIGHT: usize = 256;
+const WORDS_PER_ROW: usize = WIDTH / 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Pbm,
+    Pgm,
+    Png,
+}
+
+impl ImageFormat {
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension.to_ascii_lowercase().as_str() {
+            "pbm" => Some(ImageFormat::Pbm),
+            "pgm" => Some(ImageFormat::Pgm),
+            "png" => Some(ImageFormat::Png),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Pbm => "pbm",
+            ImageFormat::Pgm => "pgm",
+            ImageFormat::Png => "png",
+        }
+    }
+
+    pub fn encode(&self, screen: &[u16]) -> Vec<u8> {
+        match self {
+            ImageFormat::Pbm => to_pbm(screen),
+            ImageFormat::Pgm => to_pgm(screen),
+            ImageFormat::Png => to_png(screen),
+        }
+    }
+}
+
+// A set bit is a black pixel, the least significant bit is the leftmost one
+pub fn pixel(screen: &[u16], x: usize, y: usize) -> bool {
+    let word = screen[y * WORDS_PER_ROW + x / 16];
+    (word >> (x % 16)) & 1 != 0
+}
+
+// One row packed 8 pixels per byte, leftmost pixel in the most significant bit
+fn packed_row(screen: &[u16], y: usize) -> impl Iterator<Item = u8> + '_ {
+    screen[y * WORDS_PER_ROW..(y + 1) * WORDS_PER_ROW]
+        .iter()
+        .flat_map(|word| [(*word as u8).reverse_bits(), ((word >> 8) as u8).reverse_bits()])
+}
+
+pub fn to_pbm(screen: &[u16]) -> Vec<u8> {
+    let mut bytes = format!("P4\n{} {}\n", WIDTH, HEIGHT).into_bytes();
+    for y in 0..HEIGHT {
+        bytes.extend(packed_row(screen, y));
+    }
+    bytes
+}
+
+pub fn to_pgm(screen: &[u16]) -> Vec<u8> {
+    let mut bytes = format!("P5\n{} {}\n255\n", WIDTH, HEIGHT).into_bytes();
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            bytes.push(if pixel(screen, x, y) { 0 } else { 255 });
+        }
+    }
+    bytes
+}
+
+// 1-bit grayscale PNG, where 0 is black
+pub fn to_png(screen: &[u16]) -> Vec<u8> {
+    let mut raw = Vec::with_capacity(HEIGHT * (WIDTH / 8 + 1));
+    for y in 0..HEIGHT {
+        raw.push(0); // filter: none
+        raw.extend(packed_row(screen, y).map(|byte| !byte));
+    }
+
+    let mut header = vec![];
+    header.extend((WIDTH as u32).to_be_bytes());
+    header.extend((HEIGHT as u32).to_be_bytes());
+    header.extend([1, 0, 0, 0, 0]); // bit depth, grayscale, deflate, no filter, no interlace
+
+    let mut bytes = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+    write_chunk(&mut bytes, b"IHDR", &header);
+    write_chunk(&mut bytes, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut bytes, b"IEND", &[]);
+    bytes
+}
+
+fn write_chunk(bytes: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    bytes.extend((data.len() as u32).to_be_bytes());
+    let start = bytes.len();
+    bytes.extend(kind);
+    bytes.extend(data);
+    let crc = crc32(&bytes[start..]);
+    bytes.extend(crc.to_be_bytes());
+}
+
+// zlib stream made of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        bytes.extend([0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let is_final = blocks.peek().is_none();
+        let len = block.len() as u16;
+        bytes.push(is_final as u8);
+        bytes.extend(len.to_le_bytes());
+        bytes.extend((!len).to_le_bytes());
+        bytes.extend(block);
+    }
+    bytes.extend(adler32(data).to_be_bytes());
+    bytes
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+pub fn write_image(path: &Path, screen: &[u16], format: ImageFormat) -> io::Result<()> {
+    fs::write(path, format.encode(screen))
+}
+
+// Writes `frame_<cycle>` images while a program runs, and a `frame_final` one when it stops
+pub struct Snapshotter {
+    pub dir: PathBuf,
+    pub format: ImageFormat,
+    pub every: Option<u64>,
+    pub at_halt: bool,
+    pub written: Vec<PathBuf>,
+}
+
+impl Snapshotter {
+    pub fn new(dir: &Path, format: ImageFormat) -> Self {
+        Snapshotter {
+            dir: dir.to_path_buf(),
+            format,
+            every: None,
+            at_halt: true,
+            written: vec![],
+        }
+    }
+
+    pub fn snapshot<M: Machine>(&mut self, cpu: &M, name: &str) -> io::Result<()> {
+        let path = self.dir.join(format!("{}.{}", name, self.format.extension()));
+        write_image(&path, &cpu.screen_buffer(), self.format)?;
+        self.written.push(path);
+        Ok(())
+    }
+
+    // The final frame is taken however the run stopped, including a cycle limit
+    pub fn run<M: Machine>(&mut self, cpu: &mut M, options: &RunOptions) -> io::Result<RunOutcome> {
+        fs::create_dir_all(&self.dir)?;
+
+        let mut result = Ok(());
+        let outcome = match self.every {
+            Some(every) if every > 0 => cpu.run_observed(options, |cpu| {
+                if cpu.cycles() % every == 0 {
+                    result = self.snapshot(cpu, &format!("frame_{:08}", cpu.cycles()));
+                }
+                result.is_ok()
+            }),
+            _ => cpu.run(options),
+        };
+        result?;
+
+        if self.at_halt {
+            self.snapshot(cpu, "frame_final")?;
+        }
+        Ok(outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::cpu::{Cpu, HALT};
+    use crate::parser::assembly::Assembler;
+
+    fn blank() -> Vec<u16> {
+        vec![0; WIDTH * HEIGHT / 16]
+    }
+
+    #[test]
+    fn test_pixel_bit_order() {
+        let mut screen = blank();
+        screen[0] = 0b101;
+        screen[WORDS_PER_ROW + 1] = 0x8000;
+
+        assert!(pixel(&screen, 0, 0));
+        assert!(!pixel(&screen, 1, 0));
+        assert!(pixel(&screen, 2, 0));
+        assert!(pixel(&screen, 31, 1));
+        assert!(!pixel(&screen, 31, 0));
+    }
+
+    #[test]
+    fn test_pbm() {
+        let mut screen = blank();
+        screen[0] = 0x0001;
+        screen[1] = 0x8000;
+
+        let bytes = to_pbm(&screen);
+        let header = b"P4\n512 256\n";
+        assert_eq!(&bytes[..header.len()], header);
+        assert_eq!(bytes.len(), header.len() + WIDTH * HEIGHT / 8);
+        assert_eq!(&bytes[header.len()..header.len() + 4], &[0x80, 0x00, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn test_pgm() {
+        let mut screen = blank();
+        screen[0] = 0x0002;
+
+        let bytes = to_pgm(&screen);
+        let header = b"P5\n512 256\n255\n";
+        assert_eq!(&bytes[..header.len()], header);
+        assert_eq!(bytes.len(), header.len() + WIDTH * HEIGHT);
+        assert_eq!(&bytes[header.len()..header.len() + 3], &[255, 0, 255]);
+    }
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn test_zlib_stored_blocks() {
+        let data = vec![7u8; 0x1_0000];
+        let bytes = zlib_stored(&data);
+
+        // Two blocks: 65535 bytes, then the final 1 byte
+        assert_eq!(&bytes[..7], &[0x78, 0x01, 0x00, 0xFF, 0xFF, 0x00, 0x00]);
+        let second = 2 + 5 + 0xFFFF;
+        assert_eq!(&bytes[second..second + 5], &[0x01, 0x01, 0x00, 0xFE, 0xFF]);
+        assert_eq!(bytes.len(), second + 5 + 1 + 4);
+    }
+
+    #[test]
+    fn test_png_structure() {
+        let bytes = to_png(&blank());
+
+        assert_eq!(&bytes[..8], &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]);
+        assert_eq!(&bytes[12..16], b"IHDR");
+        assert_eq!(&bytes[16..24], &[0, 0, 2, 0, 0, 0, 1, 0]);
+        assert_eq!(&bytes[24..29], &[1, 0, 0, 0, 0]);
+        assert_eq!(&bytes[bytes.len() - 12..], &[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]);
+    }
+
+    #[test]
+    fn test_snapshotter_golden_frame() {
+        let mut asm = Assembler::new();
+        asm.assemble_all("\
+            @SCREEN
+            M=-1
+            @SCREEN
+            D=A
+            @8191
+            A=D+A
+            M=1
+        ").unwrap();
+
+        let mut cpu = Cpu::new();
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("rust2tetris_frames_{}", std::process::id()));
+        let mut snapshots = Snapshotter::new(&dir, ImageFormat::Pbm);
+        snapshots.every = Some(2);
+
+        let outcome = snapshots.run(&mut cpu, &RunOptions::cycles(100)).unwrap();
+        assert_eq!(outcome.cycles, 7);
+        assert_eq!(cpu.fetch(), HALT);
+
+        let names: Vec<String> = snapshots.written.iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap().to_string())
+            .collect();
+        assert_eq!(names, vec![
+            "frame_00000002.pbm",
+            "frame_00000004.pbm",
+            "frame_00000006.pbm",
+            "frame_final.pbm",
+        ]);
+
+        let mut expected = blank();
+        expected[0] = 0xFFFF;
+        expected[8191] = 0x0001;
+        let frame = fs::read(dir.join("frame_final.pbm")).unwrap();
+        assert_eq!(frame, to_pbm(&expected));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
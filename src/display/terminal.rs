@@ -0,0 +1,302 @@
+use std::io::{self, IsTerminal, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::display::image::{pixel, HEIGHT, WIDTH};
+use crate::hardware::machine::Machine;
+use crate::hardware::input::{
+    KEY_BACKSPACE,
+    KEY_DOWN,
+    KEY_ESCAPE,
+    KEY_LEFT,
+    KEY_NEWLINE,
+    KEY_RIGHT,
+    KEY_UP,
+};
+
+const CYCLES_PER_BATCH: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ViewerMode {
+    // 1x2 pixels per cell, optionally downscaled
+    HalfBlock,
+    // 2x4 pixels per cell
+    Braille,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum KeyEvent {
+    Key(u16),
+    Quit,
+}
+
+// Half-block cells cover `scale` x `2 * scale` pixels, any black pixel darkens the cell
+pub fn render_half_blocks(screen: &[u16], scale: usize) -> Vec<String> {
+    let scale = scale.max(1);
+    let black = |x: usize, y: usize| {
+        (y..y + scale).any(|py| (x..x + scale).any(|px| px < WIDTH && py < HEIGHT && pixel(screen, px, py)))
+    };
+
+    (0..HEIGHT).step_by(2 * scale)
+        .map(|y| {
+            (0..WIDTH).step_by(scale)
+                .map(|x| match (black(x, y), black(x, y + scale)) {
+                    (true, true) => '█',
+                    (true, false) => '▀',
+                    (false, true) => '▄',
+                    (false, false) => ' ',
+                })
+                .collect()
+        })
+        .collect()
+}
+
+pub fn render_braille(screen: &[u16]) -> Vec<String> {
+    // Dot bit for each (x, y) position inside a 2x4 braille cell
+    const DOTS: [[u32; 4]; 2] = [[0x01, 0x02, 0x04, 0x40], [0x08, 0x10, 0x20, 0x80]];
+
+    (0..HEIGHT).step_by(4)
+        .map(|y| {
+            (0..WIDTH).step_by(2)
+                .map(|x| {
+                    let mut bits = 0;
+                    for (dx, column) in DOTS.iter().enumerate() {
+                        for (dy, dot) in column.iter().enumerate() {
+                            if pixel(screen, x + dx, y + dy) {
+                                bits |= dot;
+                            }
+                        }
+                    }
+                    char::from_u32(0x2800 + bits).unwrap()
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// Terminal input as it arrives from one read, escape sequences included
+pub fn parse_keys(bytes: &[u8]) -> Vec<KeyEvent> {
+    let mut events = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let event = match bytes[i] {
+            0x03 => KeyEvent::Quit, // Ctrl-C
+            0x1B if bytes.get(i + 1) == Some(&b'[') && i + 2 < bytes.len() => {
+                i += 2;
+                match bytes[i] {
+                    b'A' => KeyEvent::Key(KEY_UP),
+                    b'B' => KeyEvent::Key(KEY_DOWN),
+                    b'C' => KeyEvent::Key(KEY_RIGHT),
+                    b'D' => KeyEvent::Key(KEY_LEFT),
+                    _ => {
+                        i += 1;
+                        continue;
+                    }
+                }
+            }
+            0x1B => KeyEvent::Key(KEY_ESCAPE),
+            b'\r' | b'\n' => KeyEvent::Key(KEY_NEWLINE),
+            0x7F | 0x08 => KeyEvent::Key(KEY_BACKSPACE),
+            byte if (0x20..0x7F).contains(&byte) => KeyEvent::Key(byte as u16),
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+        events.push(event);
+        i += 1;
+    }
+    events
+}
+
+// Puts the terminal in raw mode with `stty` and restores it when dropped
+struct RawMode {
+    saved: Option<String>,
+}
+
+impl RawMode {
+    fn enable() -> Self {
+        if !io::stdin().is_terminal() {
+            return RawMode { saved: None };
+        }
+        let saved = stty(&["-g"]).map(|settings| settings.trim().to_string());
+        if saved.is_some() {
+            stty(&["raw", "-echo"]);
+        }
+        RawMode { saved }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Some(settings) = &self.saved {
+            stty(&[settings]);
+        }
+    }
+}
+
+fn stty(args: &[&str]) -> Option<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .output()
+        .ok()?;
+    output.status.success().then(|| String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+fn spawn_input_reader() -> Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut stdin = io::stdin();
+        let mut buffer = [0u8; 32];
+        while let Ok(count) = stdin.read(&mut buffer) {
+            if count == 0 || sender.send(buffer[..count].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+// Draws the screen in the terminal while the Cpu runs and feeds it keystrokes.
+// Terminals don't report key releases, so a key is held for `key_hold` after its last repeat
+pub struct Viewer {
+    pub mode: ViewerMode,
+    pub scale: usize,
+    pub fps: u32,
+    pub key_hold: Duration,
+}
+
+impl Viewer {
+    pub fn new() -> Self {
+        Viewer {
+            mode: ViewerMode::Braille,
+            scale: 2,
+            fps: 20,
+            key_hold: Duration::from_millis(150),
+        }
+    }
+
+    pub fn render(&self, screen: &[u16]) -> Vec<String> {
+        match self.mode {
+            ViewerMode::HalfBlock => render_half_blocks(screen, self.scale),
+            ViewerMode::Braille => render_braille(screen),
+        }
+    }
+
+    fn draw<M: Machine>(&self, out: &mut impl Write, cpu: &M) -> io::Result<()> {
+        let mut frame = String::from("\x1b[H");
+        for line in self.render(&cpu.screen_buffer()) {
+            frame.push_str(&line);
+            frame.push_str("\r\n");
+        }
+        frame.push_str(&format!("cycles: {}  PC: {}  (Ctrl-C to quit)\x1b[K", cpu.cycles(), cpu.get_pc()));
+        out.write_all(frame.as_bytes())?;
+        out.flush()
+    }
+
+    pub fn run<M: Machine>(&self, cpu: &mut M, max_cycles: u64) -> io::Result<u64> {
+        let frame_time = Duration::from_secs_f64(1.0 / self.fps.max(1) as f64);
+        let input = spawn_input_reader();
+        let raw_mode = RawMode::enable();
+        let mut out = io::stdout();
+        out.write_all(b"\x1b[2J\x1b[?25l")?;
+
+        let mut executed = 0;
+        let mut next_frame = Instant::now();
+        let mut release_at: Option<Instant> = None;
+        let mut quit = false;
+
+        while executed < max_cycles && !quit {
+            while let Ok(bytes) = input.try_recv() {
+                for event in parse_keys(&bytes) {
+                    match event {
+                        KeyEvent::Quit => quit = true,
+                        KeyEvent::Key(code) => {
+                            cpu.press_key(code);
+                            release_at = Some(Instant::now() + self.key_hold);
+                        }
+                    }
+                }
+            }
+            if release_at.is_some_and(|at| Instant::now() >= at) {
+                cpu.release_key();
+                release_at = None;
+            }
+
+            let batch = CYCLES_PER_BATCH.min(max_cycles - executed);
+            let ran = cpu.run_cycles(batch);
+            executed += ran;
+
+            if Instant::now() >= next_frame {
+                self.draw(&mut out, cpu)?;
+                next_frame = Instant::now() + frame_time;
+            }
+            if ran < batch {
+                break;
+            }
+        }
+
+        self.draw(&mut out, cpu)?;
+        out.write_all(b"\x1b[?25h\r\n")?;
+        drop(raw_mode);
+        Ok(executed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blank() -> Vec<u16> {
+        vec![0; WIDTH * HEIGHT / 16]
+    }
+
+    #[test]
+    fn test_render_half_blocks_full_size() {
+        let mut screen = blank();
+        screen[0] = 0b0111; // row 0: pixels 0, 1, 2
+        screen[32] = 0b0101; // row 1: pixels 0, 2
+
+        let lines = render_half_blocks(&screen, 1);
+        assert_eq!(lines.len(), HEIGHT / 2);
+        assert_eq!(lines[0].chars().count(), WIDTH);
+        assert!(lines[0].starts_with("█▀█ "));
+        assert!(lines[1].trim().is_empty());
+    }
+
+    #[test]
+    fn test_render_half_blocks_scaled() {
+        let mut screen = blank();
+        screen[32 * 3] = 0b0010; // row 3, pixel 1
+
+        let lines = render_half_blocks(&screen, 2);
+        assert_eq!(lines.len(), HEIGHT / 4);
+        assert_eq!(lines[0].chars().count(), WIDTH / 2);
+        assert!(lines[0].starts_with("▄ "));
+    }
+
+    #[test]
+    fn test_render_braille() {
+        let mut screen = blank();
+        screen[0] = 0b11; // row 0: pixels 0, 1
+        screen[32 * 3] = 0b10; // row 3: pixel 1
+
+        let lines = render_braille(&screen);
+        assert_eq!(lines.len(), HEIGHT / 4);
+        assert_eq!(lines[0].chars().count(), WIDTH / 2);
+        assert_eq!(lines[0].chars().next(), Some('\u{2889}'));
+        assert_eq!(lines[0].chars().nth(1), Some('\u{2800}'));
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        assert_eq!(parse_keys(b"a"), vec![KeyEvent::Key(97)]);
+        assert_eq!(parse_keys(b"\x1b[A\x1b[D"), vec![KeyEvent::Key(KEY_UP), KeyEvent::Key(KEY_LEFT)]);
+        assert_eq!(parse_keys(b"\r\x7f"), vec![KeyEvent::Key(KEY_NEWLINE), KeyEvent::Key(KEY_BACKSPACE)]);
+        assert_eq!(parse_keys(b"\x1b"), vec![KeyEvent::Key(KEY_ESCAPE)]);
+        assert_eq!(parse_keys(b"x\x03"), vec![KeyEvent::Key(120), KeyEvent::Quit]);
+    }
+}
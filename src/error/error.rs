@@ -0,0 +1,223 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    InvalidInstruction(String),
+    UnknownSymbol(String),
+    InvalidComp(String),
+    InvalidDest(String),
+    InvalidJump(String),
+    InvalidCommand(String),
+    InvalidSegment(String),
+    InvalidIndex(String),
+    RomOverflow,
+    InvalidBinary(String),
+    InvalidKeyScript(String),
+    InvalidSymbolEntry(String),
+    InvalidDirective(String),
+    DuplicateDefinition(String),
+    UnterminatedMacro(String),
+    // Macro name, parameters it takes and arguments it was given
+    MacroArguments(String, usize, usize),
+    RecursiveMacro(String),
+    // Macro that went past the limit and the limit on expansions in a program
+    RunawayMacro(String, usize),
+    // File and why it couldn't be included
+    IncludeFailed(String, String),
+    InvalidExpression(String),
+    // Expression and what it came to
+    ConstantOutOfRange(String, i64),
+    InvalidToken(String),
+    UnterminatedString,
+    // A character in a string constant with no Hack character code
+    InvalidStringCharacter(char),
+    UnterminatedComment,
+    IntegerTooLarge(String),
+    // What was expected and what was found instead
+    UnexpectedToken(String, String),
+    UndeclaredVariable(String),
+    UnknownSubroutine(String),
+    UnknownClass(String),
+    // Subroutine, parameters it takes and arguments it was given
+    ArgumentCount(String, usize, usize),
+    MethodCalledAsFunction(String),
+    // Subroutine and whether it's a function or a constructor
+    FunctionCalledAsMethod(String, String),
+    MissingReturn(String),
+    ThisInFunction,
+    FieldInFunction(String),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::InvalidInstruction(line) => write!(f, "Invalid assembly instruction: {}", line),
+            ErrorKind::UnknownSymbol(symbol) => write!(f, "Symbol not found: {}", symbol),
+            ErrorKind::InvalidComp(comp) => write!(f, "Invalid comp field: {}", comp),
+            ErrorKind::InvalidDest(dest) => write!(f, "Invalid dest field: {}", dest),
+            ErrorKind::InvalidJump(jump) => write!(f, "Invalid jump field: {}", jump),
+            ErrorKind::InvalidCommand(command) => write!(f, "Invalid command: {}", command),
+            ErrorKind::InvalidSegment(segment) => write!(f, "Invalid segment: {}", segment),
+            ErrorKind::InvalidIndex(index) => write!(f, "Invalid index: {}", index),
+            ErrorKind::RomOverflow => write!(f, "ROM file exceeds 32K instruction limit"),
+            ErrorKind::InvalidBinary(line) => write!(f, "Invalid binary '{}'", line),
+            ErrorKind::InvalidKeyScript(entry) => write!(f, "Invalid key script entry: {}", entry),
+            ErrorKind::InvalidSymbolEntry(entry) => write!(f, "Invalid symbol file entry: {}", entry),
+            ErrorKind::InvalidDirective(line) => write!(f, "Invalid directive: {}", line),
+            ErrorKind::DuplicateDefinition(name) => write!(f, "Already defined: {}", name),
+            ErrorKind::UnterminatedMacro(name) => write!(f, "Macro {} has no .endm", name),
+            ErrorKind::MacroArguments(name, expected, found) => {
+                write!(f, "Macro {} takes {} arguments but was given {}", name, expected, found)
+            }
+            ErrorKind::RecursiveMacro(name) => write!(f, "Macro {} expands itself", name),
+            ErrorKind::RunawayMacro(name, limit) => {
+                write!(f, "Macro {} takes the program past {} macro expansions", name, limit)
+            }
+            ErrorKind::IncludeFailed(file, reason) => write!(f, "Cannot include {}: {}", file, reason),
+            ErrorKind::InvalidExpression(text) => write!(f, "Invalid expression: {}", text),
+            ErrorKind::ConstantOutOfRange(text, value) => {
+                write!(f, "{} is {}, which doesn't fit in 15 bits", text, value)
+            }
+            ErrorKind::InvalidToken(token) => write!(f, "Invalid token: {}", token),
+            ErrorKind::UnterminatedString => write!(f, "String constant has no closing quote on its line"),
+            ErrorKind::InvalidStringCharacter(c) => {
+                write!(f, "String constant has {:?}, which isn't in the Hack character set", c)
+            }
+            ErrorKind::UnterminatedComment => write!(f, "Comment has no closing */"),
+            ErrorKind::IntegerTooLarge(number) => write!(f, "Integer constant {} is larger than 32767", number),
+            ErrorKind::UnexpectedToken(expected, found) => write!(f, "Expected {} but found {}", expected, found),
+            ErrorKind::UndeclaredVariable(name) => write!(f, "Undeclared variable: {}", name),
+            ErrorKind::UnknownSubroutine(name) => write!(f, "Unknown subroutine: {}", name),
+            ErrorKind::UnknownClass(name) => write!(f, "Unknown class: {}", name),
+            ErrorKind::ArgumentCount(name, expected, found) => {
+                write!(f, "{} takes {} arguments but was given {}", name, expected, found)
+            }
+            ErrorKind::MethodCalledAsFunction(name) => {
+                write!(f, "{} is a method and needs an object to call it on", name)
+            }
+            ErrorKind::FunctionCalledAsMethod(name, kind) => {
+                write!(f, "{} is a {}, call it through its class", name, kind)
+            }
+            ErrorKind::MissingReturn(name) => write!(f, "{} can reach its end without returning", name),
+            ErrorKind::ThisInFunction => write!(f, "this can't be used in a function"),
+            ErrorKind::FieldInFunction(name) => write!(f, "Field {} can't be used in a function", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Location {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Location {
+    pub fn new(file: &str, line: usize, column: usize) -> Self {
+        Location { file: file.to_string(), line, column }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.file.is_empty() {
+            write!(f, "{}:{}", self.line, self.column)
+        } else {
+            write!(f, "{}:{}:{}", self.file, self.line, self.column)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub location: Location,
+    // For errors in a macro body, the macro and the line it was used on.
+    // Boxed since errors are passed around by value everywhere
+    pub expansion: Option<Box<(String, usize)>>,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, location: Location) -> Self {
+        Error { kind, location, expansion: None }
+    }
+
+    pub fn in_expansion(mut self, name: &str, line: usize) -> Self {
+        self.expansion = Some(Box::new((name.to_string(), line)));
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.kind)?;
+        if let Some((name, line)) = self.expansion.as_deref() {
+            write!(f, " (in expansion of {} at line {})", name, line)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {}
+
+// Runtime errors raised by the Cpu while executing a program
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    AddressOutOfRange(usize),
+    RomOutOfRange(usize),
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::AddressOutOfRange(address) => write!(f, "Address out of range: {}", address),
+            Fault::RomOutOfRange(address) => write!(f, "ROM address out of range: {}", address),
+        }
+    }
+}
+
+impl std::error::Error for Fault {}
+
+// One diagnostic per line, in the order they were reported
+pub fn format_errors(errors: &[Error]) -> String {
+    errors.iter()
+        .map(Error::to_string)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// 1-based column of `token`, which must be a slice of `line`
+pub fn column_of(line: &str, token: &str) -> usize {
+    let offset = (token.as_ptr() as usize).saturating_sub(line.as_ptr() as usize);
+    offset.min(line.len()) + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_display() {
+        let error = Error::new(
+            ErrorKind::InvalidComp("FOO".to_string()),
+            Location::new("Prog.asm", 3, 5),
+        );
+        assert_eq!(error.to_string(), "Prog.asm:3:5: Invalid comp field: FOO");
+
+        let error = error.in_expansion("SET", 12);
+        assert_eq!(error.to_string(), "Prog.asm:3:5: Invalid comp field: FOO (in expansion of SET at line 12)");
+    }
+
+    #[test]
+    fn test_error_display_without_file() {
+        let error = Error::new(ErrorKind::RomOverflow, Location::new("", 32769, 1));
+        assert_eq!(error.to_string(), "32769:1: ROM file exceeds 32K instruction limit");
+    }
+
+    #[test]
+    fn test_column_of() {
+        let line = "  push local 0";
+        let token = line.split_whitespace().nth(1).unwrap();
+        assert_eq!(column_of(line, token), 8);
+    }
+}
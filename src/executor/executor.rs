@@ -1,11 +1,14 @@
+use crate::error::error::Error;
 use crate::hardware::cpu::Cpu;
+use crate::hardware::machine::{Machine, RunOptions, RunOutcome};
 use crate::parser::assembly::Assembler;
-use crate::stack::stack::Stack;
+use crate::stack::stack::{Stack, VmFile};
 
 pub struct Executor {
     cpu: Cpu,
     asm: Assembler,
     stack: Stack,
+    program: Vec<VmFile>,
 }
 
 impl Executor {
@@ -14,6 +17,7 @@ impl Executor {
             cpu: Cpu::new(),
             asm: Assembler::new(),
             stack: Stack::new(),
+            program: vec![],
         }
     }
 
@@ -21,22 +25,32 @@ impl Executor {
         self.stack.commands = commands;
     }
 
-    pub fn assemble_all(&mut self) {
-        self.stack.assemble_all();
-        self.asm.assemble_all(&self.stack.assembly.join("\n"));
+    pub fn set_program(&mut self, files: Vec<VmFile>) {
+        self.program = files;
+    }
+
+    // Stops at the first stage that fails, with all of that stage's diagnostics
+    pub fn assemble_all(&mut self) -> Result<(), Vec<Error>> {
+        if self.program.is_empty() {
+            self.stack.assemble_all()?;
+        } else {
+            self.stack.assemble_program(&self.program)?;
+        }
+        self.asm.assemble_all(&self.stack.assembly.join("\n"))?;
 
         self.cpu.reset_pc();
-        self.cpu.load_from_string(&self.asm.binaries.join("\n"));
+        self.cpu.load_from_string(&self.asm.binaries.join("\n"))
     }
 
-    pub fn run(&mut self) {
-        self.assemble_all();
-        self.cpu.run();
+    pub fn run(&mut self) -> Result<RunOutcome, Vec<Error>> {
+        self.assemble_all()?;
+        Ok(self.cpu.run(&RunOptions::new()))
     }
 
-    pub fn run_print(&mut self) {
-        self.assemble_all();
+    pub fn run_print(&mut self) -> Result<(), Vec<Error>> {
+        self.assemble_all()?;
         self.cpu.run_print();
+        Ok(())
     }
 
 }
@@ -1,21 +1,35 @@
+use crate::error::error::{Error, Fault};
 use crate::hardware::alu::{alu, AluFlags};
 use crate::hardware::gates::get_bit;
+use crate::hardware::input::KeyboardSource;
+use crate::hardware::machine::{Machine, Register};
+use crate::hardware::state::MachineState;
+use crate::hardware::trace::{TraceRecord, Tracer};
+use crate::hardware::watch::Watchpoints;
 use crate::hardware::memory::{ 
     Register16,
     Counter16,
-    Ram16K,
+    Memory,
     Rom32K,
+    KBD,
+    ROM_SIZE,
+    SCREEN_SIZE,
 };
 use crate::parser::table::decode_instruction;
 
-const HALT: u16 = 0xFFFF;
+pub const HALT: u16 = 0xFFFF;
 
 pub struct Cpu {
     a: Register16,
     d: Register16,
     pc: Counter16,
-    data: Ram16K,
+    data: Memory,
     rom: Rom32K,
+    fault: Option<Fault>,
+    cycles: u64,
+    keyboard: Option<Box<dyn KeyboardSource>>,
+    watchpoints: Watchpoints,
+    tracer: Option<Box<dyn Tracer>>,
 }
 
 impl Cpu {
@@ -24,14 +38,19 @@ impl Cpu {
             a: Register16::new(),
             d: Register16::new(),
             pc: Counter16::new(),
-            data: Ram16K::new(),
+            data: Memory::new(),
             rom: Rom32K::new(),
+            fault: None,
+            cycles: 0,
+            keyboard: None,
+            watchpoints: Watchpoints::new(),
+            tracer: None,
         };
-        cpu.data.set(0, 256); // Stack Pointer
-        cpu.data.set(1, 300); // LCL
-        cpu.data.set(2, 400); // ARG
-        cpu.data.set(3, 3000); // THIS
-        cpu.data.set(4, 3010); // THAT
+        cpu.data.set(0, 256).unwrap(); // Stack Pointer
+        cpu.data.set(1, 300).unwrap(); // LCL
+        cpu.data.set(2, 400).unwrap(); // ARG
+        cpu.data.set(3, 3000).unwrap(); // THIS
+        cpu.data.set(4, 3010).unwrap(); // THAT
         cpu.data.tick();
         cpu
     }
@@ -80,10 +99,83 @@ impl Cpu {
         println!{"PC:  {:016b}", self.get_pc()};
     }
 
+    // Panics on unmapped addresses, see `read_data`
     pub fn get_data(&self, address: usize) -> u16 {
+        self.read_data(address).unwrap()
+    }
+
+    pub fn read_data(&self, address: usize) -> Result<u16, Fault> {
         self.data.get(address)
     }
 
+    pub fn get_screen(&self, address: usize) -> u16 {
+        self.data.screen().get(address)
+    }
+
+    pub fn screen_buffer(&self) -> Vec<u16> {
+        (0..SCREEN_SIZE).map(|address| self.get_screen(address)).collect()
+    }
+
+    pub fn press_key(&mut self, code: u16) {
+        self.data.keyboard().press(code);
+    }
+
+    pub fn release_key(&mut self) {
+        self.data.keyboard().release();
+    }
+
+    pub fn set_keyboard_source(&mut self, source: Box<dyn KeyboardSource>) {
+        self.keyboard = Some(source);
+    }
+
+    // Records every instruction executed from now on
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        self.tracer = Some(tracer);
+    }
+
+    pub fn take_tracer(&mut self) -> Option<Box<dyn Tracer>> {
+        self.tracer.take()
+    }
+
+    // Instructions executed since the Cpu was created
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Set when an instruction touched an unmapped address, the Cpu stops there
+    pub fn fault(&self) -> Option<&Fault> {
+        self.fault.as_ref()
+    }
+
+    // Sets the cycle count and clears the fault, for restoring earlier state
+    pub fn rewind(&mut self, cycles: u64) {
+        self.cycles = cycles;
+        self.fault = None;
+    }
+
+    // Sets every register and memory word before a single tick
+    pub fn restore_state(&mut self, state: &MachineState) {
+        self.a.set(state.a);
+        self.d.set(state.d);
+        self.pc.set(state.pc);
+        for (address, value) in state.data.iter().enumerate().take(KBD) {
+            self.data.set(address, *value).unwrap();
+        }
+        self.tick();
+        match state.data[KBD] {
+            0 => self.release_key(),
+            key => self.press_key(key),
+        }
+
+        for address in 0..ROM_SIZE {
+            self.rom.set(address, state.rom.get(address).copied().unwrap_or(0));
+        }
+        self.rom.tick();
+
+        self.cycles = state.cycles;
+        self.fault = state.fault.clone();
+    }
+
     pub fn print_instruction(&self) {
         let instruction = self.fetch();
         println!("ASM: {}", decode_instruction(instruction));
@@ -95,7 +187,7 @@ impl Cpu {
         self.print_a();
         self.print_d();
         self.print_pc();
-        println!("SP {:016b}", self.data.get(0));
+        println!("SP {:016b}", self.get_data(0));
     }
 
     pub fn tick(&mut self) {
@@ -105,13 +197,13 @@ impl Cpu {
         self.data.tick();
     }
 
-    pub fn load_from_string(&mut self, contents: &str) {
-        self.rom.load_from_string(contents);
+    pub fn load_from_string(&mut self, contents: &str) -> Result<(), Vec<Error>> {
+        self.rom.load_from_string(contents)
     }
 
     pub fn fetch(&self) -> u16 {
         let address = self.get_pc() as usize;
-        self.rom.get(address)
+        self.rom.get(address % ROM_SIZE)
     }
 
     pub fn execute(&mut self, instruction: u16) {
@@ -122,7 +214,18 @@ impl Cpu {
             let is_memory = get_bit(instruction, 12);
             let a_register = if is_memory{
                 let address = self.get_a() as usize;
-                self.data.get(address)
+                match self.data.get(address) {
+                    Ok(value) => {
+                        if self.watchpoints.is_active() {
+                            self.watchpoints.on_read(self.get_pc(), instruction, address, value);
+                        }
+                        value
+                    }
+                    Err(fault) => {
+                        self.fault = Some(fault);
+                        return;
+                    }
+                }
             } else {
                 self.get_a()
             };
@@ -144,7 +247,16 @@ impl Cpu {
             let d3 = get_bit(instruction, 3);
 
             if d3 {
-                self.data.set(self.get_a() as usize, output);
+                let address = self.get_a() as usize;
+                if self.watchpoints.is_active() {
+                    if let Ok(old) = self.data.get(address) {
+                        self.watchpoints.on_write(self.get_pc(), instruction, address, old, output);
+                    }
+                }
+                if let Err(fault) = self.data.set(address, output) {
+                    self.fault = Some(fault);
+                    return;
+                }
             }
 
             if d2 {
@@ -185,12 +297,39 @@ impl Cpu {
     }
 
     pub fn clock(&mut self) -> bool {
+        // The program ran or jumped past the last ROM address
+        if self.fault.is_none() && self.get_pc() as usize >= ROM_SIZE {
+            self.fault = Some(Fault::RomOutOfRange(self.get_pc() as usize));
+        }
         let instruction = self.fetch();
-        if instruction == HALT {
+        if instruction == HALT || self.fault.is_some() {
             return false;
         }
+        if let Some(code) = self.keyboard.as_mut().and_then(|source| source.poll(self.cycles)) {
+            self.press_key(code);
+        }
+
+        let pc = self.get_pc();
+        self.watchpoints.hits.clear();
+        let watching = self.watchpoints.is_active();
+        let before = if watching { self.watchpoints.sample(self) } else { vec![] };
+        // A, D and M before the instruction, only read while tracing
+        let traced = self.tracer.is_some()
+            .then(|| (self.get_a(), self.get_d(), self.read_data(self.get_a() as usize).unwrap_or(0)));
         self.execute(instruction);
         self.tick();
+        if watching {
+            let after = self.watchpoints.sample(self);
+            self.watchpoints.check_values(pc, instruction, &before, &after);
+        }
+        if self.fault.is_some() {
+            return false;
+        }
+        if let Some(before) = traced {
+            let record = TraceRecord::from_step(self.cycles, pc, instruction, before, (self.get_a(), self.get_d()));
+            self.tracer.as_mut().unwrap().record(&record);
+        }
+        self.cycles += 1;
         true
     }
 
@@ -201,14 +340,113 @@ impl Cpu {
         }
     }
 
-    pub fn run(&mut self) {
-        while self.clock() {};
+}
+
+impl Machine for Cpu {
+    fn load_from_string(&mut self, contents: &str) -> Result<(), Vec<Error>> {
+        Cpu::load_from_string(self, contents)
+    }
+
+    fn get_a(&self) -> u16 {
+        Cpu::get_a(self)
+    }
+
+    fn get_d(&self) -> u16 {
+        Cpu::get_d(self)
+    }
+
+    fn get_pc(&self) -> u16 {
+        Cpu::get_pc(self)
+    }
+
+    fn read_data(&self, address: usize) -> Result<u16, Fault> {
+        Cpu::read_data(self, address)
+    }
+
+    fn write_data(&mut self, address: usize, value: u16) -> Result<(), Fault> {
+        self.data.set(address, value)?;
+        self.data.tick();
+        Ok(())
+    }
+
+    fn write_register(&mut self, register: Register, value: u16) {
+        match register {
+            Register::A => self.a.set(value),
+            Register::D => self.d.set(value),
+            Register::PC => self.pc.set(value),
+        }
+        self.a.tick();
+        self.d.tick();
+        self.pc.tick();
+    }
+
+    fn read_rom(&self, address: u16) -> u16 {
+        self.rom.get(address as usize % ROM_SIZE)
+    }
+
+    fn watchpoints(&self) -> &Watchpoints {
+        &self.watchpoints
+    }
+
+    fn watchpoints_mut(&mut self) -> &mut Watchpoints {
+        &mut self.watchpoints
+    }
+
+    fn screen_buffer(&self) -> Vec<u16> {
+        Cpu::screen_buffer(self)
+    }
+
+    fn press_key(&mut self, code: u16) {
+        Cpu::press_key(self, code)
+    }
+
+    fn release_key(&mut self) {
+        Cpu::release_key(self)
+    }
+
+    fn set_keyboard_source(&mut self, source: Box<dyn KeyboardSource>) {
+        Cpu::set_keyboard_source(self, source)
+    }
+
+    fn set_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        Cpu::set_tracer(self, tracer)
+    }
+
+    fn take_tracer(&mut self) -> Option<Box<dyn Tracer>> {
+        Cpu::take_tracer(self)
+    }
+
+    fn cycles(&self) -> u64 {
+        Cpu::cycles(self)
+    }
+
+    fn fault(&self) -> Option<&Fault> {
+        Cpu::fault(self)
+    }
+
+    fn rewind(&mut self, cycles: u64) {
+        Cpu::rewind(self, cycles)
+    }
+
+    fn restore_state(&mut self, state: &MachineState) {
+        Cpu::restore_state(self, state)
+    }
+
+    fn fetch(&self) -> u16 {
+        Cpu::fetch(self)
+    }
+
+    fn clock(&mut self) -> bool {
+        Cpu::clock(self)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::hardware::machine::{Machine, RunOptions, RunOutcome, StopReason};
+    use crate::hardware::input::ScriptedKeyboard;
+    use crate::parser::assembly::Assembler;
 
     #[test]
     fn test_cpu_new() {
@@ -537,4 +775,123 @@ mod tests {
         assert_eq!{cpu.get_pc(), memory_loc};
     }
 
+    #[test]
+    fn test_cpu_run_cycles_limit() {
+        let mut cpu = Cpu::new();
+        // @0, 0;JMP loops forever
+        cpu.load_from_string("0000000000000000\n1110101010000111").unwrap();
+
+        assert_eq!(cpu.run_cycles(10), 10);
+        assert_eq!(cpu.get_pc(), 0);
+        assert_eq!(cpu.cycles(), 10);
+    }
+
+    #[test]
+    fn test_cpu_run_observed_stops_early() {
+        let mut cpu = Cpu::new();
+        cpu.load_from_string("0000000000000000\n1110101010000111").unwrap();
+
+        let mut seen = vec![];
+        let options = RunOptions { detect_loops: false, ..RunOptions::cycles(100) };
+        let outcome = cpu.run_observed(&options, |cpu| {
+            seen.push(cpu.get_pc());
+            seen.len() < 3
+        });
+
+        assert_eq!(outcome, RunOutcome { reason: StopReason::Interrupted, cycles: 3 });
+        assert_eq!(seen, vec![1, 0, 1]);
+    }
+
+    #[test]
+    fn test_cpu_run_cycles_halt() {
+        let mut cpu = Cpu::new();
+        cpu.load_from_string("0000000000000111\n1111111111111111").unwrap();
+
+        assert_eq!(cpu.run_cycles(10), 1);
+        assert_eq!(cpu.get_a(), 7);
+        assert_eq!(cpu.fetch(), HALT);
+    }
+
+    #[test]
+    fn test_cpu_writes_screen() {
+        let mut cpu = Cpu::new();
+        // @SCREEN, M=-1, @16415, M=1
+        cpu.load_from_string("\
+0100000000000000
+1110111010001000
+0100000000011111
+1110111111001000
+1111111111111111").unwrap();
+        cpu.run(&RunOptions::new());
+
+        assert_eq!(cpu.get_screen(0), 0xFFFF);
+        assert_eq!(cpu.get_screen(31), 1);
+        assert_eq!(cpu.get_data(16384), 0xFFFF);
+        assert!(cpu.fault().is_none());
+    }
+
+    #[test]
+    fn test_cpu_reads_keyboard() {
+        let mut cpu = Cpu::new();
+        // @KBD, D=M
+        cpu.load_from_string("\
+0110000000000000
+1111110000010000
+1111111111111111").unwrap();
+        cpu.press_key(75);
+        cpu.run(&RunOptions::new());
+
+        assert_eq!(cpu.get_d(), 75);
+    }
+
+    #[test]
+    fn test_cpu_scripted_keyboard() {
+        let mut asm = Assembler::new();
+        // Wait for a key, store it in R13, wait for the release, count it in R14
+        asm.assemble_all("\
+            (WAIT)
+            @KBD
+            D=M
+            @WAIT
+            D;JEQ
+            @R13
+            M=D
+            (HELD)
+            @KBD
+            D=M
+            @HELD
+            D;JNE
+            @R14
+            M=M+1
+        ").unwrap();
+
+        let mut cpu = Cpu::new();
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
+        let script = ScriptedKeyboard::parse("100 press 'A'\n200 release").unwrap();
+        cpu.set_keyboard_source(Box::new(script));
+
+        assert!(cpu.run_cycles(1000) < 1000);
+        assert_eq!(cpu.get_data(13), 65);
+        assert_eq!(cpu.get_data(14), 1);
+        assert!(cpu.cycles() > 200);
+    }
+
+    #[test]
+    fn test_cpu_out_of_range_faults() {
+        let mut cpu = Cpu::new();
+        // @24577, M=1, @7, D=A
+        cpu.load_from_string("\
+0110000000000001
+1110111111001000
+0000000000000111
+1110110000010000
+1111111111111111").unwrap();
+        cpu.run(&RunOptions::new());
+
+        assert_eq!(cpu.fault(), Some(&Fault::AddressOutOfRange(24577)));
+        assert_eq!(cpu.get_pc(), 1);
+        assert_eq!(cpu.get_d(), 0);
+        assert!(!cpu.clock());
+    }
+
 }
@@ -0,0 +1,549 @@
+use crate::error::error::{Error, Fault};
+use crate::hardware::alu::{alu, AluFlags};
+use crate::hardware::cpu::{Cpu, HALT};
+use crate::hardware::input::KeyboardSource;
+use crate::hardware::machine::{Machine, Register};
+use crate::hardware::state::MachineState;
+use crate::hardware::trace::{TraceRecord, Tracer};
+use crate::hardware::watch::Watchpoints;
+use crate::hardware::memory::{parse_rom, KBD, ROM_SIZE, SCREEN, SCREEN_SIZE};
+
+// Same behaviour as `Cpu`, but registers and memory are plain words written
+// directly instead of clocked flip-flops, so a cycle costs one instruction
+pub struct FastCpu {
+    a: u16,
+    d: u16,
+    pc: u16,
+    data: Vec<u16>, // RAM, screen and keyboard, addressed like the Hack memory map
+    rom: Vec<u16>,
+    fault: Option<Fault>,
+    cycles: u64,
+    keyboard: Option<Box<dyn KeyboardSource>>,
+    watchpoints: Watchpoints,
+    tracer: Option<Box<dyn Tracer>>,
+}
+
+impl FastCpu {
+    pub fn new() -> Self {
+        let mut data = vec![0; KBD + 1];
+        data[0] = 256; // Stack Pointer
+        data[1] = 300; // LCL
+        data[2] = 400; // ARG
+        data[3] = 3000; // THIS
+        data[4] = 3010; // THAT
+
+        FastCpu {
+            a: 0,
+            d: 0,
+            pc: 0,
+            data,
+            rom: vec![0; ROM_SIZE],
+            fault: None,
+            cycles: 0,
+            keyboard: None,
+            watchpoints: Watchpoints::new(),
+            tracer: None,
+        }
+    }
+
+    // Panics on unmapped addresses, see `read_data`
+    pub fn get_data(&self, address: usize) -> u16 {
+        self.read_data(address).unwrap()
+    }
+
+    pub fn execute(&mut self, instruction: u16) {
+        if instruction & 0x8000 == 0 {
+            self.a = instruction;
+            self.pc = self.pc.wrapping_add(1);
+            return;
+        }
+
+        let y = if instruction & 0x1000 != 0 {
+            match self.read_data(self.a as usize) {
+                Ok(value) => {
+                    if self.watchpoints.is_active() {
+                        self.watchpoints.on_read(self.pc, instruction, self.a as usize, value);
+                    }
+                    value
+                }
+                Err(fault) => {
+                    self.fault = Some(fault);
+                    return;
+                }
+            }
+        } else {
+            self.a
+        };
+
+        let flags = AluFlags {
+            zx: instruction & 0x0800 != 0,
+            nx: instruction & 0x0400 != 0,
+            zy: instruction & 0x0200 != 0,
+            ny: instruction & 0x0100 != 0,
+            f: instruction & 0x0080 != 0,
+            no: instruction & 0x0040 != 0,
+        };
+        let (output, is_zero, is_neg) = alu(self.d, y, flags);
+
+        if instruction & 0x0008 != 0 {
+            if self.watchpoints.is_active() {
+                if let Ok(old) = self.read_data(self.a as usize) {
+                    self.watchpoints.on_write(self.pc, instruction, self.a as usize, old, output);
+                }
+            }
+            if let Err(fault) = self.write_data(self.a as usize, output) {
+                self.fault = Some(fault);
+                return;
+            }
+        }
+        if instruction & 0x0010 != 0 {
+            self.d = output;
+        }
+        // The jump target is A before this instruction wrote to it
+        let target = self.a;
+        if instruction & 0x0020 != 0 {
+            self.a = output;
+        }
+
+        let jump = match instruction & 0b111 {
+            0b000 => false,
+            0b001 => !is_zero && !is_neg,
+            0b010 => is_zero,
+            0b011 => !is_neg,
+            0b100 => is_neg,
+            0b101 => !is_zero,
+            0b110 => is_neg || is_zero,
+            _ => true,
+        };
+        self.pc = if jump { target } else { self.pc.wrapping_add(1) };
+    }
+}
+
+impl Machine for FastCpu {
+    fn load_from_string(&mut self, contents: &str) -> Result<(), Vec<Error>> {
+        let instructions = parse_rom(contents)?;
+        self.rom[..instructions.len()].copy_from_slice(&instructions);
+        Ok(())
+    }
+
+    fn get_a(&self) -> u16 {
+        self.a
+    }
+
+    fn get_d(&self) -> u16 {
+        self.d
+    }
+
+    fn get_pc(&self) -> u16 {
+        self.pc
+    }
+
+    fn read_data(&self, address: usize) -> Result<u16, Fault> {
+        self.data.get(address).copied().ok_or(Fault::AddressOutOfRange(address))
+    }
+
+    fn write_data(&mut self, address: usize, value: u16) -> Result<(), Fault> {
+        match address {
+            0..KBD => self.data[address] = value,
+            KBD => {}
+            _ => return Err(Fault::AddressOutOfRange(address)),
+        }
+        Ok(())
+    }
+
+    fn write_register(&mut self, register: Register, value: u16) {
+        match register {
+            Register::A => self.a = value,
+            Register::D => self.d = value,
+            Register::PC => self.pc = value,
+        }
+    }
+
+    fn read_rom(&self, address: u16) -> u16 {
+        self.rom[address as usize % ROM_SIZE]
+    }
+
+    fn watchpoints(&self) -> &Watchpoints {
+        &self.watchpoints
+    }
+
+    fn watchpoints_mut(&mut self) -> &mut Watchpoints {
+        &mut self.watchpoints
+    }
+
+    fn screen_buffer(&self) -> Vec<u16> {
+        self.data[SCREEN..SCREEN + SCREEN_SIZE].to_vec()
+    }
+
+    fn press_key(&mut self, code: u16) {
+        self.data[KBD] = code;
+    }
+
+    fn release_key(&mut self) {
+        self.data[KBD] = 0;
+    }
+
+    fn set_keyboard_source(&mut self, source: Box<dyn KeyboardSource>) {
+        self.keyboard = Some(source);
+    }
+
+    fn set_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        self.tracer = Some(tracer);
+    }
+
+    fn take_tracer(&mut self) -> Option<Box<dyn Tracer>> {
+        self.tracer.take()
+    }
+
+    fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    fn fault(&self) -> Option<&Fault> {
+        self.fault.as_ref()
+    }
+
+    fn rewind(&mut self, cycles: u64) {
+        self.cycles = cycles;
+        self.fault = None;
+    }
+
+    fn restore_state(&mut self, state: &MachineState) {
+        self.a = state.a;
+        self.d = state.d;
+        self.pc = state.pc;
+        self.data.copy_from_slice(&state.data);
+        self.rom.fill(0);
+        self.rom[..state.rom.len()].copy_from_slice(&state.rom);
+        self.cycles = state.cycles;
+        self.fault = state.fault.clone();
+    }
+
+    fn fetch(&self) -> u16 {
+        self.read_rom(self.pc)
+    }
+
+    fn clock(&mut self) -> bool {
+        if self.fault.is_none() && self.pc as usize >= ROM_SIZE {
+            self.fault = Some(Fault::RomOutOfRange(self.pc as usize));
+        }
+        let instruction = self.fetch();
+        if instruction == HALT || self.fault.is_some() {
+            return false;
+        }
+        if let Some(code) = self.keyboard.as_mut().and_then(|source| source.poll(self.cycles)) {
+            self.press_key(code);
+        }
+
+        let pc = self.pc;
+        self.watchpoints.hits.clear();
+        let watching = self.watchpoints.is_active();
+        let before = if watching { self.watchpoints.sample(self) } else { vec![] };
+        // A, D and M before the instruction, only read while tracing
+        let traced = self.tracer.is_some()
+            .then(|| (self.get_a(), self.get_d(), self.read_data(self.get_a() as usize).unwrap_or(0)));
+        self.execute(instruction);
+        if watching {
+            let after = self.watchpoints.sample(self);
+            self.watchpoints.check_values(pc, instruction, &before, &after);
+        }
+        if self.fault.is_some() {
+            return false;
+        }
+        if let Some(before) = traced {
+            let record = TraceRecord::from_step(self.cycles, pc, instruction, before, (self.get_a(), self.get_d()));
+            self.tracer.as_mut().unwrap().record(&record);
+        }
+        self.cycles += 1;
+        true
+    }
+}
+
+// Runs both cores in lockstep and reports the first cycle where they disagree
+pub fn compare_cores(binaries: &str, max_cycles: u64) -> Result<u64, String> {
+    let mut reference = Cpu::new();
+    let mut fast = FastCpu::new();
+    reference.load_from_string(binaries).map_err(|errors| format!("{:?}", errors))?;
+    Machine::load_from_string(&mut fast, binaries).map_err(|errors| format!("{:?}", errors))?;
+
+    let mut executed = 0;
+    loop {
+        let state = |cpu: &dyn Machine| (cpu.get_a(), cpu.get_d(), cpu.get_pc(), cpu.fault().cloned());
+        if state(&reference) != state(&fast) {
+            return Err(format!(
+                "cycle {}: reference (A, D, PC, fault) = {:?}, fast = {:?}",
+                executed, state(&reference), state(&fast),
+            ));
+        }
+        if executed == max_cycles {
+            break;
+        }
+
+        let (ran_reference, ran_fast) = (reference.clock(), fast.clock());
+        if ran_reference != ran_fast {
+            return Err(format!("cycle {}: only one core stopped", executed));
+        }
+        if !ran_reference {
+            break;
+        }
+        executed += 1;
+    }
+
+    for address in 0..=KBD {
+        let (expected, actual) = (reference.get_data(address), fast.get_data(address));
+        if expected != actual {
+            return Err(format!(
+                "after {} cycles: memory[{}] is {} in the reference core, {} in the fast one",
+                executed, address, expected, actual,
+            ));
+        }
+    }
+    Ok(executed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::hardware::machine::{RunOptions, StopReason};
+    use crate::hardware::watch::{Access, Comparison, Operand, Watch};
+    use crate::parser::assembly::Assembler;
+    use crate::parser::table::{comp_table, dest_table, jump_table};
+    use crate::stack::stack::Stack;
+
+    fn assemble(source: &str) -> String {
+        let mut asm = Assembler::new();
+        asm.assemble_all(source).unwrap();
+        asm.binaries.join("\n")
+    }
+
+    #[test]
+    fn test_fast_cpu_new() {
+        let cpu = FastCpu::new();
+
+        assert_eq!(cpu.get_a(), 0);
+        assert_eq!(cpu.get_d(), 0);
+        assert_eq!(cpu.get_pc(), 0);
+        assert_eq!(cpu.get_data(0), 256);
+        assert_eq!(cpu.get_data(4), 3010);
+    }
+
+    #[test]
+    fn test_fast_cpu_runs_program() {
+        let mut cpu = FastCpu::new();
+        // RAM[13] = 6 * 7
+        cpu.load_from_string(&assemble("\
+            @13
+            M=0
+            @6
+            D=A
+            @14
+            M=D
+            (LOOP)
+            @14
+            D=M
+            @END
+            D;JEQ
+            @7
+            D=A
+            @13
+            M=D+M
+            @14
+            M=M-1
+            @LOOP
+            0;JMP
+            (END)
+        ")).unwrap();
+
+        let executed = cpu.run_cycles(1000);
+        assert_eq!(cpu.fetch(), HALT);
+        assert_eq!(cpu.cycles(), executed);
+        assert_eq!(cpu.get_data(13), 42);
+    }
+
+    #[test]
+    fn test_fast_cpu_keyboard_and_faults() {
+        let mut cpu = FastCpu::new();
+        cpu.load_from_string(&assemble("\
+            @KBD
+            D=M
+            M=1
+            @R13
+            M=D
+            @24577
+            M=D
+        ")).unwrap();
+        cpu.press_key(65);
+
+        assert_eq!(cpu.run_cycles(100), 6);
+        assert_eq!(cpu.get_data(13), 65);
+        assert_eq!(cpu.get_data(KBD), 65);
+        assert_eq!(cpu.fault(), Some(&Fault::AddressOutOfRange(24577)));
+        assert_eq!(cpu.get_pc(), 6);
+    }
+
+    #[test]
+    fn test_fast_cpu_tracing() {
+        let mut cpu = FastCpu::new();
+        cpu.load_from_string(&assemble("@R13\nM=-1\n@24577\nM=D")).unwrap();
+        cpu.run_cycles(2);
+        let records = Rc::new(RefCell::new(Vec::new()));
+        cpu.set_tracer(Box::new(records.clone()));
+
+        assert_eq!(cpu.run_cycles(100), 1);
+        // Starts at the current cycle and leaves out the instruction that faulted
+        assert_eq!(*records.borrow(), vec![TraceRecord {
+            cycle: 2, pc: 2, instruction: 24577, a: 24577, d: 0, write: None, jumped: false,
+        }]);
+        assert!(cpu.take_tracer().is_some());
+        assert!(cpu.take_tracer().is_none());
+    }
+
+    // Every comp with every dest, and every comp with every jump for a
+    // positive, zero and negative D
+    #[test]
+    fn test_differential_every_instruction() {
+        let mut source = String::new();
+        let mut label = 0;
+        let mut case = |setup: &str, instruction: String| {
+            source.push_str(&format!(
+                "{}\n@NEXT{}\nM=-1\n{}\n(NEXT{})\n",
+                setup, label, instruction, label,
+            ));
+            label += 1;
+        };
+
+        for comp in comp_table().keys() {
+            for dest in dest_table().keys().filter(|dest| !dest.is_empty()) {
+                case("@7\nD=A", format!("{}={}", dest, comp));
+            }
+            for jump in jump_table().keys().filter(|jump| !jump.is_empty()) {
+                for setup in ["@7\nD=A", "D=0", "@7\nD=-A"] {
+                    case(setup, format!("{};{}", comp, jump));
+                }
+            }
+        }
+
+        let binaries = assemble(&source);
+        let executed = compare_cores(&binaries, u64::MAX).unwrap();
+        assert_eq!(executed as usize, binaries.lines().count() - 1);
+    }
+
+    #[test]
+    fn test_differential_vm_program() {
+        let mut stack = Stack::new();
+        stack.commands = vec![
+            "push constant 6".into(),
+            "call Main.fibonacci 1".into(),
+            "pop static 0".into(),
+            "push constant 16384".into(),
+            "pop pointer 1".into(),
+            "push static 0".into(),
+            "neg".into(),
+            "pop that 31".into(),
+            "goto END".into(),
+
+            "function Main.fibonacci 0".into(),
+            "push argument 0".into(),
+            "push constant 2".into(),
+            "lt".into(),
+            "if-goto BASE".into(),
+            "push argument 0".into(),
+            "push constant 2".into(),
+            "sub".into(),
+            "call Main.fibonacci 1".into(),
+            "push argument 0".into(),
+            "push constant 1".into(),
+            "sub".into(),
+            "call Main.fibonacci 1".into(),
+            "add".into(),
+            "return".into(),
+            "label BASE".into(),
+            "push argument 0".into(),
+            "return".into(),
+            "label END".into(),
+        ];
+        stack.assemble_all().unwrap();
+
+        let binaries = assemble(&stack.assembly.join("\n"));
+        let executed = compare_cores(&binaries, u64::MAX).unwrap();
+
+        let mut cpu = FastCpu::new();
+        cpu.load_from_string(&binaries).unwrap();
+        assert_eq!(cpu.run_cycles(u64::MAX), executed);
+        assert_eq!(cpu.screen_buffer()[31], 8u16.wrapping_neg());
+    }
+
+    #[test]
+    fn test_watchpoints_match_reference() {
+        let binaries = assemble("\
+            @SP
+            M=M+1
+            D=M
+            @SP
+            AM=M+1
+            D=D+A
+            (END)
+            @END
+            0;JMP
+        ");
+        let watches = [
+            Watch::Access { address: 0, access: Access::ReadWrite },
+            Watch::Value { operand: Operand::Memory(0), condition: Some((Comparison::Gt, 257)) },
+            Watch::Value { operand: Operand::Register(Register::D), condition: None },
+        ];
+
+        let mut reference = Cpu::new();
+        let mut fast = FastCpu::new();
+        reference.load_from_string(&binaries).unwrap();
+        fast.load_from_string(&binaries).unwrap();
+        for watch in watches {
+            reference.watchpoints_mut().add(watch.clone());
+            fast.watchpoints_mut().add(watch);
+        }
+
+        let mut outcomes = vec![];
+        loop {
+            let outcome = fast.run(&RunOptions::new());
+            assert_eq!(reference.run(&RunOptions::new()), outcome);
+            if !matches!(outcome.reason, StopReason::Watchpoint(_)) {
+                break;
+            }
+            outcomes.push(outcome.to_string());
+        }
+        assert_eq!(outcomes, vec![
+            "Stopped after 2 cycles\n\
+             Watchpoint 0 (access RAM[0]) at PC 1, M=M+1: 256 -> 256\n\
+             Watchpoint 0 (access RAM[0]) at PC 1, M=M+1: 256 -> 257",
+            "Stopped after 1 cycles\n\
+             Watchpoint 0 (access RAM[0]) at PC 2, D=M: 257 -> 257\n\
+             Watchpoint 2 (change D) at PC 2, D=M: 0 -> 257",
+            "Stopped after 2 cycles\n\
+             Watchpoint 0 (access RAM[0]) at PC 4, AM=M+1: 257 -> 257\n\
+             Watchpoint 0 (access RAM[0]) at PC 4, AM=M+1: 257 -> 258\n\
+             Watchpoint 1 (RAM[0] > 257) at PC 4, AM=M+1: 257 -> 258",
+            "Stopped after 1 cycles\n\
+             Watchpoint 2 (change D) at PC 5, D=D+A: 257 -> 515",
+        ]);
+    }
+
+    #[test]
+    fn test_differential_fault() {
+        let binaries = assemble("@32767\nD=A\n@100\nM=D\n@32767\nM=1");
+        assert_eq!(compare_cores(&binaries, 100), Ok(5));
+    }
+
+    #[test]
+    fn test_differential_rom_fault() {
+        // Jumping past the end of ROM faults in both cores instead of wrapping around
+        let binaries = assemble("@32767\nD=A\nA=D+1\n0;JMP");
+        assert_eq!(compare_cores(&binaries, 100), Ok(4));
+
+        let mut cpu = FastCpu::new();
+        cpu.load_from_string(&binaries).unwrap();
+        let outcome = cpu.run(&RunOptions::new());
+        assert_eq!(outcome.reason, StopReason::Faulted(Fault::RomOutOfRange(32768)));
+        assert_eq!(cpu.get_pc(), 32768);
+    }
+}
@@ -0,0 +1,222 @@
+use crate::error::error::{Error, ErrorKind, Location};
+
+// Hack keyboard codes for keys that aren't plain ASCII
+pub const KEY_NEWLINE: u16 = 128;
+pub const KEY_BACKSPACE: u16 = 129;
+pub const KEY_LEFT: u16 = 130;
+pub const KEY_UP: u16 = 131;
+pub const KEY_RIGHT: u16 = 132;
+pub const KEY_DOWN: u16 = 133;
+pub const KEY_HOME: u16 = 134;
+pub const KEY_END: u16 = 135;
+pub const KEY_PAGE_UP: u16 = 136;
+pub const KEY_PAGE_DOWN: u16 = 137;
+pub const KEY_INSERT: u16 = 138;
+pub const KEY_DELETE: u16 = 139;
+pub const KEY_ESCAPE: u16 = 140;
+pub const KEY_F1: u16 = 141;
+
+const DEFAULT_TYPE_CYCLES: u64 = 1000;
+
+// Drives the KBD register, polled by the Cpu before every instruction
+pub trait KeyboardSource {
+    // The key held down at `cycle` (0 for none), or None to leave KBD unchanged
+    fn poll(&mut self, cycle: u64) -> Option<u16>;
+}
+
+// Replays key presses and releases at fixed cycles
+pub struct ScriptedKeyboard {
+    events: Vec<(u64, u16)>,
+    next: usize,
+}
+
+impl ScriptedKeyboard {
+    pub fn new(mut events: Vec<(u64, u16)>) -> Self {
+        events.sort_by_key(|(cycle, _)| *cycle);
+        ScriptedKeyboard { events, next: 0 }
+    }
+
+    // One entry per line or `;`, `#` starts a comment:
+    //   10000 press A        (a character, a key code or a key name like NEWLINE)
+    //   12000 release
+    //   15000 type "hello" 500   (press and release each key for 500 cycles)
+    pub fn parse(script: &str) -> Result<Self, Vec<Error>> {
+        let mut events = vec![];
+        let mut errors = vec![];
+
+        for (i, line) in script.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(j) => &line[..j],
+                None => line,
+            };
+            for entry in line.split(';').map(str::trim).filter(|entry| !entry.is_empty()) {
+                if let Err(kind) = parse_entry(entry, &mut events) {
+                    let column = entry.as_ptr() as usize - line.as_ptr() as usize + 1;
+                    errors.push(Error::new(kind, Location::new("", i + 1, column)));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(ScriptedKeyboard::new(events))
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn events(&self) -> &[(u64, u16)] {
+        &self.events
+    }
+}
+
+impl KeyboardSource for ScriptedKeyboard {
+    fn poll(&mut self, cycle: u64) -> Option<u16> {
+        let mut key = None;
+        while let Some(&(at, code)) = self.events.get(self.next) {
+            if at > cycle {
+                break;
+            }
+            key = Some(code);
+            self.next += 1;
+        }
+        key
+    }
+}
+
+fn parse_entry(entry: &str, events: &mut Vec<(u64, u16)>) -> Result<(), ErrorKind> {
+    let invalid = || ErrorKind::InvalidKeyScript(entry.to_string());
+
+    let entry = entry.strip_prefix("at cycle").unwrap_or(entry).trim_start();
+    let (cycle, rest) = entry.split_once(char::is_whitespace).ok_or_else(invalid)?;
+    let cycle: u64 = cycle.parse().map_err(|_| invalid())?;
+    let rest = rest.trim();
+
+    if rest == "release" {
+        events.push((cycle, 0));
+    } else if let Some(key) = rest.strip_prefix("press") {
+        let code = key_code(key.trim()).ok_or_else(invalid)?;
+        events.push((cycle, code));
+    } else if let Some(text) = rest.strip_prefix("type") {
+        let text = text.trim();
+        let close = text.rfind('"').filter(|close| text.starts_with('"') && *close > 0)
+            .ok_or_else(invalid)?;
+        let hold = match text[close + 1..].trim() {
+            "" => DEFAULT_TYPE_CYCLES,
+            hold => hold.parse().map_err(|_| invalid())?,
+        };
+
+        // A hold that runs past the last cycle would put releases before presses
+        let mut at: u64 = cycle;
+        for c in text[1..close].chars() {
+            let code = key_code(&c.to_string()).ok_or_else(invalid)?;
+            let release = at.checked_add(hold).ok_or_else(invalid)?;
+            events.push((at, code));
+            events.push((release, 0));
+            at = release.checked_add(hold).ok_or_else(invalid)?;
+        }
+    } else {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+pub fn key_code(key: &str) -> Option<u16> {
+    let unquoted = key.strip_prefix('\'').and_then(|k| k.strip_suffix('\'')).unwrap_or(key);
+    let mut chars = unquoted.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return (c.is_ascii() && !c.is_ascii_control()).then_some(c as u16);
+    }
+
+    if let Ok(code) = key.parse() {
+        return Some(code);
+    }
+
+    let code = match key.to_ascii_uppercase().as_str() {
+        "SPACE" => b' ' as u16,
+        "NEWLINE" | "ENTER" => KEY_NEWLINE,
+        "BACKSPACE" => KEY_BACKSPACE,
+        "LEFT" => KEY_LEFT,
+        "UP" => KEY_UP,
+        "RIGHT" => KEY_RIGHT,
+        "DOWN" => KEY_DOWN,
+        "HOME" => KEY_HOME,
+        "END" => KEY_END,
+        "PAGEUP" => KEY_PAGE_UP,
+        "PAGEDOWN" => KEY_PAGE_DOWN,
+        "INSERT" => KEY_INSERT,
+        "DELETE" => KEY_DELETE,
+        "ESC" | "ESCAPE" => KEY_ESCAPE,
+        name => {
+            let n: u16 = name.strip_prefix('F')?.parse().ok()?;
+            if !(1..=12).contains(&n) {
+                return None;
+            }
+            KEY_F1 + n - 1
+        }
+    };
+    Some(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_code() {
+        assert_eq!(key_code("A"), Some(65));
+        assert_eq!(key_code("'a'"), Some(97));
+        assert_eq!(key_code("7"), Some(55));
+        assert_eq!(key_code("65"), Some(65));
+        assert_eq!(key_code("newline"), Some(KEY_NEWLINE));
+        assert_eq!(key_code("F12"), Some(152));
+        assert_eq!(key_code("F13"), None);
+        assert_eq!(key_code("SHIFT"), None);
+    }
+
+    #[test]
+    fn test_parse_script() {
+        let keyboard = ScriptedKeyboard::parse("\
+            # press and let go
+            at cycle 10000 press 'A'
+            12000 release; 500 press LEFT
+            20000 type \"hi\" 100
+        ").unwrap();
+
+        assert_eq!(keyboard.events(), &[
+            (500, KEY_LEFT),
+            (10000, 65),
+            (12000, 0),
+            (20000, 104),
+            (20100, 0),
+            (20200, 105),
+            (20300, 0),
+        ]);
+    }
+
+    #[test]
+    fn test_parse_script_errors() {
+        let errors = ScriptedKeyboard::parse("10 press A\nsoon press B\n20 press SHIFT; 30 hop").err().unwrap();
+
+        let positions: Vec<(usize, usize)> = errors.iter()
+            .map(|error| (error.location.line, error.location.column))
+            .collect();
+        assert_eq!(positions, vec![(2, 1), (3, 1), (3, 17)]);
+        assert_eq!(errors[0].kind, ErrorKind::InvalidKeyScript("soon press B".to_string()));
+
+        let entry = "10 type \"ab\" 9223372036854775807";
+        let errors = ScriptedKeyboard::parse(entry).err().unwrap();
+        assert_eq!(errors[0].kind, ErrorKind::InvalidKeyScript(entry.to_string()));
+    }
+
+    #[test]
+    fn test_scripted_poll() {
+        let mut keyboard = ScriptedKeyboard::new(vec![(10, 65), (20, 0), (20, 66)]);
+
+        assert_eq!(keyboard.poll(0), None);
+        assert_eq!(keyboard.poll(10), Some(65));
+        assert_eq!(keyboard.poll(11), None);
+        // Skipped past both events at once, the last one wins
+        assert_eq!(keyboard.poll(25), Some(66));
+        assert_eq!(keyboard.poll(100), None);
+    }
+}
@@ -0,0 +1,304 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::error::error::{Error, Fault};
+use crate::hardware::input::KeyboardSource;
+use crate::hardware::state::MachineState;
+use crate::hardware::trace::Tracer;
+use crate::hardware::watch::{WatchHit, Watchpoints};
+
+// How often the wall clock is checked against the timeout
+const TIMEOUT_CHECK_CYCLES: u64 = 4096;
+
+// Limits for `Machine::run`, no limit when a field is None
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunOptions {
+    pub max_cycles: Option<u64>,
+    pub timeout: Option<Duration>,
+    // Treat an `@LOOP` / `0;JMP` loop back onto itself as the end of the program
+    pub detect_loops: bool,
+}
+
+impl RunOptions {
+    pub fn new() -> Self {
+        RunOptions { max_cycles: None, timeout: None, detect_loops: true }
+    }
+
+    pub fn cycles(max_cycles: u64) -> Self {
+        RunOptions { max_cycles: Some(max_cycles), ..RunOptions::new() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Register {
+    A,
+    D,
+    PC,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopReason {
+    // Fetched the HALT sentinel
+    Halted,
+    // Spinning in a jump to itself at this PC
+    LoopDetected(u16),
+    BudgetExhausted,
+    TimedOut,
+    Faulted(Fault),
+    // What the last instruction triggered
+    Watchpoint(Vec<WatchHit>),
+    // The observer asked to stop
+    Interrupted,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunOutcome {
+    pub reason: StopReason,
+    // Instructions executed by this run
+    pub cycles: u64,
+}
+
+impl fmt::Display for RunOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.reason {
+            StopReason::Halted => write!(f, "Halted after {} cycles", self.cycles),
+            StopReason::LoopDetected(pc) => write!(f, "Halted in a loop at PC {} after {} cycles", pc, self.cycles),
+            StopReason::BudgetExhausted => write!(f, "Stopped after {} cycles (cycle limit reached)", self.cycles),
+            StopReason::TimedOut => write!(f, "Stopped after {} cycles (timed out)", self.cycles),
+            StopReason::Faulted(fault) => write!(f, "Faulted after {} cycles: {}", self.cycles, fault),
+            StopReason::Watchpoint(hits) => {
+                write!(f, "Stopped after {} cycles", self.cycles)?;
+                for hit in hits {
+                    write!(f, "\n{}", hit)?;
+                }
+                Ok(())
+            }
+            StopReason::Interrupted => write!(f, "Interrupted after {} cycles", self.cycles),
+        }
+    }
+}
+
+// An unconditional jump that doesn't read M or write anything
+fn is_idle_jump(instruction: u16) -> bool {
+    instruction & 0xF000 == 0xE000 && instruction & 0b111_111 == 0b000_111
+}
+
+// What running a program needs from an emulator core, implemented by the
+// gate-level `Cpu` and by the array-backed `FastCpu`
+pub trait Machine {
+    fn load_from_string(&mut self, contents: &str) -> Result<(), Vec<Error>>;
+
+    fn get_a(&self) -> u16;
+
+    fn get_d(&self) -> u16;
+
+    fn get_pc(&self) -> u16;
+
+    fn read_data(&self, address: usize) -> Result<u16, Fault>;
+
+    // Takes effect immediately, for debuggers and test setup
+    fn write_data(&mut self, address: usize, value: u16) -> Result<(), Fault>;
+
+    fn write_register(&mut self, register: Register, value: u16);
+
+    fn read_rom(&self, address: u16) -> u16;
+
+    fn watchpoints(&self) -> &Watchpoints;
+
+    fn watchpoints_mut(&mut self) -> &mut Watchpoints;
+
+    fn screen_buffer(&self) -> Vec<u16>;
+
+    fn press_key(&mut self, code: u16);
+
+    fn release_key(&mut self);
+
+    fn set_keyboard_source(&mut self, source: Box<dyn KeyboardSource>);
+
+    // Records every instruction executed from now on, see `trace`
+    fn set_tracer(&mut self, tracer: Box<dyn Tracer>);
+
+    fn take_tracer(&mut self) -> Option<Box<dyn Tracer>>;
+
+    fn cycles(&self) -> u64;
+
+    fn fault(&self) -> Option<&Fault>;
+
+    // Sets the cycle count and clears the fault, for restoring earlier state
+    fn rewind(&mut self, cycles: u64);
+
+    fn save_state(&self) -> MachineState {
+        MachineState::capture(self)
+    }
+
+    // Replaces registers, memory, ROM, cycle count and fault, but keeps the
+    // keyboard source, watchpoints and tracer
+    fn restore_state(&mut self, state: &MachineState);
+
+    fn fetch(&self) -> u16;
+
+    // Executes one instruction, false once the program halted or faulted
+    fn clock(&mut self) -> bool;
+
+    // Returns the number of instructions executed before halting or hitting the limit
+    fn run_cycles(&mut self, max_cycles: u64) -> u64
+    where
+        Self: Sized,
+    {
+        let options = RunOptions { detect_loops: false, ..RunOptions::cycles(max_cycles) };
+        self.run(&options).cycles
+    }
+
+    fn run(&mut self, options: &RunOptions) -> RunOutcome
+    where
+        Self: Sized,
+    {
+        self.run_observed(options, |_| true)
+    }
+
+    // Like `run`, calling `observer` after every instruction until it returns false
+    fn run_observed<F: FnMut(&Self) -> bool>(&mut self, options: &RunOptions, mut observer: F) -> RunOutcome
+    where
+        Self: Sized,
+    {
+        let started = Instant::now();
+        let mut cycles = 0;
+        // The A-instruction executed just before the current one, as (pc, value)
+        let mut last_load = None;
+
+        let reason = loop {
+            if options.max_cycles.is_some_and(|max| cycles >= max) {
+                break StopReason::BudgetExhausted;
+            }
+            if cycles % TIMEOUT_CHECK_CYCLES == 0 && options.timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+                break StopReason::TimedOut;
+            }
+
+            let pc = self.get_pc();
+            let instruction = self.fetch();
+            // `0;JMP` to itself, or `@LOOP` followed by `0;JMP` back to that `@LOOP`
+            if options.detect_loops && is_idle_jump(instruction)
+                && (self.get_a() == pc || last_load == Some((self.get_a(), self.get_a()))) {
+                break StopReason::LoopDetected(self.get_a());
+            }
+            last_load = (instruction & 0x8000 == 0).then_some((pc, instruction));
+
+            if !self.clock() {
+                break match self.fault() {
+                    Some(fault) => StopReason::Faulted(fault.clone()),
+                    None => StopReason::Halted,
+                };
+            }
+            cycles += 1;
+            if !self.watchpoints().hits.is_empty() {
+                break StopReason::Watchpoint(self.watchpoints().hits.clone());
+            }
+            if !observer(self) {
+                break StopReason::Interrupted;
+            }
+        };
+        RunOutcome { reason, cycles }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::hardware::fast::FastCpu;
+    use crate::parser::assembly::Assembler;
+
+    // Counts R0 down from 20, adding each value into R1, then writes past the end of memory
+    pub(crate) const COUNTDOWN: &str = "\
+        @20
+        D=A
+        @R0
+        M=D
+        (LOOP)
+        @R0
+        D=M
+        @R1
+        M=D+M
+        @R0
+        MD=M-1
+        @LOOP
+        D;JGT
+        @24577
+        M=1
+    ";
+
+    // Assembles a program into either core, for the tests of anything built on Machine
+    pub(crate) fn load_program<M: Machine>(mut cpu: M, source: &str) -> M {
+        let mut asm = Assembler::new();
+        asm.assemble_all(source).unwrap();
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
+        cpu
+    }
+
+    fn load(source: &str) -> FastCpu {
+        load_program(FastCpu::new(), source)
+    }
+
+    #[test]
+    fn test_run_detects_end_loop() {
+        let mut cpu = load("\
+            @7
+            D=A
+            @R13
+            M=D
+            (END)
+            @END
+            0;JMP
+        ");
+
+        let outcome = cpu.run(&RunOptions::new());
+        assert_eq!(outcome, RunOutcome { reason: StopReason::LoopDetected(4), cycles: 5 });
+        assert_eq!(cpu.get_data(13), 7);
+        assert_eq!(outcome.to_string(), "Halted in a loop at PC 4 after 5 cycles");
+    }
+
+    #[test]
+    fn test_run_detects_self_jump() {
+        // @1, 0;JMP with A already pointing at the jump
+        let mut cpu = load("@1\n0;JMP");
+        assert_eq!(cpu.run(&RunOptions::new()).reason, StopReason::LoopDetected(1));
+    }
+
+    #[test]
+    fn test_run_keeps_polling_loops() {
+        // Waits for a key, which is a loop but not an idle one
+        let mut cpu = load("(WAIT)\n@KBD\nD=M\n@WAIT\nD;JEQ");
+
+        let outcome = cpu.run(&RunOptions::cycles(1000));
+        assert_eq!(outcome, RunOutcome { reason: StopReason::BudgetExhausted, cycles: 1000 });
+    }
+
+    #[test]
+    fn test_run_without_loop_detection() {
+        let mut cpu = load("(END)\n@END\n0;JMP");
+        let options = RunOptions { detect_loops: false, ..RunOptions::cycles(50) };
+
+        assert_eq!(cpu.run(&options).reason, StopReason::BudgetExhausted);
+        assert_eq!(cpu.cycles(), 50);
+    }
+
+    #[test]
+    fn test_run_halts_and_faults() {
+        let mut cpu = load("@3\nD=A");
+        assert_eq!(cpu.run(&RunOptions::new()), RunOutcome { reason: StopReason::Halted, cycles: 2 });
+
+        let mut cpu = load("@30000\nM=1");
+        let outcome = cpu.run(&RunOptions::new());
+        assert_eq!(outcome.reason, StopReason::Faulted(Fault::AddressOutOfRange(30000)));
+        assert_eq!(outcome.cycles, 1);
+    }
+
+    #[test]
+    fn test_run_times_out() {
+        let mut cpu = load("(LOOP)\n@LOOP\nD=D+1;JMP");
+        let options = RunOptions { timeout: Some(Duration::from_millis(20)), ..RunOptions::new() };
+
+        let outcome = cpu.run(&options);
+        assert_eq!(outcome.reason, StopReason::TimedOut);
+        assert!(outcome.cycles > 0);
+    }
+}
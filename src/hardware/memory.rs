@@ -1,5 +1,7 @@
 use std::array::from_fn;
 
+use crate::error::error::{column_of, Error, ErrorKind, Fault, Location};
+
 pub struct Dff {
     input: u16,
     output: u16,
@@ -104,6 +106,113 @@ impl Ram16K {
     }
 }
 
+pub const SCREEN: usize = 16 * 1024;
+pub const SCREEN_SIZE: usize = 8 * 1024;
+pub const KBD: usize = 24 * 1024;
+pub const ROM_SIZE: usize = 32 * 1024;
+
+pub struct Screen {
+    registers: [Register16; SCREEN_SIZE], // 8K = 512 x 256 pixels, 16 per word
+}
+
+impl Screen {
+    pub fn new() -> Self {
+        Screen {
+            registers: from_fn(|_| Register16::new()),
+        }
+    }
+
+    pub fn get(&self, address: usize) -> u16 {
+        assert!(address < SCREEN_SIZE);
+        self.registers[address].get()
+    }
+
+    pub fn set(&mut self, address: usize, value: u16) {
+        assert!(address < SCREEN_SIZE);
+        self.registers[address].set(value);
+    }
+
+    pub fn tick(&mut self) {
+        for reg in self.registers.iter_mut() {
+            reg.tick();
+        }
+    }
+}
+
+pub struct Keyboard {
+    reg: Register16,
+}
+
+impl Keyboard {
+    pub fn new() -> Self {
+        Keyboard { reg: Register16::new() }
+    }
+
+    // Driven from outside the Cpu, so the key is visible straight away
+    pub fn press(&mut self, code: u16) {
+        self.reg.set(code);
+        self.reg.tick();
+    }
+
+    pub fn release(&mut self) {
+        self.press(0);
+    }
+
+    pub fn get(&self) -> u16 {
+        self.reg.get()
+    }
+}
+
+// The Hack data memory map: RAM, then the screen buffer, then the keyboard
+pub struct Memory {
+    ram: Ram16K,
+    screen: Screen,
+    keyboard: Keyboard,
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Memory {
+            ram: Ram16K::new(),
+            screen: Screen::new(),
+            keyboard: Keyboard::new(),
+        }
+    }
+
+    pub fn get(&self, address: usize) -> Result<u16, Fault> {
+        match address {
+            0..SCREEN => Ok(self.ram.get(address)),
+            SCREEN..KBD => Ok(self.screen.get(address - SCREEN)),
+            KBD => Ok(self.keyboard.get()),
+            _ => Err(Fault::AddressOutOfRange(address)),
+        }
+    }
+
+    // The keyboard is read-only, writes to it are dropped like on the real chip
+    pub fn set(&mut self, address: usize, value: u16) -> Result<(), Fault> {
+        match address {
+            0..SCREEN => self.ram.set(address, value),
+            SCREEN..KBD => self.screen.set(address - SCREEN, value),
+            KBD => {}
+            _ => return Err(Fault::AddressOutOfRange(address)),
+        }
+        Ok(())
+    }
+
+    pub fn screen(&self) -> &Screen {
+        &self.screen
+    }
+
+    pub fn keyboard(&mut self) -> &mut Keyboard {
+        &mut self.keyboard
+    }
+
+    pub fn tick(&mut self) {
+        self.ram.tick();
+        self.screen.tick();
+    }
+}
+
 pub struct Rom32K {
     registers: [Register16; 32 * 1024], // 32K = 32768
 }
@@ -131,26 +240,46 @@ impl Rom32K {
         }
     }
 
-    pub fn load_from_string(&mut self, contents: &str) {
-        for (i, line) in contents.lines().enumerate() {
-            if i >= 32 * 1024 {
-                panic!("ROM file exceeds 32K instruction limit.");
-            }
+    pub fn load_from_string(&mut self, contents: &str) -> Result<(), Vec<Error>> {
+        for (address, instruction) in parse_rom(contents)?.into_iter().enumerate() {
+            self.set(address, instruction);
+        }
+        self.tick();
+        Ok(())
+    }
+}
 
-            let line = line.trim();
-            if line.is_empty() {
-                continue;
-            }
+// One 16-character binary word per non-blank line, at most 32K of them
+pub fn parse_rom(contents: &str) -> Result<Vec<u16>, Vec<Error>> {
+    let mut errors = vec![];
+    let mut instructions = vec![];
+
+    for (i, raw) in contents.lines().enumerate() {
+        let line = raw.trim();
+        if line.is_empty() {
+            continue;
+        }
 
-            let instruction = u16::from_str_radix(line, 2)
-                .unwrap_or_else(|_| panic!("Invalid binary '{}' on line {}", line, i + 1));
+        let location = Location::new("", i + 1, column_of(raw, line));
+        if instructions.len() >= ROM_SIZE {
+            errors.push(Error::new(ErrorKind::RomOverflow, location));
+            break;
+        }
 
-            self.set(i, instruction);
+        match u16::from_str_radix(line, 2) {
+            Ok(instruction) if line.len() == 16 => instructions.push(instruction),
+            _ => {
+                errors.push(Error::new(ErrorKind::InvalidBinary(line.to_string()), location));
+                instructions.push(0);
             }
-        self.tick();
+        }
     }
 
-
+    if errors.is_empty() {
+        Ok(instructions)
+    } else {
+        Err(errors)
+    }
 }
 
 #[cfg(test)]
@@ -314,6 +443,47 @@ mod tests {
         ram.set(40000, 0x1234); // Invalid index
     }
 
+    #[test]
+    fn test_memory_routes_ram_and_screen() {
+        let mut memory = Memory::new();
+
+        memory.set(100, 0x1111).unwrap();
+        memory.set(SCREEN, 0x2222).unwrap();
+        memory.set(KBD - 1, 0x3333).unwrap();
+        memory.tick();
+
+        assert_eq!(memory.get(100), Ok(0x1111));
+        assert_eq!(memory.get(SCREEN), Ok(0x2222));
+        assert_eq!(memory.get(KBD - 1), Ok(0x3333));
+        assert_eq!(memory.screen().get(0), 0x2222);
+        assert_eq!(memory.screen().get(SCREEN_SIZE - 1), 0x3333);
+    }
+
+    #[test]
+    fn test_memory_keyboard() {
+        let mut memory = Memory::new();
+        assert_eq!(memory.get(KBD), Ok(0));
+
+        memory.keyboard().press(65);
+        assert_eq!(memory.get(KBD), Ok(65));
+
+        // Writes to the keyboard are ignored
+        memory.set(KBD, 1).unwrap();
+        memory.tick();
+        assert_eq!(memory.get(KBD), Ok(65));
+
+        memory.keyboard().release();
+        assert_eq!(memory.get(KBD), Ok(0));
+    }
+
+    #[test]
+    fn test_memory_out_of_range() {
+        let mut memory = Memory::new();
+
+        assert_eq!(memory.get(KBD + 1), Err(Fault::AddressOutOfRange(KBD + 1)));
+        assert_eq!(memory.set(0x7FFF, 1), Err(Fault::AddressOutOfRange(0x7FFF)));
+    }
+
     #[test]
     fn test_rom32k_basic() {
         let mut rom = Rom32K::new();
@@ -334,7 +504,7 @@ mod tests {
 0000000000000011
 1110001100001000
 ";
-        rom.load_from_string(contents);
+        rom.load_from_string(contents).unwrap();
 
         // Check that each line was loaded properly
         let expected: [u16; 4] = [
@@ -350,12 +520,27 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "Invalid binary")]
     fn test_rom32k_load_from_string_invalid_binary() {
         let mut rom = Rom32K::new();
 
-        let contents = "Not a binary";
-        rom.load_from_string(contents);
+        let contents = "0000000000000001\nNot a binary\n01";
+        let errors = rom.load_from_string(contents).unwrap_err();
+
+        assert_eq!(errors, vec![
+            Error::new(ErrorKind::InvalidBinary("Not a binary".to_string()), Location::new("", 2, 1)),
+            Error::new(ErrorKind::InvalidBinary("01".to_string()), Location::new("", 3, 1)),
+        ]);
+    }
+
+    #[test]
+    fn test_rom32k_load_from_string_overflow() {
+        let mut rom = Rom32K::new();
+
+        let contents = "0000000000000000\n".repeat(32 * 1024 + 1);
+        let errors = rom.load_from_string(&contents).unwrap_err();
 
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ErrorKind::RomOverflow);
+        assert_eq!(errors[0].location.line, 32 * 1024 + 1);
     }
 }
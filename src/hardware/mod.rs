@@ -1,4 +1,10 @@
 pub mod alu;
 pub mod cpu;
+pub mod fast;
 pub mod gates;
+pub mod input;
+pub mod machine;
 pub mod memory;
+pub mod state;
+pub mod trace;
+pub mod watch;
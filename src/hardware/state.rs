@@ -0,0 +1,194 @@
+use crate::error::error::Fault;
+use crate::hardware::machine::Machine;
+use crate::hardware::memory::{KBD, ROM_SIZE};
+
+const MAGIC: &[u8; 4] = b"HKST";
+const VERSION: u16 = 1;
+
+// Everything needed to resume a program where it was left, on either core
+#[derive(Debug, Clone, PartialEq)]
+pub struct MachineState {
+    pub a: u16,
+    pub d: u16,
+    pub pc: u16,
+    pub cycles: u64,
+    pub fault: Option<Fault>,
+    // RAM, screen and keyboard, addressed like the Hack memory map
+    pub data: Vec<u16>,
+    // Without trailing zero words
+    pub rom: Vec<u16>,
+}
+
+impl MachineState {
+    pub fn capture<M: Machine + ?Sized>(cpu: &M) -> Self {
+        let mut rom: Vec<u16> = (0..ROM_SIZE as u16).map(|address| cpu.read_rom(address)).collect();
+        while rom.last() == Some(&0) {
+            rom.pop();
+        }
+
+        MachineState {
+            a: cpu.get_a(),
+            d: cpu.get_d(),
+            pc: cpu.get_pc(),
+            cycles: cpu.cycles(),
+            fault: cpu.fault().cloned(),
+            data: (0..=KBD).map(|address| cpu.read_data(address).unwrap()).collect(),
+            rom,
+        }
+    }
+
+    // "HKST", a u16 version, then little-endian A, D and PC as u16s, the cycle
+    // count as a u64, a fault byte (0: none, 1: address out of range, 2: ROM
+    // address out of range) with the address as a u64, every data memory word,
+    // the ROM length as a u32 and the ROM
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(VERSION.to_le_bytes());
+        for register in [self.a, self.d, self.pc] {
+            bytes.extend(register.to_le_bytes());
+        }
+        bytes.extend(self.cycles.to_le_bytes());
+
+        let (kind, address) = match self.fault {
+            None => (0, 0),
+            Some(Fault::AddressOutOfRange(address)) => (1, address as u64),
+            Some(Fault::RomOutOfRange(address)) => (2, address as u64),
+        };
+        bytes.push(kind);
+        bytes.extend(address.to_le_bytes());
+
+        bytes.extend(self.data.iter().flat_map(|word| word.to_le_bytes()));
+        bytes.extend((self.rom.len() as u32).to_le_bytes());
+        bytes.extend(self.rom.iter().flat_map(|word| word.to_le_bytes()));
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader { bytes, position: 0 };
+        if reader.take(4)? != MAGIC {
+            return Err("Not a machine state file".to_string());
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(format!("Unsupported machine state version {}", version));
+        }
+
+        let (a, d, pc) = (reader.u16()?, reader.u16()?, reader.u16()?);
+        let cycles = reader.u64()?;
+        let fault = match (reader.take(1)?[0], reader.u64()?) {
+            (0, _) => None,
+            (1, address) => Some(Fault::AddressOutOfRange(address as usize)),
+            (2, address) => Some(Fault::RomOutOfRange(address as usize)),
+            (kind, _) => return Err(format!("Unknown fault kind {}", kind)),
+        };
+        let data = reader.words(KBD + 1)?;
+
+        let rom_size = u32::from_le_bytes(reader.take(4)?.try_into().unwrap()) as usize;
+        if rom_size > ROM_SIZE {
+            return Err(format!("ROM of {} words doesn't fit in {}", rom_size, ROM_SIZE));
+        }
+        let rom = reader.words(rom_size)?;
+        if reader.position != bytes.len() {
+            return Err("Unexpected data after the ROM".to_string());
+        }
+
+        Ok(MachineState { a, d, pc, cycles, fault, data, rom })
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], String> {
+        let bytes = self.bytes.get(self.position..self.position + count)
+            .ok_or_else(|| "Machine state file is truncated".to_string())?;
+        self.position += count;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn words(&mut self, count: usize) -> Result<Vec<u16>, String> {
+        Ok(self.take(2 * count)?
+            .chunks(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::cpu::Cpu;
+    use crate::hardware::fast::FastCpu;
+    use crate::hardware::machine::tests::{load_program, COUNTDOWN};
+    use crate::hardware::memory::SCREEN;
+
+    #[test]
+    fn test_round_trip_between_cores() {
+        let mut fast = load_program(FastCpu::new(), COUNTDOWN);
+        fast.run_cycles(100);
+        fast.press_key(65);
+        fast.write_data(SCREEN + 100, 0xBEEF).unwrap();
+
+        let state = MachineState::from_bytes(&fast.save_state().to_bytes()).unwrap();
+        assert_eq!(state, fast.save_state());
+        assert_eq!(state.rom.len(), 15);
+
+        let mut reference = Cpu::new();
+        reference.restore_state(&state);
+        assert_eq!(reference.save_state(), state);
+        // The screen and keyboard come across with the rest of memory
+        assert_eq!(reference.read_data(SCREEN + 100), Ok(0xBEEF));
+        assert_eq!(reference.read_data(KBD), Ok(65));
+
+        // Both run on into the write past the end of memory
+        fast.run_cycles(100);
+        reference.run_cycles(100);
+        assert!(fast.fault().is_some());
+        assert_eq!(reference.save_state(), fast.save_state());
+    }
+
+    #[test]
+    fn test_restores_faults() {
+        let mut cpu = FastCpu::new();
+        cpu.load_from_string("0111111111111111\n1110111111001000").unwrap();
+        cpu.run_cycles(10);
+
+        let state = MachineState::from_bytes(&cpu.save_state().to_bytes()).unwrap();
+        assert_eq!(state.fault, Some(Fault::AddressOutOfRange(32767)));
+
+        let mut restored = Cpu::new();
+        restored.restore_state(&state);
+        assert_eq!(restored.fault(), Some(&Fault::AddressOutOfRange(32767)));
+        assert!(!restored.clock());
+    }
+
+    #[test]
+    fn test_rejects_bad_files() {
+        let bytes = load_program(FastCpu::new(), COUNTDOWN).save_state().to_bytes();
+
+        assert_eq!(MachineState::from_bytes(b"HACK"), Err("Not a machine state file".to_string()));
+        assert_eq!(
+            MachineState::from_bytes(&bytes[..bytes.len() - 1]),
+            Err("Machine state file is truncated".to_string()),
+        );
+
+        let mut newer = bytes.clone();
+        newer[4] = 2;
+        assert_eq!(MachineState::from_bytes(&newer), Err("Unsupported machine state version 2".to_string()));
+
+        let mut longer = bytes;
+        longer.push(0);
+        assert!(MachineState::from_bytes(&longer).is_err());
+    }
+}
@@ -0,0 +1,335 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::hardware::alu::{alu, AluFlags};
+
+const MAGIC: &[u8; 4] = b"HKTR";
+// Version 1 records had 2 spare bytes at the end
+const VERSION: u8 = 2;
+const HEADER_SIZE: usize = 13;
+const RECORD_SIZE: usize = 13;
+
+// One executed instruction, A and D as they are after it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceRecord {
+    pub cycle: u64,
+    pub pc: u16,
+    pub instruction: u16,
+    pub a: u16,
+    pub d: u16,
+    // (address, value) written to data memory
+    pub write: Option<(u16, u16)>,
+    pub jumped: bool,
+}
+
+impl TraceRecord {
+    // Works out the write and the jump from the state before the instruction,
+    // so the Cpu only pays for this while tracing
+    pub fn from_step(cycle: u64, pc: u16, instruction: u16, before: (u16, u16, u16), after: (u16, u16)) -> Self {
+        let (a, d, m) = before;
+        let mut record = TraceRecord {
+            cycle,
+            pc,
+            instruction,
+            a: after.0,
+            d: after.1,
+            write: None,
+            jumped: false,
+        };
+        if instruction & 0x8000 == 0 {
+            return record;
+        }
+
+        let y = if instruction & 0x1000 != 0 { m } else { a };
+        let flags = AluFlags {
+            zx: instruction & 0x0800 != 0,
+            nx: instruction & 0x0400 != 0,
+            zy: instruction & 0x0200 != 0,
+            ny: instruction & 0x0100 != 0,
+            f: instruction & 0x0080 != 0,
+            no: instruction & 0x0040 != 0,
+        };
+        let (output, is_zero, is_neg) = alu(d, y, flags);
+
+        if instruction & 0x0008 != 0 {
+            record.write = Some((a, output));
+        }
+        record.jumped = match instruction & 0b111 {
+            0b000 => false,
+            0b001 => !is_zero && !is_neg,
+            0b010 => is_zero,
+            0b011 => !is_neg,
+            0b100 => is_neg,
+            0b101 => !is_zero,
+            0b110 => is_neg || is_zero,
+            _ => true,
+        };
+        record
+    }
+
+    pub fn to_json(&self) -> String {
+        let write = match self.write {
+            Some((address, value)) => format!("{{\"address\":{},\"value\":{}}}", address, value),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"cycle\":{},\"pc\":{},\"instruction\":{},\"a\":{},\"d\":{},\"write\":{},\"jump\":{}}}",
+            self.cycle, self.pc, self.instruction, self.a, self.d, write, self.jumped,
+        )
+    }
+
+    // Reads back what `to_json` wrote
+    pub fn from_json(line: &str) -> Option<Self> {
+        let field = |name: &str| -> Option<&str> {
+            let start = line.find(&format!("\"{}\":", name))? + name.len() + 3;
+            let rest = &line[start..];
+            let end = rest.find([',', '}']).unwrap_or(rest.len());
+            Some(rest[..end].trim())
+        };
+        let number = |name: &str| field(name)?.parse::<u64>().ok();
+
+        let write = match field("write")? {
+            "null" => None,
+            _ => Some((number("address")? as u16, number("value")? as u16)),
+        };
+        Some(TraceRecord {
+            cycle: number("cycle")?,
+            pc: number("pc")? as u16,
+            instruction: number("instruction")? as u16,
+            a: number("a")? as u16,
+            d: number("d")? as u16,
+            write,
+            jumped: field("jump")?.parse().ok()?,
+        })
+    }
+
+    fn to_bytes(self) -> [u8; RECORD_SIZE] {
+        let (address, value) = self.write.unwrap_or((0, 0));
+        let flags = self.write.is_some() as u8 | (self.jumped as u8) << 1;
+
+        let mut bytes = [0; RECORD_SIZE];
+        for (i, word) in [self.pc, self.instruction, self.a, self.d, address, value].iter().enumerate() {
+            bytes[2 * i..2 * i + 2].copy_from_slice(&word.to_le_bytes());
+        }
+        bytes[12] = flags;
+        bytes
+    }
+
+    fn from_bytes(cycle: u64, bytes: &[u8]) -> Self {
+        let word = |i: usize| u16::from_le_bytes([bytes[2 * i], bytes[2 * i + 1]]);
+        let flags = bytes[12];
+        TraceRecord {
+            cycle,
+            pc: word(0),
+            instruction: word(1),
+            a: word(2),
+            d: word(3),
+            write: (flags & 1 != 0).then(|| (word(4), word(5))),
+            jumped: flags & 2 != 0,
+        }
+    }
+}
+
+// Receives a record for every instruction the Cpu executes while it is installed
+pub trait Tracer {
+    fn record(&mut self, record: &TraceRecord);
+
+    // Flushes and reports the first error hit while recording
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Tracer for Vec<TraceRecord> {
+    fn record(&mut self, record: &TraceRecord) {
+        self.push(*record);
+    }
+}
+
+// Lets the caller keep a handle on a tracer it hands to the Cpu
+impl<T: Tracer> Tracer for Rc<RefCell<T>> {
+    fn record(&mut self, record: &TraceRecord) {
+        self.borrow_mut().record(record);
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.borrow_mut().finish()
+    }
+}
+
+// One JSON object per line
+pub struct JsonLinesTracer<W: Write> {
+    out: W,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> JsonLinesTracer<W> {
+    pub fn new(out: W) -> Self {
+        JsonLinesTracer { out, error: None }
+    }
+}
+
+impl<W: Write> Tracer for JsonLinesTracer<W> {
+    fn record(&mut self, record: &TraceRecord) {
+        if self.error.is_none() {
+            self.error = writeln!(self.out, "{}", record.to_json()).err();
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => self.out.flush(),
+        }
+    }
+}
+
+// "HKTR", a version byte and the first cycle as a little-endian u64, then
+// fixed 13-byte records: pc, instruction, A, D, write address, write value
+// as little-endian u16s and a flags byte (1: wrote memory, 2: jumped)
+pub struct BinaryTracer<W: Write> {
+    out: W,
+    started: bool,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> BinaryTracer<W> {
+    pub fn new(out: W) -> Self {
+        BinaryTracer { out, started: false, error: None }
+    }
+
+    fn write(&mut self, record: &TraceRecord) -> io::Result<()> {
+        if !self.started {
+            self.out.write_all(MAGIC)?;
+            self.out.write_all(&[VERSION])?;
+            self.out.write_all(&record.cycle.to_le_bytes())?;
+            self.started = true;
+        }
+        self.out.write_all(&record.to_bytes())
+    }
+}
+
+impl<W: Write> Tracer for BinaryTracer<W> {
+    fn record(&mut self, record: &TraceRecord) {
+        if self.error.is_none() {
+            self.error = self.write(record).err();
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => self.out.flush(),
+        }
+    }
+}
+
+pub fn read_binary_trace(bytes: &[u8]) -> Result<Vec<TraceRecord>, String> {
+    if bytes.is_empty() {
+        return Ok(vec![]);
+    }
+    if bytes.len() < HEADER_SIZE || &bytes[..4] != MAGIC {
+        return Err("Not a binary trace".to_string());
+    }
+    if bytes[4] != VERSION {
+        return Err(format!("Unsupported trace version {}", bytes[4]));
+    }
+    let body = &bytes[HEADER_SIZE..];
+    if !body.len().is_multiple_of(RECORD_SIZE) {
+        return Err("Trace ends in the middle of a record".to_string());
+    }
+
+    let start = u64::from_le_bytes(bytes[5..HEADER_SIZE].try_into().unwrap());
+    Ok(body.chunks(RECORD_SIZE)
+        .enumerate()
+        .map(|(i, chunk)| TraceRecord::from_bytes(start + i as u64, chunk))
+        .collect())
+}
+
+pub fn read_json_trace(contents: &str) -> Result<Vec<TraceRecord>, String> {
+    contents.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| TraceRecord::from_json(line).ok_or_else(|| format!("Invalid trace record on line {}", i + 1)))
+        .collect()
+}
+
+// Index of the first record that differs, or where the shorter trace ends
+pub fn first_divergence(left: &[TraceRecord], right: &[TraceRecord]) -> Option<usize> {
+    let differs = left.iter().zip(right).position(|(l, r)| l != r);
+    match differs {
+        Some(i) => Some(i),
+        None if left.len() != right.len() => Some(left.len().min(right.len())),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Vec<TraceRecord> {
+        vec![
+            // @300
+            TraceRecord::from_step(0, 0, 300, (0, 0, 0), (300, 0)),
+            // M=D-1 with D=0 writes -1 to RAM[300]
+            TraceRecord::from_step(1, 1, 0xE388, (300, 0, 0), (300, 0)),
+            // D;JLE with D=0
+            TraceRecord::from_step(2, 2, 0xE306, (300, 0, 0xFFFF), (300, 0)),
+        ]
+    }
+
+    #[test]
+    fn test_from_step() {
+        let records = records();
+        assert_eq!(records[0].write, None);
+        assert!(!records[0].jumped);
+        assert_eq!(records[1].write, Some((300, 0xFFFF)));
+        assert!(!records[1].jumped);
+        assert!(records[2].jumped);
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let record = records()[1];
+        assert_eq!(
+            record.to_json(),
+            "{\"cycle\":1,\"pc\":1,\"instruction\":58248,\"a\":300,\"d\":0,\"write\":{\"address\":300,\"value\":65535},\"jump\":false}",
+        );
+
+        let text: String = records().iter().map(|record| record.to_json() + "\n").collect();
+        assert_eq!(read_json_trace(&text).unwrap(), records());
+        assert!(read_json_trace("{\"cycle\":1}").is_err());
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let mut tracer = BinaryTracer::new(vec![]);
+        for record in records() {
+            tracer.record(&record);
+        }
+        tracer.finish().unwrap();
+
+        assert_eq!(tracer.out.len(), HEADER_SIZE + 3 * RECORD_SIZE);
+        assert_eq!(&tracer.out[..5], b"HKTR\x02");
+        assert_eq!(read_binary_trace(&tracer.out).unwrap(), records());
+        assert!(read_binary_trace(&tracer.out[..20]).is_err());
+
+        // Padded version 1 traces aren't read as the current layout
+        let mut old = tracer.out.clone();
+        old[4] = 1;
+        assert_eq!(read_binary_trace(&old), Err("Unsupported trace version 1".to_string()));
+    }
+
+    #[test]
+    fn test_first_divergence() {
+        let left = records();
+        let mut right = records();
+        assert_eq!(first_divergence(&left, &right), None);
+
+        right[2].d = 1;
+        assert_eq!(first_divergence(&left, &right), Some(2));
+        assert_eq!(first_divergence(&left, &left[..1]), Some(1));
+    }
+}
@@ -0,0 +1,250 @@
+use std::fmt;
+
+use crate::hardware::machine::{Machine, Register};
+use crate::parser::table::decode_instruction;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operand {
+    Register(Register),
+    Memory(u16),
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Register(register) => write!(f, "{:?}", register),
+            Operand::Memory(address) => write!(f, "RAM[{}]", address),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Comparison {
+    pub fn from_symbol(symbol: &str) -> Option<Self> {
+        match symbol {
+            "==" | "=" => Some(Comparison::Eq),
+            "!=" => Some(Comparison::Ne),
+            "<" => Some(Comparison::Lt),
+            "<=" => Some(Comparison::Le),
+            ">" => Some(Comparison::Gt),
+            ">=" => Some(Comparison::Ge),
+            _ => None,
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Comparison::Eq => "==",
+            Comparison::Ne => "!=",
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+        }
+    }
+
+    // Hack words compare as signed 16-bit numbers
+    pub fn holds(&self, value: u16, limit: i16) -> bool {
+        let value = value as i16;
+        match self {
+            Comparison::Eq => value == limit,
+            Comparison::Ne => value != limit,
+            Comparison::Lt => value < limit,
+            Comparison::Le => value <= limit,
+            Comparison::Gt => value > limit,
+            Comparison::Ge => value >= limit,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Watch {
+    // Data memory accesses made by an instruction
+    Access { address: u16, access: Access },
+    // Stops when the condition turns true, or on any change without one
+    Value { operand: Operand, condition: Option<(Comparison, i16)> },
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Watch::Access { address, access } => {
+                let access = match access {
+                    Access::Read => "read",
+                    Access::Write => "write",
+                    Access::ReadWrite => "access",
+                };
+                write!(f, "{} RAM[{}]", access, address)
+            }
+            Watch::Value { operand, condition: None } => write!(f, "change {}", operand),
+            Watch::Value { operand, condition: Some((comparison, limit)) } => {
+                write!(f, "{} {} {}", operand, comparison.symbol(), limit)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchHit {
+    // Index into `Watchpoints::watches`
+    pub watch: usize,
+    pub description: String,
+    pub pc: u16,
+    pub instruction: u16,
+    // A read reports the value read as both
+    pub old: u16,
+    pub new: u16,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Watchpoint {} ({}) at PC {}, {}: {} -> {}",
+            self.watch, self.description, self.pc, decode_instruction(self.instruction),
+            self.old as i16, self.new as i16,
+        )
+    }
+}
+
+// Checked by the Cpu on every data memory access and after every instruction.
+// `hits` only holds what the last instruction triggered
+pub struct Watchpoints {
+    pub watches: Vec<Watch>,
+    pub hits: Vec<WatchHit>,
+}
+
+impl Watchpoints {
+    pub fn new() -> Self {
+        Watchpoints { watches: vec![], hits: vec![] }
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.watches.is_empty()
+    }
+
+    pub fn add(&mut self, watch: Watch) -> usize {
+        self.watches.push(watch);
+        self.watches.len() - 1
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Watch> {
+        (index < self.watches.len()).then(|| self.watches.remove(index))
+    }
+
+    fn hit(&mut self, watch: usize, pc: u16, instruction: u16, old: u16, new: u16) {
+        let description = self.watches[watch].to_string();
+        self.hits.push(WatchHit { watch, description, pc, instruction, old, new });
+    }
+
+    pub fn on_read(&mut self, pc: u16, instruction: u16, address: usize, value: u16) {
+        for i in 0..self.watches.len() {
+            if let Watch::Access { address: watched, access: Access::Read | Access::ReadWrite } = self.watches[i] {
+                if watched as usize == address {
+                    self.hit(i, pc, instruction, value, value);
+                }
+            }
+        }
+    }
+
+    pub fn on_write(&mut self, pc: u16, instruction: u16, address: usize, old: u16, new: u16) {
+        for i in 0..self.watches.len() {
+            if let Watch::Access { address: watched, access: Access::Write | Access::ReadWrite } = self.watches[i] {
+                if watched as usize == address {
+                    self.hit(i, pc, instruction, old, new);
+                }
+            }
+        }
+    }
+
+    // Values of the `Watch::Value` operands, taken before and after each instruction
+    pub fn sample<M: Machine + ?Sized>(&self, cpu: &M) -> Vec<u16> {
+        self.watches.iter()
+            .filter_map(|watch| match watch {
+                Watch::Value { operand: Operand::Register(Register::A), .. } => Some(cpu.get_a()),
+                Watch::Value { operand: Operand::Register(Register::D), .. } => Some(cpu.get_d()),
+                Watch::Value { operand: Operand::Register(Register::PC), .. } => Some(cpu.get_pc()),
+                Watch::Value { operand: Operand::Memory(address), .. } => {
+                    Some(cpu.read_data(*address as usize).unwrap_or(0))
+                }
+                Watch::Access { .. } => None,
+            })
+            .collect()
+    }
+
+    pub fn check_values(&mut self, pc: u16, instruction: u16, before: &[u16], after: &[u16]) {
+        let mut value_index = 0;
+        for i in 0..self.watches.len() {
+            let Watch::Value { condition, .. } = self.watches[i] else {
+                continue;
+            };
+            let (old, new) = (before[value_index], after[value_index]);
+            value_index += 1;
+
+            let triggered = match condition {
+                None => old != new,
+                Some((comparison, limit)) => !comparison.holds(old, limit) && comparison.holds(new, limit),
+            };
+            if triggered {
+                self.hit(i, pc, instruction, old, new);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_comparison_is_signed() {
+        assert!(Comparison::Gt.holds(2048, 2047));
+        assert!(!Comparison::Gt.holds(0xFFFF, 0));
+        assert!(Comparison::Lt.holds(0xFFFF, 0));
+        assert_eq!(Comparison::from_symbol(">="), Some(Comparison::Ge));
+        assert_eq!(Comparison::from_symbol("=>"), None);
+    }
+
+    #[test]
+    fn test_access_hits() {
+        let mut watchpoints = Watchpoints::new();
+        watchpoints.add(Watch::Access { address: 0, access: Access::Write });
+        watchpoints.add(Watch::Access { address: 0, access: Access::ReadWrite });
+        watchpoints.add(Watch::Access { address: 1, access: Access::Read });
+
+        // M=M+1 with A=0
+        watchpoints.on_read(7, 0xFDC8, 0, 256);
+        watchpoints.on_write(7, 0xFDC8, 0, 256, 257);
+
+        let hits: Vec<(usize, u16, u16)> = watchpoints.hits.iter()
+            .map(|hit| (hit.watch, hit.old, hit.new))
+            .collect();
+        assert_eq!(hits, vec![(1, 256, 256), (0, 256, 257), (1, 256, 257)]);
+        assert_eq!(watchpoints.hits[1].to_string(), "Watchpoint 0 (write RAM[0]) at PC 7, M=M+1: 256 -> 257");
+    }
+
+    #[test]
+    fn test_watch_display() {
+        let condition = Watch::Value { operand: Operand::Memory(0), condition: Some((Comparison::Gt, 2047)) };
+        let change = Watch::Value { operand: Operand::Register(Register::D), condition: None };
+
+        assert_eq!(condition.to_string(), "RAM[0] > 2047");
+        assert_eq!(change.to_string(), "change D");
+    }
+}
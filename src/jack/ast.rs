@@ -0,0 +1,467 @@
+use std::fmt;
+
+use crate::jack::tokenizer::{escape_xml, Span};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Name {
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Int,
+    Char,
+    Boolean,
+    Class(String),
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Char => write!(f, "char"),
+            Type::Boolean => write!(f, "boolean"),
+            Type::Class(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClassVarKind {
+    Static,
+    Field,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SubroutineKind {
+    Constructor,
+    Function,
+    Method,
+}
+
+impl fmt::Display for SubroutineKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SubroutineKind::Constructor => write!(f, "constructor"),
+            SubroutineKind::Function => write!(f, "function"),
+            SubroutineKind::Method => write!(f, "method"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Class {
+    pub name: Name,
+    pub vars: Vec<ClassVarDec>,
+    pub subroutines: Vec<Subroutine>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClassVarDec {
+    pub kind: ClassVarKind,
+    pub ty: Type,
+    pub names: Vec<Name>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subroutine {
+    pub kind: SubroutineKind,
+    // None for void
+    pub return_type: Option<Type>,
+    pub name: Name,
+    pub params: Vec<Parameter>,
+    pub locals: Vec<VarDec>,
+    pub statements: Vec<Statement>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Parameter {
+    pub ty: Type,
+    pub name: Name,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VarDec {
+    pub ty: Type,
+    pub names: Vec<Name>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub kind: StatementKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatementKind {
+    Let { target: Name, index: Option<Expression>, value: Expression },
+    If { condition: Expression, then_branch: Vec<Statement>, else_branch: Option<Vec<Statement>> },
+    While { condition: Expression, body: Vec<Statement> },
+    Do(SubroutineCall),
+    Return(Option<Expression>),
+}
+
+// Jack has no operator precedence, terms are applied left to right
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    pub first: Box<Term>,
+    pub rest: Vec<(BinaryOp, Term)>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    And,
+    Or,
+    Lt,
+    Gt,
+    Eq,
+}
+
+impl BinaryOp {
+    pub fn from_symbol(symbol: char) -> Option<Self> {
+        let op = match symbol {
+            '+' => BinaryOp::Add,
+            '-' => BinaryOp::Sub,
+            '*' => BinaryOp::Mul,
+            '/' => BinaryOp::Div,
+            '&' => BinaryOp::And,
+            '|' => BinaryOp::Or,
+            '<' => BinaryOp::Lt,
+            '>' => BinaryOp::Gt,
+            '=' => BinaryOp::Eq,
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    pub fn symbol(&self) -> char {
+        match self {
+            BinaryOp::Add => '+',
+            BinaryOp::Sub => '-',
+            BinaryOp::Mul => '*',
+            BinaryOp::Div => '/',
+            BinaryOp::And => '&',
+            BinaryOp::Or => '|',
+            BinaryOp::Lt => '<',
+            BinaryOp::Gt => '>',
+            BinaryOp::Eq => '=',
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+impl UnaryOp {
+    pub fn symbol(&self) -> char {
+        match self {
+            UnaryOp::Neg => '-',
+            UnaryOp::Not => '~',
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeywordConstant {
+    True,
+    False,
+    Null,
+    This,
+}
+
+impl fmt::Display for KeywordConstant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeywordConstant::True => write!(f, "true"),
+            KeywordConstant::False => write!(f, "false"),
+            KeywordConstant::Null => write!(f, "null"),
+            KeywordConstant::This => write!(f, "this"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Term {
+    pub kind: TermKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TermKind {
+    IntegerConstant(u16),
+    StringConstant(String),
+    KeywordConstant(KeywordConstant),
+    Variable(Name),
+    ArrayAccess(Name, Box<Expression>),
+    Call(SubroutineCall),
+    Parenthesized(Box<Expression>),
+    Unary(UnaryOp, Box<Term>),
+}
+
+// `name(args)`, or `receiver.name(args)` where the receiver is a class or a variable
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubroutineCall {
+    pub receiver: Option<Name>,
+    pub name: Name,
+    pub args: Vec<Expression>,
+    pub span: Span,
+}
+
+impl Class {
+    // The parse tree in the format of the nand2tetris reference .xml files
+    pub fn to_xml(&self) -> String {
+        let mut xml = Xml { text: String::new(), depth: 0 };
+        xml.open("class");
+        xml.keyword("class");
+        xml.identifier(&self.name.name);
+        xml.symbol('{');
+        for var in &self.vars {
+            xml.open("classVarDec");
+            xml.keyword(match var.kind {
+                ClassVarKind::Static => "static",
+                ClassVarKind::Field => "field",
+            });
+            xml.ty(&var.ty);
+            xml.names(&var.names);
+            xml.symbol(';');
+            xml.close("classVarDec");
+        }
+        for subroutine in &self.subroutines {
+            xml.subroutine(subroutine);
+        }
+        xml.symbol('}');
+        xml.close("class");
+        xml.text
+    }
+}
+
+struct Xml {
+    text: String,
+    depth: usize,
+}
+
+impl Xml {
+    fn line(&mut self, line: &str) {
+        self.text.push_str(&"  ".repeat(self.depth));
+        self.text.push_str(line);
+        self.text.push('\n');
+    }
+
+    fn open(&mut self, tag: &str) {
+        self.line(&format!("<{}>", tag));
+        self.depth += 1;
+    }
+
+    fn close(&mut self, tag: &str) {
+        self.depth -= 1;
+        self.line(&format!("</{}>", tag));
+    }
+
+    fn leaf(&mut self, tag: &str, text: &str) {
+        self.line(&format!("<{}> {} </{}>", tag, escape_xml(text), tag));
+    }
+
+    fn keyword(&mut self, keyword: &str) {
+        self.leaf("keyword", keyword);
+    }
+
+    fn symbol(&mut self, symbol: char) {
+        self.leaf("symbol", &symbol.to_string());
+    }
+
+    fn identifier(&mut self, name: &str) {
+        self.leaf("identifier", name);
+    }
+
+    fn ty(&mut self, ty: &Type) {
+        match ty {
+            Type::Class(name) => self.identifier(name),
+            _ => self.keyword(&ty.to_string()),
+        }
+    }
+
+    // Comma separated
+    fn names(&mut self, names: &[Name]) {
+        for (i, name) in names.iter().enumerate() {
+            if i > 0 {
+                self.symbol(',');
+            }
+            self.identifier(&name.name);
+        }
+    }
+
+    fn subroutine(&mut self, subroutine: &Subroutine) {
+        self.open("subroutineDec");
+        self.keyword(&subroutine.kind.to_string());
+        match &subroutine.return_type {
+            Some(ty) => self.ty(ty),
+            None => self.keyword("void"),
+        }
+        self.identifier(&subroutine.name.name);
+        self.symbol('(');
+        self.open("parameterList");
+        for (i, param) in subroutine.params.iter().enumerate() {
+            if i > 0 {
+                self.symbol(',');
+            }
+            self.ty(&param.ty);
+            self.identifier(&param.name.name);
+        }
+        self.close("parameterList");
+        self.symbol(')');
+
+        self.open("subroutineBody");
+        self.symbol('{');
+        for local in &subroutine.locals {
+            self.open("varDec");
+            self.keyword("var");
+            self.ty(&local.ty);
+            self.names(&local.names);
+            self.symbol(';');
+            self.close("varDec");
+        }
+        self.statements(&subroutine.statements);
+        self.symbol('}');
+        self.close("subroutineBody");
+        self.close("subroutineDec");
+    }
+
+    fn statements(&mut self, statements: &[Statement]) {
+        self.open("statements");
+        for statement in statements {
+            self.statement(statement);
+        }
+        self.close("statements");
+    }
+
+    fn block(&mut self, statements: &[Statement]) {
+        self.symbol('{');
+        self.statements(statements);
+        self.symbol('}');
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        match &statement.kind {
+            StatementKind::Let { target, index, value } => {
+                self.open("letStatement");
+                self.keyword("let");
+                self.identifier(&target.name);
+                if let Some(index) = index {
+                    self.symbol('[');
+                    self.expression(index);
+                    self.symbol(']');
+                }
+                self.symbol('=');
+                self.expression(value);
+                self.symbol(';');
+                self.close("letStatement");
+            }
+            StatementKind::If { condition, then_branch, else_branch } => {
+                self.open("ifStatement");
+                self.keyword("if");
+                self.symbol('(');
+                self.expression(condition);
+                self.symbol(')');
+                self.block(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.keyword("else");
+                    self.block(else_branch);
+                }
+                self.close("ifStatement");
+            }
+            StatementKind::While { condition, body } => {
+                self.open("whileStatement");
+                self.keyword("while");
+                self.symbol('(');
+                self.expression(condition);
+                self.symbol(')');
+                self.block(body);
+                self.close("whileStatement");
+            }
+            StatementKind::Do(call) => {
+                self.open("doStatement");
+                self.keyword("do");
+                self.call(call);
+                self.symbol(';');
+                self.close("doStatement");
+            }
+            StatementKind::Return(value) => {
+                self.open("returnStatement");
+                self.keyword("return");
+                if let Some(value) = value {
+                    self.expression(value);
+                }
+                self.symbol(';');
+                self.close("returnStatement");
+            }
+        }
+    }
+
+    fn call(&mut self, call: &SubroutineCall) {
+        if let Some(receiver) = &call.receiver {
+            self.identifier(&receiver.name);
+            self.symbol('.');
+        }
+        self.identifier(&call.name.name);
+        self.symbol('(');
+        self.open("expressionList");
+        for (i, arg) in call.args.iter().enumerate() {
+            if i > 0 {
+                self.symbol(',');
+            }
+            self.expression(arg);
+        }
+        self.close("expressionList");
+        self.symbol(')');
+    }
+
+    fn expression(&mut self, expression: &Expression) {
+        self.open("expression");
+        self.term(&expression.first);
+        for (op, term) in &expression.rest {
+            self.symbol(op.symbol());
+            self.term(term);
+        }
+        self.close("expression");
+    }
+
+    fn term(&mut self, term: &Term) {
+        self.open("term");
+        match &term.kind {
+            TermKind::IntegerConstant(value) => self.leaf("integerConstant", &value.to_string()),
+            TermKind::StringConstant(text) => self.leaf("stringConstant", text),
+            TermKind::KeywordConstant(constant) => self.keyword(&constant.to_string()),
+            TermKind::Variable(name) => self.identifier(&name.name),
+            TermKind::ArrayAccess(name, index) => {
+                self.identifier(&name.name);
+                self.symbol('[');
+                self.expression(index);
+                self.symbol(']');
+            }
+            TermKind::Call(call) => self.call(call),
+            TermKind::Parenthesized(expression) => {
+                self.symbol('(');
+                self.expression(expression);
+                self.symbol(')');
+            }
+            TermKind::Unary(op, term) => {
+                self.symbol(op.symbol());
+                self.term(term);
+            }
+        }
+        self.close("term");
+    }
+}
@@ -0,0 +1,379 @@
+use std::collections::HashMap;
+
+use crate::error::error::{Error, ErrorKind};
+use crate::jack::ast::{
+    Class,
+    ClassVarKind,
+    Expression,
+    KeywordConstant,
+    Name,
+    Statement,
+    StatementKind,
+    SubroutineCall,
+    SubroutineKind,
+    Term,
+    TermKind,
+    Type,
+};
+use crate::jack::os;
+use crate::jack::parser::Parser;
+use crate::jack::table::{SymbolTable, VarKind};
+use crate::jack::tokenizer::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Signature {
+    kind: SubroutineKind,
+    params: usize,
+}
+
+// Catches the mistakes the parser lets through and the VM would only show as
+// a misbehaving program. Every class a program calls has to be declared,
+// the OS included
+pub struct Checker {
+    classes: HashMap<String, HashMap<String, Signature>>,
+    file_name: String,
+    class_name: String,
+    kind: SubroutineKind,
+    table: SymbolTable,
+    errors: Vec<Error>,
+}
+
+impl Checker {
+    pub fn new() -> Self {
+        Checker {
+            classes: HashMap::new(),
+            file_name: String::new(),
+            class_name: String::new(),
+            kind: SubroutineKind::Function,
+            table: SymbolTable::new(),
+            errors: vec![],
+        }
+    }
+
+    // Makes the subroutines of `class` known to the classes that call them
+    pub fn declare(&mut self, class: &Class) {
+        let subroutines = self.classes.entry(class.name.name.clone()).or_default();
+        for subroutine in &class.subroutines {
+            let signature = Signature { kind: subroutine.kind, params: subroutine.params.len() };
+            subroutines.entry(subroutine.name.name.clone()).or_insert(signature);
+        }
+    }
+
+    // The OS classes the program doesn't have its own class for. Call after
+    // declaring the program's classes
+    pub fn declare_os(&mut self) {
+        for (name, source) in os::CLASSES {
+            if !self.classes.contains_key(name) {
+                self.declare(&Parser::new().parse_source(source).expect("the OS classes parse"));
+            }
+        }
+    }
+
+    pub fn check_class(&mut self, file_name: &str, class: &Class) -> Result<(), Vec<Error>> {
+        self.file_name = file_name.to_string();
+        self.class_name = class.name.name.clone();
+        self.errors.clear();
+        self.table.start_class();
+
+        for var in &class.vars {
+            let kind = match var.kind {
+                ClassVarKind::Static => VarKind::Static,
+                ClassVarKind::Field => VarKind::Field,
+            };
+            for name in &var.names {
+                self.define(name, var.ty.clone(), kind);
+            }
+        }
+
+        let mut seen: Vec<&str> = vec![];
+        for subroutine in &class.subroutines {
+            if seen.contains(&subroutine.name.name.as_str()) {
+                self.error(ErrorKind::DuplicateDefinition(subroutine.name.name.clone()), subroutine.name.span);
+            }
+            seen.push(&subroutine.name.name);
+
+            self.table.start_subroutine();
+            self.kind = subroutine.kind;
+            if subroutine.kind == SubroutineKind::Method {
+                self.table.define("this", Type::Class(self.class_name.clone()), VarKind::Argument);
+            }
+            for param in &subroutine.params {
+                self.define(&param.name, param.ty.clone(), VarKind::Argument);
+            }
+            for local in &subroutine.locals {
+                for name in &local.names {
+                    self.define(name, local.ty.clone(), VarKind::Local);
+                }
+            }
+
+            self.statements(&subroutine.statements);
+            if !always_returns(&subroutine.statements) {
+                let name = format!("{}.{}", self.class_name, subroutine.name.name);
+                self.error(ErrorKind::MissingReturn(name), subroutine.name.span);
+            }
+        }
+
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            self.errors.sort_by_key(|error| (error.location.line, error.location.column));
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    fn error(&mut self, kind: ErrorKind, span: Span) {
+        self.errors.push(Error::new(kind, span.location(&self.file_name)));
+    }
+
+    fn define(&mut self, name: &Name, ty: Type, kind: VarKind) {
+        if !self.table.define(&name.name, ty, kind) {
+            self.error(ErrorKind::DuplicateDefinition(name.name.clone()), name.span);
+        }
+    }
+
+    // The type of a variable, once it's known to be usable here
+    fn variable(&mut self, name: &Name) -> Option<Type> {
+        let variable = match self.table.get(&name.name) {
+            Some(variable) => variable.clone(),
+            None => {
+                self.error(ErrorKind::UndeclaredVariable(name.name.clone()), name.span);
+                return None;
+            }
+        };
+        if variable.kind == VarKind::Field && self.kind == SubroutineKind::Function {
+            self.error(ErrorKind::FieldInFunction(name.name.clone()), name.span);
+        }
+        Some(variable.ty)
+    }
+
+    fn statements(&mut self, statements: &[Statement]) {
+        for statement in statements {
+            match &statement.kind {
+                StatementKind::Let { target, index, value } => {
+                    self.variable(target);
+                    if let Some(index) = index {
+                        self.expression(index);
+                    }
+                    self.expression(value);
+                }
+                StatementKind::If { condition, then_branch, else_branch } => {
+                    self.expression(condition);
+                    self.statements(then_branch);
+                    if let Some(else_branch) = else_branch {
+                        self.statements(else_branch);
+                    }
+                }
+                StatementKind::While { condition, body } => {
+                    self.expression(condition);
+                    self.statements(body);
+                }
+                StatementKind::Do(call) => self.call(call),
+                StatementKind::Return(value) => {
+                    if let Some(value) = value {
+                        self.expression(value);
+                    }
+                }
+            }
+        }
+    }
+
+    fn expression(&mut self, expression: &Expression) {
+        self.term(&expression.first);
+        for (_, term) in &expression.rest {
+            self.term(term);
+        }
+    }
+
+    fn term(&mut self, term: &Term) {
+        match &term.kind {
+            TermKind::IntegerConstant(_) | TermKind::StringConstant(_) => {}
+            TermKind::KeywordConstant(KeywordConstant::This) => {
+                if self.kind == SubroutineKind::Function {
+                    self.error(ErrorKind::ThisInFunction, term.span);
+                }
+            }
+            TermKind::KeywordConstant(_) => {}
+            TermKind::Variable(name) => {
+                self.variable(name);
+            }
+            TermKind::ArrayAccess(name, index) => {
+                self.variable(name);
+                self.expression(index);
+            }
+            TermKind::Call(call) => self.call(call),
+            TermKind::Parenthesized(expression) => self.expression(expression),
+            TermKind::Unary(_, term) => self.term(term),
+        }
+    }
+
+    fn call(&mut self, call: &SubroutineCall) {
+        for arg in &call.args {
+            self.expression(arg);
+        }
+
+        // The class the subroutine is looked up in, and whether it's called on an object
+        let (class, on_object) = match &call.receiver {
+            None => (self.class_name.clone(), true),
+            Some(receiver) if self.table.get(&receiver.name).is_some() => {
+                match self.variable(receiver) {
+                    Some(Type::Class(class)) => (class, true),
+                    // int, char and boolean have no subroutines
+                    Some(ty) => (ty.to_string(), true),
+                    None => return,
+                }
+            }
+            Some(receiver) => (receiver.name.clone(), false),
+        };
+
+        let name = format!("{}.{}", class, call.name.name);
+        let signature = match self.classes.get(&class) {
+            Some(subroutines) => subroutines.get(&call.name.name).copied(),
+            None if matches!(class.as_str(), "int" | "char" | "boolean") => None,
+            None => {
+                let span = call.receiver.as_ref().map_or(call.name.span, |receiver| receiver.span);
+                return self.error(ErrorKind::UnknownClass(class), span);
+            }
+        };
+        let signature = match signature {
+            Some(signature) => signature,
+            None => return self.error(ErrorKind::UnknownSubroutine(name), call.name.span),
+        };
+
+        let is_method = signature.kind == SubroutineKind::Method;
+        if is_method && !on_object {
+            self.error(ErrorKind::MethodCalledAsFunction(name.clone()), call.span);
+        } else if is_method && call.receiver.is_none() && self.kind == SubroutineKind::Function {
+            // `f()` calls f on `this`, which a function doesn't have
+            self.error(ErrorKind::MethodCalledAsFunction(name.clone()), call.span);
+        } else if !is_method && on_object {
+            self.error(ErrorKind::FunctionCalledAsMethod(name.clone(), signature.kind.to_string()), call.span);
+        }
+        if signature.params != call.args.len() {
+            self.error(ErrorKind::ArgumentCount(name, signature.params, call.args.len()), call.span);
+        }
+    }
+}
+
+// Whether every path through `statements` ends in a return. Loops don't
+// count, the compiler can't tell when they finish
+fn always_returns(statements: &[Statement]) -> bool {
+    match statements.last().map(|statement| &statement.kind) {
+        Some(StatementKind::Return(_)) => true,
+        Some(StatementKind::If { then_branch, else_branch: Some(else_branch), .. }) => {
+            always_returns(then_branch) && always_returns(else_branch)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jack::parser::Parser;
+
+    fn parse(source: &str) -> Class {
+        Parser::new().parse_source(source).unwrap()
+    }
+
+    fn check(sources: &[&str]) -> Vec<String> {
+        let classes: Vec<Class> = sources.iter().map(|source| parse(source)).collect();
+        let mut checker = Checker::new();
+        for class in &classes {
+            checker.declare(class);
+        }
+        checker.declare_os();
+        classes.iter()
+            .flat_map(|class| checker.check_class("Main.jack", class).err().unwrap_or_default())
+            .map(|error| error.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_program() {
+        let point = "\
+class Point {
+    field int x;
+    constructor Point new(int ax) { let x = ax; return this; }
+    method int getX() { return x; }
+    method int twice() { return getX() + getX(); }
+    function int origin() { return 0; }
+}";
+        let main = "\
+class Main {
+    function void main() {
+        var Point p;
+        let p = Point.new(Point.origin());
+        if (p.twice() > 0) { return; } else { do Output.printInt(p.getX()); return; }
+    }
+}";
+        assert!(check(&[point, main]).is_empty());
+    }
+
+    #[test]
+    fn test_calls() {
+        let point = "\
+class Point {
+    method int getX() { return 0; }
+    function int origin() { return 0; }
+}";
+        let main = "\
+class Main {
+    function void main() {
+        var Point p;
+        var int n;
+        do Point.getX();
+        do p.origin();
+        do p.getY(1);
+        do Point.origin(1, 2);
+        do n.size();
+        do run();
+        do Sys.halt(1, 2, 3);
+        do Ouput.printInt(42);
+        return;
+    }
+    method void run() { return; }
+}";
+        assert_eq!(check(&[point, main]), [
+            "Main.jack:5:12: Point.getX is a method and needs an object to call it on",
+            "Main.jack:6:12: Point.origin is a function, call it through its class",
+            "Main.jack:7:14: Unknown subroutine: Point.getY",
+            "Main.jack:8:12: Point.origin takes 0 arguments but was given 2",
+            "Main.jack:9:14: Unknown subroutine: int.size",
+            "Main.jack:10:12: Main.run is a method and needs an object to call it on",
+            "Main.jack:11:12: Sys.halt takes 0 arguments but was given 3",
+            "Main.jack:12:12: Unknown class: Ouput",
+        ]);
+
+        // The OS's Sys calls Main.main, but that doesn't make Main a class
+        let foo = "class Foo { function void f() { do Main.main(); return; } }";
+        assert_eq!(check(&[foo]), ["Main.jack:1:36: Unknown class: Main"]);
+    }
+
+    #[test]
+    fn test_variables_this_and_returns() {
+        let main = "\
+class Main {
+    field int count;
+    function int f(int a, int a) {
+        let b = count + a;
+        return this;
+    }
+    method int g(boolean flag) {
+        if (flag) { return 1; }
+        while (flag) { return 2; }
+    }
+    method int h(boolean flag) {
+        if (flag) { return 1; } else { return count; }
+    }
+    function void g() { return; }
+}";
+        assert_eq!(check(&[main]), [
+            "Main.jack:3:31: Already defined: a",
+            "Main.jack:4:13: Undeclared variable: b",
+            "Main.jack:4:17: Field count can't be used in a function",
+            "Main.jack:5:16: this can't be used in a function",
+            "Main.jack:7:16: Main.g can reach its end without returning",
+            "Main.jack:14:19: Already defined: g",
+        ]);
+    }
+}
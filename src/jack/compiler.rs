@@ -0,0 +1,527 @@
+use crate::error::error::{Error, ErrorKind};
+use crate::jack::ast::{
+    BinaryOp,
+    Class,
+    ClassVarKind,
+    Expression,
+    KeywordConstant,
+    Name,
+    Statement,
+    StatementKind,
+    Subroutine,
+    SubroutineCall,
+    SubroutineKind,
+    Term,
+    TermKind,
+    Type,
+    UnaryOp,
+};
+use crate::jack::checker::Checker;
+use crate::jack::parser::Parser;
+use crate::jack::table::{SymbolTable, VarKind};
+use crate::stack::stack::VmFile;
+
+// Lowers the AST of one class to VM commands, the same ones `Stack` translates
+pub struct Compiler {
+    pub file_name: String,
+    pub class_name: String,
+    pub commands: Vec<String>,
+    table: SymbolTable,
+    // Per subroutine, so the labels stay the same when other subroutines change
+    if_counter: usize,
+    while_counter: usize,
+    errors: Vec<Error>,
+}
+
+impl Compiler {
+    pub fn new() -> Self {
+        Compiler {
+            file_name: String::new(),
+            class_name: String::new(),
+            commands: vec![],
+            table: SymbolTable::new(),
+            if_counter: 0,
+            while_counter: 0,
+            errors: vec![],
+        }
+    }
+
+    pub fn compile_class(&mut self, class: &Class) -> Result<(), Vec<Error>> {
+        self.class_name = class.name.name.clone();
+        self.commands.clear();
+        self.errors.clear();
+        self.table.start_class();
+
+        for var in &class.vars {
+            let kind = match var.kind {
+                ClassVarKind::Static => VarKind::Static,
+                ClassVarKind::Field => VarKind::Field,
+            };
+            for name in &var.names {
+                self.define(name, var.ty.clone(), kind);
+            }
+        }
+        for subroutine in &class.subroutines {
+            self.subroutine(subroutine);
+        }
+
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            // `let` compiles its value before its target, report in source order
+            self.errors.sort_by_key(|error| (error.location.line, error.location.column));
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    fn emit(&mut self, command: String) {
+        self.commands.push(command);
+    }
+
+    fn error(&mut self, kind: ErrorKind, name: &Name) {
+        self.errors.push(Error::new(kind, name.span.location(&self.file_name)));
+    }
+
+    fn define(&mut self, name: &Name, ty: Type, kind: VarKind) {
+        if !self.table.define(&name.name, ty, kind) {
+            self.error(ErrorKind::DuplicateDefinition(name.name.clone()), name);
+        }
+    }
+
+    fn subroutine(&mut self, subroutine: &Subroutine) {
+        self.table.start_subroutine();
+        self.if_counter = 0;
+        self.while_counter = 0;
+
+        if subroutine.kind == SubroutineKind::Method {
+            let this = Name { name: "this".to_string(), span: subroutine.name.span };
+            self.define(&this, Type::Class(self.class_name.clone()), VarKind::Argument);
+        }
+        for param in &subroutine.params {
+            self.define(&param.name, param.ty.clone(), VarKind::Argument);
+        }
+        for local in &subroutine.locals {
+            for name in &local.names {
+                self.define(name, local.ty.clone(), VarKind::Local);
+            }
+        }
+
+        let locals = self.table.count(VarKind::Local);
+        self.emit(format!("function {}.{} {}", self.class_name, subroutine.name.name, locals));
+        match subroutine.kind {
+            SubroutineKind::Constructor => {
+                let fields = self.table.count(VarKind::Field);
+                self.emit(format!("push constant {}", fields));
+                self.emit("call Memory.alloc 1".to_string());
+                self.emit("pop pointer 0".to_string());
+            }
+            SubroutineKind::Method => {
+                self.emit("push argument 0".to_string());
+                self.emit("pop pointer 0".to_string());
+            }
+            SubroutineKind::Function => {}
+        }
+        self.statements(&subroutine.statements);
+    }
+
+    fn statements(&mut self, statements: &[Statement]) {
+        for statement in statements {
+            self.statement(statement);
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        match &statement.kind {
+            StatementKind::Let { target, index: None, value } => {
+                self.expression(value);
+                if let Some((segment, index)) = self.variable(target) {
+                    self.emit(format!("pop {} {}", segment, index));
+                }
+            }
+            StatementKind::Let { target, index: Some(index), value } => {
+                // The address is worked out first, `value` may use `that` too
+                self.array_address(target, index);
+                self.expression(value);
+                self.emit("pop temp 0".to_string());
+                self.emit("pop pointer 1".to_string());
+                self.emit("push temp 0".to_string());
+                self.emit("pop that 0".to_string());
+            }
+            StatementKind::If { condition, then_branch, else_branch } => {
+                let n = self.if_counter;
+                self.if_counter += 1;
+                self.expression(condition);
+                self.emit("not".to_string());
+                self.emit(format!("if-goto IF_FALSE{}", n));
+                self.statements(then_branch);
+                match else_branch {
+                    Some(else_branch) => {
+                        self.emit(format!("goto IF_END{}", n));
+                        self.emit(format!("label IF_FALSE{}", n));
+                        self.statements(else_branch);
+                        self.emit(format!("label IF_END{}", n));
+                    }
+                    None => self.emit(format!("label IF_FALSE{}", n)),
+                }
+            }
+            StatementKind::While { condition, body } => {
+                let n = self.while_counter;
+                self.while_counter += 1;
+                self.emit(format!("label WHILE_EXP{}", n));
+                // `while (true)` needs no test, which also leaves Sys.halt a plain idle loop
+                if !is_true(condition) {
+                    self.expression(condition);
+                    self.emit("not".to_string());
+                    self.emit(format!("if-goto WHILE_END{}", n));
+                }
+                self.statements(body);
+                self.emit(format!("goto WHILE_EXP{}", n));
+                self.emit(format!("label WHILE_END{}", n));
+            }
+            StatementKind::Do(call) => {
+                self.call(call);
+                self.emit("pop temp 0".to_string());
+            }
+            StatementKind::Return(value) => {
+                match value {
+                    Some(value) => self.expression(value),
+                    None => self.emit("push constant 0".to_string()),
+                }
+                self.emit("return".to_string());
+            }
+        }
+    }
+
+    // Segment and index of a variable, reporting it when it isn't declared
+    fn variable(&mut self, name: &Name) -> Option<(&'static str, u16)> {
+        match self.table.get(&name.name) {
+            Some(variable) => Some((variable.kind.segment(), variable.index)),
+            None => {
+                self.error(ErrorKind::UndeclaredVariable(name.name.clone()), name);
+                None
+            }
+        }
+    }
+
+    fn push_variable(&mut self, name: &Name) {
+        if let Some((segment, index)) = self.variable(name) {
+            self.emit(format!("push {} {}", segment, index));
+        }
+    }
+
+    // Leaves `array + index` on the stack
+    fn array_address(&mut self, array: &Name, index: &Expression) {
+        self.push_variable(array);
+        self.expression(index);
+        self.emit("add".to_string());
+    }
+
+    fn expression(&mut self, expression: &Expression) {
+        self.term(&expression.first);
+        for (op, term) in &expression.rest {
+            self.term(term);
+            let command = match op {
+                BinaryOp::Add => "add",
+                BinaryOp::Sub => "sub",
+                BinaryOp::Mul => "call Math.multiply 2",
+                BinaryOp::Div => "call Math.divide 2",
+                BinaryOp::And => "and",
+                BinaryOp::Or => "or",
+                BinaryOp::Lt => "lt",
+                BinaryOp::Gt => "gt",
+                BinaryOp::Eq => "eq",
+            };
+            self.emit(command.to_string());
+        }
+    }
+
+    fn term(&mut self, term: &Term) {
+        match &term.kind {
+            TermKind::IntegerConstant(value) => self.emit(format!("push constant {}", value)),
+            TermKind::StringConstant(text) => {
+                self.emit(format!("push constant {}", text.chars().count()));
+                self.emit("call String.new 1".to_string());
+                for c in text.chars() {
+                    self.emit(format!("push constant {}", c as u32));
+                    self.emit("call String.appendChar 2".to_string());
+                }
+            }
+            TermKind::KeywordConstant(constant) => match constant {
+                KeywordConstant::True => {
+                    self.emit("push constant 0".to_string());
+                    self.emit("not".to_string());
+                }
+                KeywordConstant::False | KeywordConstant::Null => self.emit("push constant 0".to_string()),
+                KeywordConstant::This => self.emit("push pointer 0".to_string()),
+            },
+            TermKind::Variable(name) => self.push_variable(name),
+            TermKind::ArrayAccess(array, index) => {
+                self.array_address(array, index);
+                self.emit("pop pointer 1".to_string());
+                self.emit("push that 0".to_string());
+            }
+            TermKind::Call(call) => self.call(call),
+            TermKind::Parenthesized(expression) => self.expression(expression),
+            TermKind::Unary(op, term) => {
+                self.term(term);
+                self.emit(match op {
+                    UnaryOp::Neg => "neg".to_string(),
+                    UnaryOp::Not => "not".to_string(),
+                });
+            }
+        }
+    }
+
+    // `f()` is a method of this class, `v.f()` a method of the object in `v`,
+    // and `C.f()` a function or constructor of class C
+    fn call(&mut self, call: &SubroutineCall) {
+        let (class, extra) = match &call.receiver {
+            None => {
+                self.emit("push pointer 0".to_string());
+                (self.class_name.clone(), 1)
+            }
+            Some(receiver) => match self.table.get(&receiver.name).cloned() {
+                Some(variable) => {
+                    self.emit(format!("push {} {}", variable.kind.segment(), variable.index));
+                    (variable.ty.to_string(), 1)
+                }
+                None => (receiver.name.clone(), 0),
+            },
+        };
+
+        for arg in &call.args {
+            self.expression(arg);
+        }
+        self.emit(format!("call {}.{} {}", class, call.name.name, call.args.len() + extra));
+    }
+}
+
+fn is_true(expression: &Expression) -> bool {
+    expression.rest.is_empty() && expression.first.kind == TermKind::KeywordConstant(KeywordConstant::True)
+}
+
+// Parses, checks and compiles one .jack file into a VM file named after its class
+pub fn compile_source(file_name: &str, contents: &str) -> Result<VmFile, Vec<Error>> {
+    let mut files = compile_program(&[(file_name.to_string(), contents.to_string())])?;
+    Ok(files.remove(0))
+}
+
+// Same for the (file name, contents) of every file in a program, so calls
+// between its classes can be checked
+pub fn compile_program(sources: &[(String, String)]) -> Result<Vec<VmFile>, Vec<Error>> {
+    let mut errors = vec![];
+    let mut classes = vec![];
+    for (file_name, contents) in sources {
+        let mut parser = Parser::new();
+        parser.file_name = file_name.clone();
+        match parser.parse_source(contents) {
+            Ok(class) => classes.push((file_name, class)),
+            Err(file_errors) => errors.extend(file_errors),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut checker = Checker::new();
+    for (_, class) in &classes {
+        checker.declare(class);
+    }
+    checker.declare_os();
+    for (file_name, class) in &classes {
+        if let Err(class_errors) = checker.check_class(file_name, class) {
+            errors.extend(class_errors);
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let mut files = vec![];
+    for (file_name, class) in &classes {
+        let mut compiler = Compiler::new();
+        compiler.file_name = file_name.to_string();
+        compiler.compile_class(class)?;
+        files.push(VmFile::new(&compiler.class_name, compiler.commands));
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::hardware::fast::FastCpu;
+    use crate::hardware::machine::{Machine, RunOptions, StopReason};
+    use crate::parser::assembly::Assembler;
+    use crate::stack::stack::Stack;
+
+    fn compile(source: &str) -> Vec<String> {
+        compile_source("Main.jack", source).unwrap().commands
+    }
+
+    // Translates the files with the bootstrap, sharing the call and return code
+    // if `shared_routines` is set, as for programs linked with the OS, and runs
+    // them until they end in a loop
+    pub(crate) fn run(files: &[VmFile], shared_routines: bool) -> FastCpu {
+        let mut stack = Stack::new();
+        stack.shared_routines = shared_routines;
+        stack.assemble_program(files).unwrap();
+        let mut asm = Assembler::new();
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
+
+        let mut cpu = FastCpu::new();
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
+        let outcome = cpu.run(&RunOptions::cycles(20_000_000));
+        assert!(matches!(outcome.reason, StopReason::LoopDetected(_)), "{:?}", outcome.reason);
+        cpu
+    }
+
+    #[test]
+    fn test_functions_and_statements() {
+        let commands = compile("\
+class Main {
+    static int total;
+    function int sum(int n) {
+        var int i;
+        let i = 0;
+        while (i < n) {
+            let total = total + i;
+            let i = i + 1;
+        }
+        if (total > 10) { return total; } else { return -1; }
+    }
+}");
+        assert_eq!(commands, [
+            "function Main.sum 1",
+            "push constant 0", "pop local 0",
+            "label WHILE_EXP0",
+            "push local 0", "push argument 0", "lt", "not", "if-goto WHILE_END0",
+            "push static 0", "push local 0", "add", "pop static 0",
+            "push local 0", "push constant 1", "add", "pop local 0",
+            "goto WHILE_EXP0",
+            "label WHILE_END0",
+            "push static 0", "push constant 10", "gt", "not", "if-goto IF_FALSE0",
+            "push static 0", "return",
+            "goto IF_END0",
+            "label IF_FALSE0",
+            "push constant 1", "neg", "return",
+            "label IF_END0",
+        ]);
+    }
+
+    #[test]
+    fn test_objects_arrays_and_strings() {
+        let commands = compile("\
+class Point {
+    field int x, y;
+    constructor Point new(int ax) { let x = ax; return this; }
+    method int getX() { return x; }
+    method void copy(Point other, Array a) {
+        let a[x] = other.getX();
+        do Output.printString(\"Hi\");
+        do getX();
+        return;
+    }
+}");
+        assert_eq!(commands, [
+            "function Point.new 0",
+            "push constant 2", "call Memory.alloc 1", "pop pointer 0",
+            "push argument 0", "pop this 0",
+            "push pointer 0", "return",
+            "function Point.getX 0",
+            "push argument 0", "pop pointer 0",
+            "push this 0", "return",
+            "function Point.copy 0",
+            "push argument 0", "pop pointer 0",
+            "push argument 2", "push this 0", "add",
+            "push argument 1", "call Point.getX 1",
+            "pop temp 0", "pop pointer 1", "push temp 0", "pop that 0",
+            "push constant 2", "call String.new 1",
+            "push constant 72", "call String.appendChar 2",
+            "push constant 105", "call String.appendChar 2",
+            "call Output.printString 1", "pop temp 0",
+            "push pointer 0", "call Point.getX 1", "pop temp 0",
+            "push constant 0", "return",
+        ]);
+    }
+
+    #[test]
+    fn test_undeclared_and_duplicate_variables() {
+        // Straight to the compiler, compile_source checks these before it gets there
+        let class = Parser::new().parse_source("\
+class Main {
+    field int x;
+    method void f(int a, int a) {
+        let y = x + b[1];
+        return;
+    }
+}").unwrap();
+        let mut compiler = Compiler::new();
+        compiler.file_name = "Main.jack".to_string();
+        let errors = compiler.compile_class(&class).unwrap_err();
+
+        let found: Vec<String> = errors.iter().map(Error::to_string).collect();
+        assert_eq!(found, [
+            "Main.jack:3:30: Already defined: a",
+            "Main.jack:4:13: Undeclared variable: y",
+            "Main.jack:4:21: Undeclared variable: b",
+        ]);
+    }
+
+    #[test]
+    fn test_runs_on_the_cpu() {
+        // Minimal Memory and Math, the OS classes aren't needed for this
+        let memory = "\
+class Memory {
+    static int free;
+    function int alloc(int size) {
+        var int block;
+        if (free = 0) { let free = 2048; }
+        let block = free;
+        let free = free + size;
+        return block;
+    }
+}";
+        let math = "\
+class Math {
+    function int multiply(int a, int b) {
+        var int product;
+        while (b > 0) { let product = product + a; let b = b - 1; }
+        return product;
+    }
+}";
+        let counter = "\
+class Counter {
+    field int count, step;
+    constructor Counter new(int s) { let step = s; return this; }
+    method void tick() { let count = count + step; return; }
+    method int get() { return count; }
+}";
+        let sys = "\
+class Sys {
+    function void init() {
+        var Counter c;
+        var Array out;
+        var int i;
+        let out = 8000;
+        let c = Counter.new(3);
+        while (i < 5) { do c.tick(); let i = i + 1; }
+        let out[0] = c.get();
+        let out[1] = 6 * 7;
+        let out[2] = ~(1 = 2) & (3 < 4);
+        while (true) {}
+        return;
+    }
+}";
+        let sources: Vec<(String, String)> = [memory, math, counter, sys].iter()
+            .map(|source| (String::new(), source.to_string()))
+            .collect();
+        let files = compile_program(&sources).unwrap();
+        for shared_routines in [false, true] {
+            let cpu = run(&files, shared_routines);
+            assert_eq!(cpu.read_data(8000), Ok(15));
+            assert_eq!(cpu.read_data(8001), Ok(42));
+            assert_eq!(cpu.read_data(8002), Ok(0xFFFF));
+        }
+    }
+}
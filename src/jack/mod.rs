@@ -0,0 +1,7 @@
+pub mod ast;
+pub mod checker;
+pub mod compiler;
+pub mod os;
+pub mod parser;
+pub mod table;
+pub mod tokenizer;
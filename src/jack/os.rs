@@ -0,0 +1,251 @@
+use std::collections::HashSet;
+
+use crate::jack::compiler::compile_program;
+use crate::stack::stack::VmFile;
+
+// The standard library Jack programs run on, compiled like any other Jack code
+pub const CLASSES: [(&str, &str); 8] = [
+    ("Array", include_str!("os/Array.jack")),
+    ("Keyboard", include_str!("os/Keyboard.jack")),
+    ("Math", include_str!("os/Math.jack")),
+    ("Memory", include_str!("os/Memory.jack")),
+    ("Output", include_str!("os/Output.jack")),
+    ("Screen", include_str!("os/Screen.jack")),
+    ("String", include_str!("os/String.jack")),
+    ("Sys", include_str!("os/Sys.jack")),
+];
+
+pub fn sources() -> Vec<(String, String)> {
+    CLASSES.iter().map(|(name, source)| (format!("{}.jack", name), source.to_string())).collect()
+}
+
+// Sys.init starts the program at Main.main, which the OS on its own doesn't
+// have. This stands in for it while the OS is checked, and isn't kept
+const MAIN: &str = "class Main { function void main() { return; } }";
+
+pub fn compile() -> Vec<VmFile> {
+    let mut sources = sources();
+    sources.push(("Main.jack".to_string(), MAIN.to_string()));
+    let mut files = compile_program(&sources).expect("the OS classes compile");
+    files.retain(|file| file.name != "Main");
+    files
+}
+
+// Adds the OS classes `files` call, directly or through other OS classes,
+// unless the program has a class of that name itself, and returns their
+// names. Sys sets the OS up and starts Main.main, so only a program with a
+// Main.main gets it, whether it calls it or not. The OS is only compiled if
+// something is wanted from it
+pub fn link(files: &mut Vec<VmFile>) -> Vec<String> {
+    let defined: HashSet<String> = files.iter()
+        .flat_map(|file| file.commands.iter())
+        .filter_map(|command| match command.split_whitespace().collect::<Vec<_>>()[..] {
+            ["function", name, _] => name.split('.').next().map(String::from),
+            _ => None,
+        })
+        .collect();
+
+    let has_main = defines(files, "Main.main");
+    let linkable = |class: &String| {
+        !defined.contains(class) && (class != "Sys" || has_main) && CLASSES.iter().any(|(name, _)| name == class)
+    };
+
+    let mut wanted: Vec<String> = called_classes(files);
+    if has_main {
+        wanted.push("Sys".to_string());
+    }
+    wanted.retain(linkable);
+    if wanted.is_empty() {
+        return vec![];
+    }
+
+    let mut os = compile();
+    let mut linked = vec![];
+    while let Some(class) = wanted.pop() {
+        if !linkable(&class) || linked.contains(&class) {
+            continue;
+        }
+        if let Some(position) = os.iter().position(|file| file.name == class) {
+            let file = os.remove(position);
+            wanted.extend(called_classes(std::slice::from_ref(&file)));
+            linked.push(class);
+            files.push(file);
+        }
+    }
+    linked
+}
+
+// Whether one of `files` has a `function <name>` command
+pub fn defines(files: &[VmFile], name: &str) -> bool {
+    files.iter()
+        .flat_map(|file| file.commands.iter())
+        .any(|command| command.split_whitespace().take(2).eq(["function", name]))
+}
+
+fn called_classes(files: &[VmFile]) -> Vec<String> {
+    files.iter()
+        .flat_map(|file| file.commands.iter())
+        .filter_map(|command| match command.split_whitespace().collect::<Vec<_>>()[..] {
+            ["call", name, _] => name.split('.').next().map(String::from),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hardware::fast::FastCpu;
+    use crate::hardware::machine::Machine;
+    use crate::jack::compiler::compile_source;
+    use crate::jack::compiler::tests::run as run_files;
+
+    const SCREEN: usize = 16384;
+
+    // Links and runs a Main class until Sys.halt
+    fn run(main: &str) -> FastCpu {
+        let mut files = vec![compile_source("Main.jack", main).unwrap()];
+        link(&mut files);
+        run_files(&files, true)
+    }
+
+    fn names(files: &[VmFile]) -> Vec<&str> {
+        files.iter().map(|file| file.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_links_what_is_called() {
+        let mut files = vec![VmFile::from_source("Prog", "function Prog.f 0\npush constant 6\ncall Math.abs 1\nreturn")];
+        assert_eq!(link(&mut files), ["Math", "Array", "Memory"]);
+        assert_eq!(names(&files), ["Prog", "Math", "Array", "Memory"]);
+
+        // Sys comes with Main.main, and everything Sys.init sets up with it
+        let mut files = vec![VmFile::from_source("Main", "function Main.main 0\npush constant 0\nreturn")];
+        link(&mut files);
+        assert_eq!(names(&files), ["Main", "Sys", "Output", "String", "Math", "Array", "Memory", "Keyboard", "Screen"]);
+
+        // Classes the program defines itself are left out
+        let mut files = vec![
+            VmFile::from_source("Main", "function Main.main 0\ncall Memory.alloc 0\nreturn"),
+            VmFile::from_source("Memory", "function Memory.alloc 0\nreturn"),
+            VmFile::from_source("Sys", "function Sys.init 0\ncall Main.main 0\nreturn"),
+        ];
+        assert!(link(&mut files).is_empty());
+        assert_eq!(names(&files), ["Main", "Memory", "Sys"]);
+        // The Main the OS is checked against stays out of it
+        assert!(!defines(&compile(), "Main.main"));
+    }
+
+    #[test]
+    fn test_math_and_memory() {
+        let cpu = run("\
+class Main {
+    function void main() {
+        var Array out, a, b;
+        let out = 8000;
+        let out[0] = 123 * -45;
+        let out[1] = -5535 / 45;
+        let out[2] = Math.sqrt(30000);
+        let out[3] = 32767 / 1;
+        let out[6] = (-32767 - 1) / 2;
+        let out[7] = (-32767 - 1) / -3;
+        let out[8] = (-32767 - 1) / (-32767 - 1);
+        let a = Array.new(3);
+        let b = Array.new(3);
+        let out[4] = b - a;
+        do a.dispose();
+        do b.dispose();
+        let out[5] = Array.new(3) - a;
+        return;
+    }
+}");
+        assert_eq!(cpu.read_data(8000), Ok((123 * -45i16) as u16));
+        assert_eq!(cpu.read_data(8001), Ok((-123i16) as u16));
+        assert_eq!(cpu.read_data(8002), Ok(173));
+        assert_eq!(cpu.read_data(8003), Ok(32767));
+        assert_eq!(cpu.read_data(8006), Ok((-16384i16) as u16));
+        assert_eq!(cpu.read_data(8007), Ok(10922));
+        assert_eq!(cpu.read_data(8008), Ok(1));
+        // Blocks come from the end of the heap, and freed ones merge back into it
+        assert_eq!(cpu.read_data(8004), Ok((-4i16) as u16));
+        assert_eq!(cpu.read_data(8005), Ok(0));
+    }
+
+    #[test]
+    fn test_heap_survives_alloc_and_dispose() {
+        let cpu = run("\
+class Main {
+    function void main() {
+        var Array out, first, a, b;
+        var int i;
+        let out = 8000;
+        let first = Array.new(12000);
+        do first.dispose();
+        while (i < 100) {
+            let a = Array.new((i * 80) + 1);
+            let b = Array.new(100 - i);
+            do a.dispose();
+            do b.dispose();
+            let i = i + 1;
+        }
+        let out[0] = Array.new(12000) = first;
+        return;
+    }
+}");
+        // Without merging, the freed blocks soon get too small for a
+        assert_eq!(cpu.read_data(8000), Ok(0xFFFF));
+    }
+
+    #[test]
+    fn test_strings_and_output() {
+        let cpu = run("\
+class Main {
+    function void main() {
+        var String s;
+        var Array out;
+        let out = 8000;
+        let s = String.new(6);
+        do s.setInt(-1234);
+        let out[0] = s.intValue();
+        let out[1] = s.length();
+        do Output.printString(\"Hi\");
+        do Output.println();
+        do Output.printInt(42);
+        return;
+    }
+}");
+        assert_eq!(cpu.read_data(8000), Ok((-1234i16) as u16));
+        assert_eq!(cpu.read_data(8001), Ok(5));
+        // Second row of 'H' and 'i' side by side, then of '4' and '2' a text row down
+        assert_eq!(cpu.read_data(SCREEN + 32), Ok(51 | (12 << 8)));
+        assert_eq!(cpu.read_data(SCREEN + 352 + 32), Ok(24 | (51 << 8)));
+    }
+
+    #[test]
+    fn test_drawing() {
+        let cpu = run("\
+class Main {
+    function void main() {
+        do Screen.drawLine(0, 0, 40, 0);
+        do Screen.drawLine(3, 10, 0, 13);
+        do Screen.drawRectangle(16, 20, 47, 21);
+        do Screen.drawCircle(100, 100, 5);
+        do Screen.setColor(false);
+        do Screen.drawPixel(1, 0);
+        return;
+    }
+}");
+        assert_eq!(cpu.read_data(SCREEN), Ok(0xFFFD));
+        assert_eq!(cpu.read_data(SCREEN + 1), Ok(0xFFFF));
+        assert_eq!(cpu.read_data(SCREEN + 2), Ok(0x01FF));
+        assert_eq!(cpu.read_data(SCREEN + 10 * 32), Ok(0b1100));
+        assert_eq!(cpu.read_data(SCREEN + 13 * 32), Ok(0b0001));
+        assert_eq!(cpu.read_data(SCREEN + 20 * 32 + 1), Ok(0xFFFF));
+        assert_eq!(cpu.read_data(SCREEN + 21 * 32 + 2), Ok(0xFFFF));
+        assert_eq!(cpu.read_data(SCREEN + 21 * 32 + 3), Ok(0));
+        // The circle's middle row runs from x 95 to 105
+        assert_eq!(cpu.read_data(SCREEN + 100 * 32 + 5), Ok(0x8000));
+        assert_eq!(cpu.read_data(SCREEN + 100 * 32 + 6), Ok(0x03FF));
+        assert_eq!(cpu.read_data(SCREEN + 95 * 32 + 6), Ok(0x0010));
+    }
+}
@@ -0,0 +1,707 @@
+use crate::error::error::{Error, ErrorKind};
+use crate::jack::ast::{
+    BinaryOp,
+    Class,
+    ClassVarDec,
+    ClassVarKind,
+    Expression,
+    KeywordConstant,
+    Name,
+    Parameter,
+    Statement,
+    StatementKind,
+    Subroutine,
+    SubroutineCall,
+    SubroutineKind,
+    Term,
+    TermKind,
+    Type,
+    UnaryOp,
+    VarDec,
+};
+use crate::jack::tokenizer::{Keyword, Span, Token, TokenKind, Tokenizer};
+
+const STATEMENT_KEYWORDS: [Keyword; 5] = [Keyword::Let, Keyword::If, Keyword::While, Keyword::Do, Keyword::Return];
+const SUBROUTINE_KEYWORDS: [Keyword; 3] = [Keyword::Constructor, Keyword::Function, Keyword::Method];
+
+// Recursive descent over the tokens of one class. After a syntax error it
+// skips to the next statement or subroutine, so one mistake doesn't hide the rest
+pub struct Parser {
+    pub file_name: String,
+    tokens: Vec<Token>,
+    position: usize,
+    errors: Vec<Error>,
+}
+
+impl Parser {
+    pub fn new() -> Self {
+        Parser { file_name: String::new(), tokens: vec![], position: 0, errors: vec![] }
+    }
+
+    // Tokenizes and parses a .jack file, stopping after tokenizing if that fails
+    pub fn parse_source(&mut self, contents: &str) -> Result<Class, Vec<Error>> {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.file_name = self.file_name.clone();
+        tokenizer.tokenize(contents)?;
+        self.parse_tokens(tokenizer.tokens)
+    }
+
+    pub fn parse_tokens(&mut self, tokens: Vec<Token>) -> Result<Class, Vec<Error>> {
+        self.tokens = tokens;
+        self.position = 0;
+        self.errors.clear();
+
+        let class = match self.class() {
+            Ok(class) => class,
+            Err(error) => {
+                self.errors.push(error);
+                return Err(std::mem::take(&mut self.errors));
+            }
+        };
+        if let Some(token) = self.peek() {
+            let error = Error::new(
+                ErrorKind::UnexpectedToken("end of file".to_string(), describe(Some(token))),
+                token.span.location(&self.file_name),
+            );
+            self.errors.push(error);
+        }
+
+        if self.errors.is_empty() {
+            Ok(class)
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn peek_kind(&self, offset: usize) -> Option<&TokenKind> {
+        self.tokens.get(self.position + offset).map(|token| &token.kind)
+    }
+
+    fn is_symbol(&self, symbol: char) -> bool {
+        self.peek_kind(0) == Some(&TokenKind::Symbol(symbol))
+    }
+
+    fn is_keyword(&self, keywords: &[Keyword]) -> bool {
+        matches!(self.peek_kind(0), Some(TokenKind::Keyword(keyword)) if keywords.contains(keyword))
+    }
+
+    fn advance(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    // Span of the last token taken, to end the span of whatever it closes
+    fn previous(&self) -> Span {
+        self.tokens[self.position - 1].span
+    }
+
+    // Points at the token that was found instead, or just past the last one
+    fn unexpected(&self, expected: &str) -> Error {
+        let span = match self.peek() {
+            Some(token) => token.span,
+            None => self.tokens.last().map_or(Span::new(1, 1, 1, 1), |token| {
+                Span::new(token.span.end_line, token.span.end_column, token.span.end_line, token.span.end_column)
+            }),
+        };
+        Error::new(
+            ErrorKind::UnexpectedToken(expected.to_string(), describe(self.peek())),
+            span.location(&self.file_name),
+        )
+    }
+
+    fn expect_symbol(&mut self, symbol: char) -> Result<Span, Error> {
+        if self.is_symbol(symbol) {
+            Ok(self.advance().unwrap().span)
+        } else {
+            Err(self.unexpected(&format!("'{}'", symbol)))
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: Keyword) -> Result<Span, Error> {
+        if self.is_keyword(&[keyword]) {
+            Ok(self.advance().unwrap().span)
+        } else {
+            Err(self.unexpected(&format!("'{}'", keyword.as_str())))
+        }
+    }
+
+    fn identifier(&mut self, what: &str) -> Result<Name, Error> {
+        match self.peek_kind(0) {
+            Some(TokenKind::Identifier(name)) => {
+                let name = name.clone();
+                let span = self.advance().unwrap().span;
+                Ok(Name { name, span })
+            }
+            _ => Err(self.unexpected(what)),
+        }
+    }
+
+    // Skips to the next token in `stops` or past the next ';', stepping over
+    // whole `{ }` blocks. Stops before a '}' that closes the current block
+    fn synchronize(&mut self, stops: &[Keyword]) {
+        let mut depth = 0;
+        while let Some(kind) = self.peek_kind(0) {
+            match kind {
+                TokenKind::Symbol('{') => depth += 1,
+                TokenKind::Symbol('}') if depth == 0 => return,
+                TokenKind::Symbol('}') => depth -= 1,
+                TokenKind::Symbol(';') if depth == 0 => {
+                    self.advance();
+                    return;
+                }
+                TokenKind::Keyword(keyword) if depth == 0 && stops.contains(keyword) => return,
+                _ => {}
+            }
+            self.advance();
+        }
+    }
+
+    fn class(&mut self) -> Result<Class, Error> {
+        let start = self.expect_keyword(Keyword::Class)?;
+        let name = self.identifier("a class name")?;
+        self.expect_symbol('{')?;
+
+        let mut vars = vec![];
+        while self.is_keyword(&[Keyword::Static, Keyword::Field]) {
+            match self.class_var_dec() {
+                Ok(var) => vars.push(var),
+                Err(error) => {
+                    self.errors.push(error);
+                    self.synchronize(&[Keyword::Static, Keyword::Field, Keyword::Constructor, Keyword::Function, Keyword::Method]);
+                }
+            }
+        }
+
+        let mut subroutines = vec![];
+        while self.is_keyword(&SUBROUTINE_KEYWORDS) {
+            match self.subroutine() {
+                Ok(subroutine) => subroutines.push(subroutine),
+                Err(error) => {
+                    self.errors.push(error);
+                    // Resume at the next subroutine, or at the '}' that closes the class
+                    while self.peek().is_some() && !self.is_keyword(&SUBROUTINE_KEYWORDS) {
+                        self.advance();
+                    }
+                    if self.peek().is_none() && self.tokens.last().is_some_and(|token| token.kind == TokenKind::Symbol('}')) {
+                        self.position = self.tokens.len() - 1;
+                    }
+                }
+            }
+        }
+
+        if !self.is_symbol('}') {
+            let expected = if subroutines.is_empty() { "a class member or '}'" } else { "a subroutine or '}'" };
+            return Err(self.unexpected(expected));
+        }
+        let end = self.advance().unwrap().span;
+        Ok(Class { name, vars, subroutines, span: start.to(end) })
+    }
+
+    fn class_var_dec(&mut self) -> Result<ClassVarDec, Error> {
+        let token = self.advance().unwrap();
+        let kind = match token.kind {
+            TokenKind::Keyword(Keyword::Static) => ClassVarKind::Static,
+            _ => ClassVarKind::Field,
+        };
+        let ty = self.ty()?;
+        let names = self.names()?;
+        let end = self.expect_symbol(';')?;
+        Ok(ClassVarDec { kind, ty, names, span: token.span.to(end) })
+    }
+
+    fn ty(&mut self) -> Result<Type, Error> {
+        let ty = match self.peek_kind(0) {
+            Some(TokenKind::Keyword(Keyword::Int)) => Type::Int,
+            Some(TokenKind::Keyword(Keyword::Char)) => Type::Char,
+            Some(TokenKind::Keyword(Keyword::Boolean)) => Type::Boolean,
+            Some(TokenKind::Identifier(name)) => Type::Class(name.clone()),
+            _ => return Err(self.unexpected("a type")),
+        };
+        self.advance();
+        Ok(ty)
+    }
+
+    // One or more comma separated variable names
+    fn names(&mut self) -> Result<Vec<Name>, Error> {
+        let mut names = vec![self.identifier("a variable name")?];
+        while self.is_symbol(',') {
+            self.advance();
+            names.push(self.identifier("a variable name")?);
+        }
+        Ok(names)
+    }
+
+    fn subroutine(&mut self) -> Result<Subroutine, Error> {
+        let token = self.advance().unwrap();
+        let kind = match token.kind {
+            TokenKind::Keyword(Keyword::Constructor) => SubroutineKind::Constructor,
+            TokenKind::Keyword(Keyword::Function) => SubroutineKind::Function,
+            _ => SubroutineKind::Method,
+        };
+        let return_type = if self.is_keyword(&[Keyword::Void]) {
+            self.advance();
+            None
+        } else {
+            Some(self.ty().map_err(|_| self.unexpected("a type or 'void'"))?)
+        };
+        let name = self.identifier("a subroutine name")?;
+
+        self.expect_symbol('(')?;
+        let mut params = vec![];
+        if !self.is_symbol(')') {
+            loop {
+                let ty = self.ty().map_err(|_| self.unexpected("a parameter type or ')'"))?;
+                let name = self.identifier("a parameter name")?;
+                params.push(Parameter { ty, name });
+                if !self.is_symbol(',') {
+                    break;
+                }
+                self.advance();
+            }
+        }
+        self.expect_symbol(')')?;
+
+        self.expect_symbol('{')?;
+        let mut locals = vec![];
+        while self.is_keyword(&[Keyword::Var]) {
+            let start = self.advance().unwrap().span;
+            let ty = self.ty()?;
+            let names = self.names()?;
+            let end = self.expect_symbol(';')?;
+            locals.push(VarDec { ty, names, span: start.to(end) });
+        }
+        let statements = self.statements();
+        let end = self.expect_symbol('}').map_err(|_| self.unexpected("a statement or '}'"))?;
+
+        Ok(Subroutine { kind, return_type, name, params, locals, statements, span: token.span.to(end) })
+    }
+
+    fn statements(&mut self) -> Vec<Statement> {
+        let mut statements = vec![];
+        while self.is_keyword(&STATEMENT_KEYWORDS) {
+            match self.statement() {
+                Ok(statement) => statements.push(statement),
+                Err(error) => {
+                    self.errors.push(error);
+                    self.synchronize(&STATEMENT_KEYWORDS);
+                }
+            }
+        }
+        statements
+    }
+
+    // A `{ statements }` block
+    fn block(&mut self) -> Result<Vec<Statement>, Error> {
+        self.expect_symbol('{')?;
+        let statements = self.statements();
+        self.expect_symbol('}').map_err(|_| self.unexpected("a statement or '}'"))?;
+        Ok(statements)
+    }
+
+    fn statement(&mut self) -> Result<Statement, Error> {
+        let token = self.advance().unwrap();
+        let kind = match token.kind {
+            TokenKind::Keyword(Keyword::Let) => {
+                let target = self.identifier("a variable name")?;
+                let index = if self.is_symbol('[') {
+                    self.advance();
+                    let index = self.expression()?;
+                    self.expect_symbol(']')?;
+                    Some(index)
+                } else {
+                    None
+                };
+                self.expect_symbol('=').map_err(|_| self.unexpected(if index.is_some() { "'='" } else { "'[' or '='" }))?;
+                let value = self.expression()?;
+                self.expect_symbol(';')?;
+                StatementKind::Let { target, index, value }
+            }
+            TokenKind::Keyword(Keyword::If) => {
+                self.expect_symbol('(')?;
+                let condition = self.expression()?;
+                self.expect_symbol(')')?;
+                let then_branch = self.block()?;
+                let else_branch = if self.is_keyword(&[Keyword::Else]) {
+                    self.advance();
+                    Some(self.block()?)
+                } else {
+                    None
+                };
+                StatementKind::If { condition, then_branch, else_branch }
+            }
+            TokenKind::Keyword(Keyword::While) => {
+                self.expect_symbol('(')?;
+                let condition = self.expression()?;
+                self.expect_symbol(')')?;
+                let body = self.block()?;
+                StatementKind::While { condition, body }
+            }
+            TokenKind::Keyword(Keyword::Do) => {
+                let name = self.identifier("a subroutine call")?;
+                let call = self.call(name)?;
+                self.expect_symbol(';')?;
+                StatementKind::Do(call)
+            }
+            _ => {
+                let value = if self.is_symbol(';') { None } else { Some(self.expression()?) };
+                self.expect_symbol(';')?;
+                StatementKind::Return(value)
+            }
+        };
+        Ok(Statement { kind, span: token.span.to(self.previous()) })
+    }
+
+    // The rest of a call after its first name, which is the subroutine or the receiver
+    fn call(&mut self, first: Name) -> Result<SubroutineCall, Error> {
+        let (receiver, name) = if self.is_symbol('.') {
+            self.advance();
+            let name = self.identifier("a subroutine name")?;
+            (Some(first), name)
+        } else {
+            (None, first)
+        };
+        let start = receiver.as_ref().unwrap_or(&name).span;
+
+        self.expect_symbol('(').map_err(|_| self.unexpected(if receiver.is_some() { "'('" } else { "'(' or '.'" }))?;
+        let mut args = vec![];
+        if !self.is_symbol(')') {
+            args.push(self.expression()?);
+            while self.is_symbol(',') {
+                self.advance();
+                args.push(self.expression()?);
+            }
+        }
+        let end = self.expect_symbol(')').map_err(|_| self.unexpected("',' or ')'"))?;
+        Ok(SubroutineCall { receiver, name, args, span: start.to(end) })
+    }
+
+    fn expression(&mut self) -> Result<Expression, Error> {
+        let first = self.term()?;
+        let mut rest = vec![];
+        while let Some(op) = match self.peek_kind(0) {
+            Some(TokenKind::Symbol(symbol)) => BinaryOp::from_symbol(*symbol),
+            _ => None,
+        } {
+            self.advance();
+            rest.push((op, self.term()?));
+        }
+        let span = first.span.to(rest.last().map_or(first.span, |(_, term)| term.span));
+        Ok(Expression { first: Box::new(first), rest, span })
+    }
+
+    fn term(&mut self) -> Result<Term, Error> {
+        let Some(token) = self.peek().cloned() else {
+            return Err(self.unexpected("an expression"));
+        };
+        let kind = match token.kind {
+            TokenKind::IntegerConstant(value) => {
+                self.advance();
+                TermKind::IntegerConstant(value)
+            }
+            TokenKind::StringConstant(text) => {
+                self.advance();
+                TermKind::StringConstant(text)
+            }
+            TokenKind::Keyword(keyword) => {
+                let constant = match keyword {
+                    Keyword::True => KeywordConstant::True,
+                    Keyword::False => KeywordConstant::False,
+                    Keyword::Null => KeywordConstant::Null,
+                    Keyword::This => KeywordConstant::This,
+                    _ => return Err(self.unexpected("an expression")),
+                };
+                self.advance();
+                TermKind::KeywordConstant(constant)
+            }
+            TokenKind::Identifier(name) => {
+                self.advance();
+                let name = Name { name, span: token.span };
+                match self.peek_kind(0) {
+                    Some(TokenKind::Symbol('[')) => {
+                        self.advance();
+                        let index = self.expression()?;
+                        self.expect_symbol(']')?;
+                        TermKind::ArrayAccess(name, Box::new(index))
+                    }
+                    Some(TokenKind::Symbol('(' | '.')) => TermKind::Call(self.call(name)?),
+                    _ => TermKind::Variable(name),
+                }
+            }
+            TokenKind::Symbol('(') => {
+                self.advance();
+                let expression = self.expression()?;
+                self.expect_symbol(')')?;
+                TermKind::Parenthesized(Box::new(expression))
+            }
+            TokenKind::Symbol(symbol @ ('-' | '~')) => {
+                self.advance();
+                let op = if symbol == '-' { UnaryOp::Neg } else { UnaryOp::Not };
+                TermKind::Unary(op, Box::new(self.term()?))
+            }
+            _ => return Err(self.unexpected("an expression")),
+        };
+        Ok(Term { kind, span: token.span.to(self.previous()) })
+    }
+}
+
+// How a token is named in error messages
+fn describe(token: Option<&Token>) -> String {
+    match token.map(|token| &token.kind) {
+        None => "end of file".to_string(),
+        Some(kind @ TokenKind::StringConstant(_)) => kind.to_string(),
+        Some(kind) => format!("'{}'", kind),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Result<Class, Vec<Error>> {
+        let mut parser = Parser::new();
+        parser.file_name = "Main.jack".to_string();
+        parser.parse_source(source)
+    }
+
+    // "line:column: message" for every error
+    fn errors(source: &str) -> Vec<String> {
+        parse(source).unwrap_err().iter()
+            .map(|error| format!("{}:{}: {}", error.location.line, error.location.column, error.kind))
+            .collect()
+    }
+
+    const SQUARE: &str = "\
+class Square {
+    field int x, y;
+    static Array cache;
+
+    constructor Square new(int ax, int ay) {
+        let x = ax;
+        let y = ay;
+        return this;
+    }
+
+    method void move(boolean left) {
+        var int i;
+        let i = 0;
+        while (i < 2) {
+            if (left) { let x = x - 1; } else { let x = x + 1; }
+            let cache[i] = -x;
+            let i = i + 1;
+        }
+        do Screen.drawRectangle(x, y, x + 10, (y + 10) * 2);
+        do draw();
+        return;
+    }
+
+    method void draw() {
+        return;
+    }
+}
+";
+
+    #[test]
+    fn test_parse_class() {
+        let class = parse(SQUARE).unwrap();
+        assert_eq!(class.name.name, "Square");
+        assert_eq!(class.vars.len(), 2);
+        assert_eq!(class.vars[0].names.len(), 2);
+        assert_eq!(class.vars[1].ty, Type::Class("Array".to_string()));
+        assert_eq!(class.subroutines.len(), 3);
+
+        let new = &class.subroutines[0];
+        assert_eq!(new.kind, SubroutineKind::Constructor);
+        assert_eq!(new.return_type, Some(Type::Class("Square".to_string())));
+        assert_eq!(new.params.len(), 2);
+        assert_eq!(new.span, Span::new(5, 5, 9, 6));
+
+        let method = &class.subroutines[1];
+        assert_eq!(method.return_type, None);
+        assert_eq!(method.locals[0].names[0].name, "i");
+        assert_eq!(method.statements.len(), 5);
+
+        let StatementKind::Do(call) = &method.statements[2].kind else { panic!() };
+        assert_eq!(call.receiver.as_ref().unwrap().name, "Screen");
+        assert_eq!(call.args.len(), 4);
+        assert_eq!(call.args[3].rest[0].0, BinaryOp::Mul);
+        assert!(matches!(call.args[3].first.kind, TermKind::Parenthesized(_)));
+        assert_eq!(call.span, Span::new(19, 12, 19, 60));
+    }
+
+    #[test]
+    fn test_terms() {
+        let class = parse("class A { function int f() { return -a[1] + ~(b) & g(\"s\", null) | B.h() = this; } }").unwrap();
+        let StatementKind::Return(Some(expression)) = &class.subroutines[0].statements[0].kind else { panic!() };
+
+        let TermKind::Unary(UnaryOp::Neg, negated) = &expression.first.kind else { panic!() };
+        assert!(matches!(negated.kind, TermKind::ArrayAccess(_, _)));
+        let ops: Vec<BinaryOp> = expression.rest.iter().map(|(op, _)| *op).collect();
+        assert_eq!(ops, [BinaryOp::Add, BinaryOp::And, BinaryOp::Or, BinaryOp::Eq]);
+        assert!(matches!(expression.rest[1].1.kind, TermKind::Call(SubroutineCall { receiver: None, .. })));
+        assert_eq!(expression.rest[3].1.kind, TermKind::KeywordConstant(KeywordConstant::This));
+    }
+
+    #[test]
+    fn test_syntax_errors_with_hints() {
+        assert_eq!(errors("class Main { function void main() { let x 1; return; } }"), [
+            "1:43: Expected '[' or '=' but found '1'",
+        ]);
+        assert_eq!(errors("class Main { function void main() { do Output.print(1 2); } }"), [
+            "1:55: Expected ',' or ')' but found '2'",
+        ]);
+        assert_eq!(errors("class Main { function void main() { let x = ; } }"), [
+            "1:45: Expected an expression but found ';'",
+        ]);
+        assert_eq!(errors("class Main { function main() { return; } }"), [
+            "1:27: Expected a subroutine name but found '('",
+        ]);
+        assert_eq!(errors("class Main { function void main() { return; }"), [
+            "1:46: Expected a subroutine or '}' but found end of file",
+        ]);
+        assert_eq!(errors("class Main { } class"), [
+            "1:16: Expected end of file but found 'class'",
+        ]);
+    }
+
+    #[test]
+    fn test_recovers_after_errors() {
+        let source = "\
+class Main {
+    function void main() {
+        let x = (1;
+        if (x { let y = 2; }
+        do f(;
+        return;
+    }
+    function void other() {
+        var int;
+        return;
+    }
+}";
+        assert_eq!(errors(source), [
+            "3:19: Expected ')' but found ';'",
+            "4:15: Expected ')' but found '{'",
+            "5:14: Expected an expression but found ';'",
+            "9:16: Expected a variable name but found ';'",
+        ]);
+    }
+
+    #[test]
+    fn test_xml() {
+        let class = parse("class Main {\n  static int n;\n  function void main(int a, char b) {\n    var Array v;\n    let v[a] = -1;\n    if (a < 2) { do Main.f(); } else { return; }\n    return \"x\";\n  }\n}").unwrap();
+
+        assert_eq!(class.to_xml(), "\
+<class>
+  <keyword> class </keyword>
+  <identifier> Main </identifier>
+  <symbol> { </symbol>
+  <classVarDec>
+    <keyword> static </keyword>
+    <keyword> int </keyword>
+    <identifier> n </identifier>
+    <symbol> ; </symbol>
+  </classVarDec>
+  <subroutineDec>
+    <keyword> function </keyword>
+    <keyword> void </keyword>
+    <identifier> main </identifier>
+    <symbol> ( </symbol>
+    <parameterList>
+      <keyword> int </keyword>
+      <identifier> a </identifier>
+      <symbol> , </symbol>
+      <keyword> char </keyword>
+      <identifier> b </identifier>
+    </parameterList>
+    <symbol> ) </symbol>
+    <subroutineBody>
+      <symbol> { </symbol>
+      <varDec>
+        <keyword> var </keyword>
+        <identifier> Array </identifier>
+        <identifier> v </identifier>
+        <symbol> ; </symbol>
+      </varDec>
+      <statements>
+        <letStatement>
+          <keyword> let </keyword>
+          <identifier> v </identifier>
+          <symbol> [ </symbol>
+          <expression>
+            <term>
+              <identifier> a </identifier>
+            </term>
+          </expression>
+          <symbol> ] </symbol>
+          <symbol> = </symbol>
+          <expression>
+            <term>
+              <symbol> - </symbol>
+              <term>
+                <integerConstant> 1 </integerConstant>
+              </term>
+            </term>
+          </expression>
+          <symbol> ; </symbol>
+        </letStatement>
+        <ifStatement>
+          <keyword> if </keyword>
+          <symbol> ( </symbol>
+          <expression>
+            <term>
+              <identifier> a </identifier>
+            </term>
+            <symbol> &lt; </symbol>
+            <term>
+              <integerConstant> 2 </integerConstant>
+            </term>
+          </expression>
+          <symbol> ) </symbol>
+          <symbol> { </symbol>
+          <statements>
+            <doStatement>
+              <keyword> do </keyword>
+              <identifier> Main </identifier>
+              <symbol> . </symbol>
+              <identifier> f </identifier>
+              <symbol> ( </symbol>
+              <expressionList>
+              </expressionList>
+              <symbol> ) </symbol>
+              <symbol> ; </symbol>
+            </doStatement>
+          </statements>
+          <symbol> } </symbol>
+          <keyword> else </keyword>
+          <symbol> { </symbol>
+          <statements>
+            <returnStatement>
+              <keyword> return </keyword>
+              <symbol> ; </symbol>
+            </returnStatement>
+          </statements>
+          <symbol> } </symbol>
+        </ifStatement>
+        <returnStatement>
+          <keyword> return </keyword>
+          <expression>
+            <term>
+              <stringConstant> x </stringConstant>
+            </term>
+          </expression>
+          <symbol> ; </symbol>
+        </returnStatement>
+      </statements>
+      <symbol> } </symbol>
+    </subroutineBody>
+  </subroutineDec>
+  <symbol> } </symbol>
+</class>
+");
+    }
+}
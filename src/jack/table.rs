@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+
+use crate::jack::ast::Type;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum VarKind {
+    Static,
+    Field,
+    Argument,
+    Local,
+}
+
+impl VarKind {
+    // VM memory segment the variable lives in
+    pub fn segment(&self) -> &'static str {
+        match self {
+            VarKind::Static => "static",
+            VarKind::Field => "this",
+            VarKind::Argument => "argument",
+            VarKind::Local => "local",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variable {
+    pub ty: Type,
+    pub kind: VarKind,
+    pub index: u16,
+}
+
+// Class scope for statics and fields, subroutine scope for arguments and
+// locals. Subroutine names hide class names
+pub struct SymbolTable {
+    class: HashMap<String, Variable>,
+    subroutine: HashMap<String, Variable>,
+    counts: HashMap<VarKind, u16>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        SymbolTable { class: HashMap::new(), subroutine: HashMap::new(), counts: HashMap::new() }
+    }
+
+    // Forgets everything, for the next class
+    pub fn start_class(&mut self) {
+        self.class.clear();
+        self.subroutine.clear();
+        self.counts.clear();
+    }
+
+    pub fn start_subroutine(&mut self) {
+        self.subroutine.clear();
+        self.counts.remove(&VarKind::Argument);
+        self.counts.remove(&VarKind::Local);
+    }
+
+    // Gives the variable the next index of its kind. Returns false, and
+    // changes nothing, when the name is already taken in the same scope
+    pub fn define(&mut self, name: &str, ty: Type, kind: VarKind) -> bool {
+        let scope = match kind {
+            VarKind::Static | VarKind::Field => &mut self.class,
+            VarKind::Argument | VarKind::Local => &mut self.subroutine,
+        };
+        if scope.contains_key(name) {
+            return false;
+        }
+
+        let count = self.counts.entry(kind).or_insert(0);
+        scope.insert(name.to_string(), Variable { ty, kind, index: *count });
+        *count += 1;
+        true
+    }
+
+    pub fn count(&self, kind: VarKind) -> u16 {
+        self.counts.get(&kind).copied().unwrap_or(0)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Variable> {
+        self.subroutine.get(name).or_else(|| self.class.get(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scopes_and_indexes() {
+        let mut table = SymbolTable::new();
+        assert!(table.define("x", Type::Int, VarKind::Field));
+        assert!(table.define("y", Type::Int, VarKind::Field));
+        assert!(table.define("count", Type::Int, VarKind::Static));
+        assert!(!table.define("x", Type::Char, VarKind::Static));
+
+        table.start_subroutine();
+        assert!(table.define("this", Type::Class("Point".to_string()), VarKind::Argument));
+        assert!(table.define("x", Type::Boolean, VarKind::Argument));
+        assert!(table.define("i", Type::Int, VarKind::Local));
+
+        assert_eq!(table.get("x"), Some(&Variable { ty: Type::Boolean, kind: VarKind::Argument, index: 1 }));
+        assert_eq!(table.get("y").unwrap().index, 1);
+        assert_eq!(table.count(VarKind::Field), 2);
+        assert_eq!(table.count(VarKind::Local), 1);
+
+        table.start_subroutine();
+        assert_eq!(table.get("x").unwrap().kind, VarKind::Field);
+        assert_eq!(table.get("i"), None);
+        assert_eq!(table.count(VarKind::Argument), 0);
+    }
+}
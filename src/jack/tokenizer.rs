@@ -0,0 +1,398 @@
+use std::fmt;
+
+use crate::error::error::{Error, ErrorKind, Location};
+
+const SYMBOLS: &str = "{}()[].,;+-*/&|<>=~";
+// Largest integer constant Jack allows
+const MAX_INTEGER: u32 = 32767;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Keyword {
+    Class,
+    Constructor,
+    Function,
+    Method,
+    Field,
+    Static,
+    Var,
+    Int,
+    Char,
+    Boolean,
+    Void,
+    True,
+    False,
+    Null,
+    This,
+    Let,
+    Do,
+    If,
+    Else,
+    While,
+    Return,
+}
+
+impl Keyword {
+    pub fn from_word(word: &str) -> Option<Self> {
+        let keyword = match word {
+            "class" => Keyword::Class,
+            "constructor" => Keyword::Constructor,
+            "function" => Keyword::Function,
+            "method" => Keyword::Method,
+            "field" => Keyword::Field,
+            "static" => Keyword::Static,
+            "var" => Keyword::Var,
+            "int" => Keyword::Int,
+            "char" => Keyword::Char,
+            "boolean" => Keyword::Boolean,
+            "void" => Keyword::Void,
+            "true" => Keyword::True,
+            "false" => Keyword::False,
+            "null" => Keyword::Null,
+            "this" => Keyword::This,
+            "let" => Keyword::Let,
+            "do" => Keyword::Do,
+            "if" => Keyword::If,
+            "else" => Keyword::Else,
+            "while" => Keyword::While,
+            "return" => Keyword::Return,
+            _ => return None,
+        };
+        Some(keyword)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Keyword::Class => "class",
+            Keyword::Constructor => "constructor",
+            Keyword::Function => "function",
+            Keyword::Method => "method",
+            Keyword::Field => "field",
+            Keyword::Static => "static",
+            Keyword::Var => "var",
+            Keyword::Int => "int",
+            Keyword::Char => "char",
+            Keyword::Boolean => "boolean",
+            Keyword::Void => "void",
+            Keyword::True => "true",
+            Keyword::False => "false",
+            Keyword::Null => "null",
+            Keyword::This => "this",
+            Keyword::Let => "let",
+            Keyword::Do => "do",
+            Keyword::If => "if",
+            Keyword::Else => "else",
+            Keyword::While => "while",
+            Keyword::Return => "return",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Keyword(Keyword),
+    Symbol(char),
+    IntegerConstant(u16),
+    StringConstant(String),
+    Identifier(String),
+}
+
+impl TokenKind {
+    // Element name in the token XML
+    pub fn tag(&self) -> &'static str {
+        match self {
+            TokenKind::Keyword(_) => "keyword",
+            TokenKind::Symbol(_) => "symbol",
+            TokenKind::IntegerConstant(_) => "integerConstant",
+            TokenKind::StringConstant(_) => "stringConstant",
+            TokenKind::Identifier(_) => "identifier",
+        }
+    }
+
+    // The token as it is written, without the quotes of a string constant
+    pub fn text(&self) -> String {
+        match self {
+            TokenKind::Keyword(keyword) => keyword.as_str().to_string(),
+            TokenKind::Symbol(symbol) => symbol.to_string(),
+            TokenKind::IntegerConstant(value) => value.to_string(),
+            TokenKind::StringConstant(text) | TokenKind::Identifier(text) => text.clone(),
+        }
+    }
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenKind::StringConstant(text) => write!(f, "\"{}\"", text),
+            _ => write!(f, "{}", self.text()),
+        }
+    }
+}
+
+// 1-based line and column of the first character and of the one just past the end
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub end_line: usize,
+    pub end_column: usize,
+}
+
+impl Span {
+    pub fn new(line: usize, column: usize, end_line: usize, end_column: usize) -> Self {
+        Span { line, column, end_line, end_column }
+    }
+
+    // From the start of this span to the end of `other`
+    pub fn to(&self, other: Span) -> Span {
+        Span::new(self.line, self.column, other.end_line, other.end_column)
+    }
+
+    pub fn location(&self, file: &str) -> Location {
+        Location::new(file, self.line, self.column)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+pub struct Tokenizer {
+    pub file_name: String,
+    pub tokens: Vec<Token>,
+}
+
+impl Tokenizer {
+    pub fn new() -> Self {
+        Tokenizer { file_name: String::new(), tokens: vec![] }
+    }
+
+    // Skips over bad tokens so every one of them is reported
+    pub fn tokenize(&mut self, contents: &str) -> Result<(), Vec<Error>> {
+        let mut errors = vec![];
+        self.tokens.clear();
+
+        let mut cursor = Cursor { chars: contents.chars().collect(), position: 0, line: 1, column: 1 };
+        while let Some(c) = cursor.peek(0) {
+            let (line, column) = (cursor.line, cursor.column);
+            let error = |kind| Error::new(kind, Location::new(&self.file_name, line, column));
+
+            let kind = if c.is_whitespace() {
+                cursor.advance();
+                continue;
+            } else if c == '/' && cursor.peek(1) == Some('/') {
+                while cursor.peek(0).is_some_and(|c| c != '\n') {
+                    cursor.advance();
+                }
+                continue;
+            } else if c == '/' && cursor.peek(1) == Some('*') {
+                // Covers /** */ documentation comments too
+                cursor.advance();
+                cursor.advance();
+                while cursor.peek(0).is_some() && !(cursor.peek(0) == Some('*') && cursor.peek(1) == Some('/')) {
+                    cursor.advance();
+                }
+                if cursor.peek(0).is_none() {
+                    errors.push(error(ErrorKind::UnterminatedComment));
+                } else {
+                    cursor.advance();
+                    cursor.advance();
+                }
+                continue;
+            } else if SYMBOLS.contains(c) {
+                cursor.advance();
+                TokenKind::Symbol(c)
+            } else if c == '"' {
+                cursor.advance();
+                let text = cursor.take_while(|c| c != '"' && c != '\n');
+                if cursor.peek(0) != Some('"') {
+                    errors.push(error(ErrorKind::UnterminatedString));
+                    continue;
+                }
+                cursor.advance();
+                // The compiler pushes each character's code, so only printable ASCII fits
+                if let Some(bad) = text.chars().find(|c| !(' '..='~').contains(c)) {
+                    errors.push(error(ErrorKind::InvalidStringCharacter(bad)));
+                    continue;
+                }
+                TokenKind::StringConstant(text)
+            } else if c.is_ascii_digit() {
+                let word = cursor.take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+                match word.parse::<u32>() {
+                    Ok(value) if value <= MAX_INTEGER => TokenKind::IntegerConstant(value as u16),
+                    _ if word.chars().all(|c| c.is_ascii_digit()) => {
+                        errors.push(error(ErrorKind::IntegerTooLarge(word)));
+                        continue;
+                    }
+                    _ => {
+                        errors.push(error(ErrorKind::InvalidToken(word)));
+                        continue;
+                    }
+                }
+            } else if c.is_ascii_alphabetic() || c == '_' {
+                let word = cursor.take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+                match Keyword::from_word(&word) {
+                    Some(keyword) => TokenKind::Keyword(keyword),
+                    None => TokenKind::Identifier(word),
+                }
+            } else {
+                cursor.advance();
+                errors.push(error(ErrorKind::InvalidToken(c.to_string())));
+                continue;
+            };
+
+            let span = Span::new(line, column, cursor.line, cursor.column);
+            self.tokens.push(Token { kind, span });
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    // The `<tokens>` format of the nand2tetris reference *T.xml files
+    pub fn to_xml(&self) -> String {
+        let mut xml = String::from("<tokens>\n");
+        for token in &self.tokens {
+            let tag = token.kind.tag();
+            xml.push_str(&format!("<{}> {} </{}>\n", tag, escape_xml(&token.kind.text()), tag));
+        }
+        xml.push_str("</tokens>\n");
+        xml
+    }
+}
+
+pub fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+struct Cursor {
+    chars: Vec<char>,
+    position: usize,
+    line: usize,
+    column: usize,
+}
+
+impl Cursor {
+    fn peek(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.position + offset).copied()
+    }
+
+    fn advance(&mut self) {
+        if self.peek(0) == Some('\n') {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        self.position += 1;
+    }
+
+    fn take_while<F: Fn(char) -> bool>(&mut self, keep: F) -> String {
+        let mut text = String::new();
+        while let Some(c) = self.peek(0).filter(|c| keep(*c)) {
+            text.push(c);
+            self.advance();
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(source: &str) -> Vec<TokenKind> {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.tokenize(source).unwrap();
+        tokenizer.tokens.into_iter().map(|token| token.kind).collect()
+    }
+
+    #[test]
+    fn test_token_kinds() {
+        assert_eq!(kinds("let x[i] = \"a < b\" + 32767;"), vec![
+            TokenKind::Keyword(Keyword::Let),
+            TokenKind::Identifier("x".to_string()),
+            TokenKind::Symbol('['),
+            TokenKind::Identifier("i".to_string()),
+            TokenKind::Symbol(']'),
+            TokenKind::Symbol('='),
+            TokenKind::StringConstant("a < b".to_string()),
+            TokenKind::Symbol('+'),
+            TokenKind::IntegerConstant(32767),
+            TokenKind::Symbol(';'),
+        ]);
+        assert_eq!(kinds("classy _x1 this"), vec![
+            TokenKind::Identifier("classy".to_string()),
+            TokenKind::Identifier("_x1".to_string()),
+            TokenKind::Keyword(Keyword::This),
+        ]);
+    }
+
+    #[test]
+    fn test_comments_and_spans() {
+        let source = "/** Doc\n * comment */\nclass Main { // the class\n  /* inline */ field int x;\n}";
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.tokenize(source).unwrap();
+
+        assert_eq!(tokenizer.tokens.len(), 8);
+        assert_eq!(tokenizer.tokens[0].span, Span::new(3, 1, 3, 6));
+        assert_eq!(tokenizer.tokens[3].kind, TokenKind::Keyword(Keyword::Field));
+        assert_eq!(tokenizer.tokens[3].span, Span::new(4, 16, 4, 21));
+        assert_eq!(tokenizer.tokens[7].span, Span::new(5, 1, 5, 2));
+    }
+
+    #[test]
+    fn test_errors_have_positions() {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.file_name = "Main.jack".to_string();
+        let errors = tokenizer.tokenize("let x = 40000;\nlet y = 3a # \"open\nlet z = \"hé 😀\";\n/* never closed").unwrap_err();
+
+        let found: Vec<(usize, usize, ErrorKind)> = errors.into_iter()
+            .map(|error| (error.location.line, error.location.column, error.kind))
+            .collect();
+        assert_eq!(found, vec![
+            (1, 9, ErrorKind::IntegerTooLarge("40000".to_string())),
+            (2, 9, ErrorKind::InvalidToken("3a".to_string())),
+            (2, 12, ErrorKind::InvalidToken("#".to_string())),
+            (2, 14, ErrorKind::UnterminatedString),
+            (3, 9, ErrorKind::InvalidStringCharacter('é')),
+            (4, 1, ErrorKind::UnterminatedComment),
+        ]);
+        // Tokens after the bad ones are still read
+        assert!(tokenizer.tokens.iter().any(|token| token.kind == TokenKind::Identifier("z".to_string())));
+    }
+
+    #[test]
+    fn test_xml() {
+        let mut tokenizer = Tokenizer::new();
+        tokenizer.tokenize("if (x < 1) { do Output.printString(\"A&B\"); }").unwrap();
+
+        assert_eq!(tokenizer.to_xml(), "\
+<tokens>
+<keyword> if </keyword>
+<symbol> ( </symbol>
+<identifier> x </identifier>
+<symbol> &lt; </symbol>
+<integerConstant> 1 </integerConstant>
+<symbol> ) </symbol>
+<symbol> { </symbol>
+<keyword> do </keyword>
+<identifier> Output </identifier>
+<symbol> . </symbol>
+<identifier> printString </identifier>
+<symbol> ( </symbol>
+<stringConstant> A&amp;B </stringConstant>
+<symbol> ) </symbol>
+<symbol> ; </symbol>
+<symbol> } </symbol>
+</tokens>
+");
+    }
+}
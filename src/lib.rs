@@ -0,0 +1,11 @@
+#![allow(clippy::new_without_default)]
+
+pub mod cli;
+pub mod debugger;
+pub mod display;
+pub mod error;
+pub mod executor;
+pub mod hardware;
+pub mod jack;
+pub mod parser;
+pub mod stack;
@@ -1,19 +1,15 @@
-mod executor;
-mod hardware;
-mod parser;
-mod stack;
+use std::process::ExitCode;
 
-use crate::executor::executor::Executor;
+use rust2tetris::cli::cli;
 
-fn main() {
-    let mut executor = Executor::new();
-
-    let commands = vec![
-        "push constant 7".into(),
-    ];
-
-    executor.set_stack(commands);
-    executor.run();
-    executor.run_print();
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
 
+    match cli::run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            err.exit_code()
+        }
+    }
 }
@@ -1,3 +1,11 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::error::{column_of, Error, ErrorKind, Location};
+use crate::parser::expression::evaluate;
+use crate::parser::symbols::{SymbolKind, Symbols};
 use crate::parser::table::{
     SymbolTable,
     comp_table,
@@ -10,13 +18,98 @@ pub enum AssemblyCommand {
     AInstruction(String),
     CInstruction(String),
     Label(String),
+    // `.word n`, a raw ROM word for encodings with no mnemonic
+    Word(u16),
+}
+
+// Where the instruction at a ROM address came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLine {
+    pub location: Location,
+    pub text: String,
+    // The closest label above the instruction
+    pub label: Option<String>,
+}
+
+impl SourceLine {
+    // `Prog.asm:12 in LOOP`, or `line 12` for generated code before any label
+    pub fn position(&self) -> String {
+        let position = if self.location.file.is_empty() {
+            format!("line {}", self.location.line)
+        } else {
+            format!("{}:{}", self.location.file, self.location.line)
+        };
+        match &self.label {
+            Some(label) => format!("{} in {}", position, label),
+            None => position,
+        }
+    }
+}
+
+impl fmt::Display for SourceLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.position(), self.text)
+    }
+}
+
+// Where a command from a macro body was written, and the use of the macro it came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expansion {
+    pub location: Location,
+    pub name: String,
+    pub line: usize,
+}
+
+impl Expansion {
+    pub fn error(&self, kind: ErrorKind) -> Error {
+        Error::new(kind, self.location.clone()).in_expansion(&self.name, self.line)
+    }
+}
+
+// Largest value an A-instruction can load
+const MAX_CONSTANT: i64 = 0x7FFF;
+// Macro expansions in one program before giving up, more than fit in ROM
+// for any macro that emits an instruction
+const MAX_EXPANSIONS: usize = 32 * 1024;
+
+// A `.macro NAME params` ... `.endm` block
+#[derive(Debug, Clone, PartialEq)]
+pub struct Macro {
+    pub params: Vec<String>,
+    // Each line with where it was written
+    pub body: Vec<(String, Location)>,
+    // Labels defined in the body, renamed on every expansion
+    pub locals: Vec<String>,
+}
+
+// State that lasts for one `parse_source`, across includes and expansions
+struct Preprocessor {
+    // Files being included, innermost last
+    includes: Vec<PathBuf>,
+    // Macros being expanded and the lines they were used on, innermost last
+    expanding: Vec<(String, Location)>,
+    expansions: usize,
+    // Set by a recursive macro or too many expansions, which stop all expanding
+    stopped: bool,
+    // With the index of the command they come before
+    errors: Vec<(usize, Error)>,
 }
 
 pub struct Assembler {
     pub symbol_table: SymbolTable,
     pub commands: Vec<AssemblyCommand>,
+    pub locations: Vec<Location>,
+    // The line each command came from, after macro expansion
+    pub texts: Vec<String>,
+    // Where each command came from in a macro body, if it did
+    pub expansions: Vec<Option<Expansion>>,
+    pub file_name: String,
+    pub defines: HashMap<String, u16>,
+    pub macros: HashMap<String, Macro>,
     pub next_variable_address: u16,
     pub binaries: Vec<String>,
+    // One entry per instruction in `binaries`, not counting the HALT sentinel
+    pub source_map: Vec<SourceLine>,
 }
 
 impl Assembler {
@@ -24,57 +117,295 @@ impl Assembler {
         Assembler { 
             symbol_table: SymbolTable::new(),
             commands: vec![],
+            locations: vec![],
+            texts: vec![],
+            expansions: vec![],
+            file_name: String::new(),
+            defines: HashMap::new(),
+            macros: HashMap::new(),
             next_variable_address: 16,
             binaries: vec![],
+            source_map: vec![],
+        }
+    }
+
+    // Includes are read relative to the directory of `file_name`. Errors in
+    // macro bodies are located at the body line and name the use of the macro
+    pub fn parse_source(&mut self, contents: &str) -> Result<(), Vec<Error>> {
+        let errors: Vec<Error> = self.preprocess(contents).into_iter().map(|(_, error)| error).collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn preprocess(&mut self, contents: &str) -> Vec<(usize, Error)> {
+        self.commands.clear();
+        self.locations.clear();
+        self.texts.clear();
+        self.expansions.clear();
+        self.defines.clear();
+        self.macros.clear();
+
+        let mut preprocessor = Preprocessor {
+            includes: vec![],
+            expanding: vec![],
+            expansions: 0,
+            stopped: false,
+            errors: vec![],
+        };
+        if !self.file_name.is_empty() {
+            let path = Path::new(&self.file_name);
+            preprocessor.includes.push(fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()));
         }
+        let file_name = self.file_name.clone();
+        self.parse_file(&file_name, contents, &mut preprocessor);
+        preprocessor.errors
     }
 
-    pub fn parse_source(&mut self, contents: &str) {
-        self.commands = contents
-            .lines()
-            .map(str::trim)
-            .filter(|line| !line.is_empty() && !line.starts_with("//"))
-            .map(|line| {
-                if line.starts_with('@') {
-                    AssemblyCommand::AInstruction(line.strip_prefix('@').unwrap().to_string())
-                } else if line.starts_with('(') && line.ends_with(')') {
-                    AssemblyCommand::Label(
-                        line.strip_prefix('(')
-                            .and_then(|s| s.strip_suffix(')'))
-                            .unwrap()
-                            .to_string()
-                    )
-                } else if line.contains('=') || line.contains(';') {
-                    AssemblyCommand::CInstruction(line.to_string())
-                } else {
-                    panic!("Invalid assembly instruction: {}", line);
+    fn parse_file(&mut self, file: &str, contents: &str, preprocessor: &mut Preprocessor) {
+        // The macro whose body is being read
+        let mut defining: Option<(String, Macro, Location)> = None;
+
+        for (i, raw) in contents.lines().enumerate() {
+            let line = raw.trim();
+            if line.is_empty() || line.starts_with("//") {
+                continue;
+            }
+            let location = Location::new(file, i + 1, column_of(raw, line));
+            let (word, rest) = split_word(line);
+
+            if let Some((name, mut definition, start)) = defining.take() {
+                match word {
+                    ".endm" if rest.is_empty() => {
+                        self.macros.insert(name, definition);
+                    }
+                    ".macro" => {
+                        self.report(preprocessor, ErrorKind::InvalidDirective(line.to_string()), location);
+                        defining = Some((name, definition, start));
+                    }
+                    _ => {
+                        if let Some(label) = line.strip_prefix('(').and_then(|label| label.strip_suffix(')')) {
+                            definition.locals.push(label.to_string());
+                        }
+                        definition.body.push((line.to_string(), location));
+                        defining = Some((name, definition, start));
+                    }
                 }
-            })
-            .collect()
+                continue;
+            }
+
+            if word == ".macro" {
+                let mut fields = rest.split(|c: char| c == ',' || c.is_whitespace()).filter(|field| !field.is_empty());
+                let name = fields.next().unwrap_or_default();
+                let params: Vec<String> = fields.map(String::from).collect();
+                if !is_identifier(name) || !params.iter().all(|param| is_identifier(param)) {
+                    self.report(preprocessor, ErrorKind::InvalidDirective(line.to_string()), location.clone());
+                } else if self.macros.contains_key(name) {
+                    self.report(preprocessor, ErrorKind::DuplicateDefinition(name.to_string()), location.clone());
+                }
+                let definition = Macro { params, body: vec![], locals: vec![] };
+                defining = Some((name.to_string(), definition, location));
+                continue;
+            }
+
+            self.parse_line(line, location, preprocessor);
+        }
+
+        if let Some((name, _, start)) = defining {
+            self.report(preprocessor, ErrorKind::UnterminatedMacro(name), start);
+        }
     }
 
-    pub fn assemble_a_instruction(&self, value: &str) -> String {
-        let number: u16 = value.parse().unwrap_or_else(|_| {
-            self.symbol_table.get_address(value)
-                .unwrap_or_else(|| panic!("Symbol not found: {}", value))
+    fn parse_line(&mut self, line: &str, location: Location, preprocessor: &mut Preprocessor) {
+        let (word, rest) = split_word(line);
+        match word {
+            ".define" => return self.define(line, rest, location, preprocessor),
+            ".include" => return self.include(rest, location, preprocessor),
+            ".macro" | ".endm" => {
+                self.report(preprocessor, ErrorKind::InvalidDirective(line.to_string()), location);
+                return;
+            }
+            _ if self.macros.contains_key(word) => return self.expand(word, rest, location, preprocessor),
+            _ => {}
+        }
+
+        let command = if let Some(operand) = line.strip_prefix(".word") {
+            let word = parse_word(operand.trim()).or_else(|| self.defines.get(operand.trim()).copied());
+            match word {
+                Some(word) if operand.starts_with(char::is_whitespace) => AssemblyCommand::Word(word),
+                _ => {
+                    self.report(preprocessor, ErrorKind::InvalidInstruction(line.to_string()), location);
+                    return;
+                }
+            }
+        } else if let Some(value) = line.strip_prefix('@') {
+            match self.defines.get(value) {
+                Some(number) => AssemblyCommand::AInstruction(number.to_string()),
+                None => AssemblyCommand::AInstruction(value.to_string()),
+            }
+        } else if line.starts_with('(') && line.ends_with(')') {
+            AssemblyCommand::Label(
+                line.strip_prefix('(')
+                    .and_then(|s| s.strip_suffix(')'))
+                    .unwrap()
+                    .to_string()
+            )
+        } else if line.contains('=') || line.contains(';') {
+            AssemblyCommand::CInstruction(line.to_string())
+        } else {
+            self.report(preprocessor, ErrorKind::InvalidInstruction(line.to_string()), location);
+            return;
+        };
+
+        // Commands from a macro body are placed at the line outside any macro
+        // that used it, and remember where in the body they came from
+        let expansion = preprocessor.expanding.last().map(|(name, used)| Expansion {
+            location: location.clone(),
+            name: name.clone(),
+            line: used.line,
         });
-        format!("0{:015b}", number)
+        self.commands.push(command);
+        self.locations.push(preprocessor.expanding.first().map_or(location, |(_, used)| used.clone()));
+        self.texts.push(line.to_string());
+        self.expansions.push(expansion);
+    }
+
+    // `.define NAME value`, where the value is a number or an earlier define
+    fn define(&mut self, line: &str, rest: &str, location: Location, preprocessor: &mut Preprocessor) {
+        let fields: Vec<&str> = rest.split_whitespace().collect();
+        let [name, value] = fields.as_slice() else {
+            self.report(preprocessor, ErrorKind::InvalidDirective(line.to_string()), location);
+            return;
+        };
+
+        match parse_word(value).or_else(|| self.defines.get(*value).copied()) {
+            _ if self.defines.contains_key(*name) => {
+                self.report(preprocessor, ErrorKind::DuplicateDefinition(name.to_string()), location);
+            }
+            Some(value) if is_identifier(name) => {
+                self.defines.insert(name.to_string(), value);
+            }
+            _ => self.report(preprocessor, ErrorKind::InvalidDirective(line.to_string()), location),
+        }
+    }
+
+    // `.include "file.asm"`, relative to the file the directive is in
+    fn include(&mut self, rest: &str, location: Location, preprocessor: &mut Preprocessor) {
+        let Some(name) = rest.strip_prefix('"').and_then(|name| name.strip_suffix('"')) else {
+            let line = format!(".include {}", rest);
+            self.report(preprocessor, ErrorKind::InvalidDirective(line), location);
+            return;
+        };
+        let path = Path::new(&location.file).parent().unwrap_or(Path::new("")).join(name);
+        let canonical = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+
+        if preprocessor.includes.contains(&canonical) {
+            let kind = ErrorKind::IncludeFailed(name.to_string(), "it includes itself".to_string());
+            self.report(preprocessor, kind, location);
+            return;
+        }
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) => {
+                self.report(preprocessor, ErrorKind::IncludeFailed(name.to_string(), err.to_string()), location);
+                return;
+            }
+        };
+
+        preprocessor.includes.push(canonical);
+        self.parse_file(&path.display().to_string(), &contents, preprocessor);
+        preprocessor.includes.pop();
+    }
+
+    // Arguments are separated by commas or spaces. Labels in the body get the
+    // macro name and a count in front so every expansion has its own
+    fn expand(&mut self, name: &str, rest: &str, location: Location, preprocessor: &mut Preprocessor) {
+        if preprocessor.stopped {
+            return;
+        }
+        let definition = self.macros[name].clone();
+        let args: Vec<&str> = rest.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .collect();
+        if args.len() != definition.params.len() {
+            let kind = ErrorKind::MacroArguments(name.to_string(), definition.params.len(), args.len());
+            self.report(preprocessor, kind, location);
+            return;
+        }
+        if preprocessor.expanding.iter().any(|(expanding, _)| expanding == name) {
+            self.report(preprocessor, ErrorKind::RecursiveMacro(name.to_string()), location);
+            preprocessor.stopped = true;
+            return;
+        }
+        if preprocessor.expansions == MAX_EXPANSIONS {
+            self.report(preprocessor, ErrorKind::RunawayMacro(name.to_string(), MAX_EXPANSIONS), location);
+            preprocessor.stopped = true;
+            return;
+        }
+
+        preprocessor.expansions += 1;
+        let mut names: HashMap<&str, String> = HashMap::new();
+        for local in &definition.locals {
+            names.insert(local, format!("{}${}.{}", name, preprocessor.expansions, local));
+        }
+        for (param, arg) in definition.params.iter().zip(args) {
+            names.insert(param, arg.to_string());
+        }
+
+        preprocessor.expanding.push((name.to_string(), location));
+        for (line, written) in &definition.body {
+            self.parse_line(&substitute(line, &names), written.clone(), preprocessor);
+        }
+        preprocessor.expanding.pop();
+    }
+
+    // Keeps the number of commands parsed so far, to put errors in program order
+    fn report(&self, preprocessor: &mut Preprocessor, kind: ErrorKind, location: Location) {
+        let mut error = Error::new(kind, location);
+        if let Some((name, used)) = preprocessor.expanding.last() {
+            error = error.in_expansion(name, used.line);
+        }
+        preprocessor.errors.push((self.commands.len(), error));
+    }
+
+    // A number, a symbol or a constant expression of them. Call after `resolve_symbols`
+    pub fn assemble_a_instruction(&self, value: &str) -> Result<String, ErrorKind> {
+        let number = match value.parse::<i64>() {
+            Ok(number) => number,
+            Err(_) => evaluate(value, |symbol| {
+                self.defines.get(symbol).copied()
+                    .or_else(|| self.symbol_table.get_address(symbol))
+                    .map(i64::from)
+            })?,
+        };
+        if !(0..=MAX_CONSTANT).contains(&number) {
+            return Err(ErrorKind::ConstantOutOfRange(value.to_string(), number));
+        }
+        Ok(format!("0{:015b}", number))
 
     }
 
-    pub fn assemble_c_instruction(&self, value: &str) -> String {
+    pub fn assemble_c_instruction(&self, value: &str) -> Result<String, ErrorKind> {
         let mut dest = "";
         let comp;
         let mut jump = "";
 
-        // Split on ';' first (jump part)
+        // Split on ';' first (jump part). A second ';' is part of the jump
         let parts: Vec<&str> = value.split(';').collect();
+        if parts.len() > 2 {
+            return Err(ErrorKind::InvalidJump(parts[1..].join(";").trim().to_string()));
+        }
         if parts.len() == 2 {
             jump = parts[1].trim();
         }
 
-        // Split on '=' next (dest part)
+        // Split on '=' next (dest part). A second '=' is part of the comp
         let eq_parts: Vec<&str> = parts[0].split('=').collect();
+        if eq_parts.len() > 2 {
+            return Err(ErrorKind::InvalidComp(eq_parts[1..].join("=").trim().to_string()));
+        }
         if eq_parts.len() == 2 {
             dest = eq_parts[0].trim();
             comp = eq_parts[1].trim();
@@ -87,15 +418,15 @@ impl Assembler {
         let jump_map = jump_table();
 
         let (a_bit, c_bits) = comp_map.get(comp)
-            .unwrap_or_else(|| panic!("Invalid comp field: {}", comp));
+            .ok_or_else(|| ErrorKind::InvalidComp(comp.to_string()))?;
 
         let d_bits = dest_map.get(dest)
-            .unwrap_or_else(|| panic!("Invalid dest field: {}", dest));
+            .ok_or_else(|| ErrorKind::InvalidDest(dest.to_string()))?;
 
         let j_bits = jump_map.get(jump)
-            .unwrap_or_else(|| panic!("Invalid jump field: {}", jump));
+            .ok_or_else(|| ErrorKind::InvalidJump(jump.to_string()))?;
 
-        format!("111{}{}{}{}", a_bit, c_bits, d_bits, j_bits)
+        Ok(format!("111{}{}{}{}", a_bit, c_bits, d_bits, j_bits))
 
     }
 
@@ -114,10 +445,11 @@ impl Assembler {
             }
         }
 
-        // Second pass: handle variables
+        // Second pass: handle variables. Only bare symbols are, the ones in
+        // expressions have to be defined somewhere else
         for command in &self.commands {
             if let AssemblyCommand::AInstruction(value) = command {
-                if value.parse::<u16>().is_err() && !self.symbol_table.contains(value) {
+                if is_identifier(value) && !self.symbol_table.contains(value) {
                     self.symbol_table.add_entry(value, self.next_variable_address);
                     self.next_variable_address += 1;
                 }
@@ -125,27 +457,153 @@ impl Assembler {
         }
     }
 
-    pub fn assemble_all(&mut self, contents: &str) {
-        self.parse_source(contents);
+    // ROM address of every label, in program order
+    pub fn labels(&self) -> Vec<(String, u16)> {
+        let mut labels = vec![];
+        let mut instruction_address = 0;
+        for command in &self.commands {
+            match command {
+                AssemblyCommand::Label(label) => labels.push((label.clone(), instruction_address)),
+                _ => instruction_address += 1,
+            }
+        }
+        labels
+    }
+
+    // Labels, then variables in the order they were first used. Call after `resolve_symbols`
+    pub fn symbols(&self) -> Symbols {
+        let mut symbols = Symbols::new();
+        for (label, address) in self.labels() {
+            symbols.add(&label, SymbolKind::Label, address);
+        }
+
+        let predefined = SymbolTable::new();
+        for command in &self.commands {
+            if let AssemblyCommand::AInstruction(value) = command {
+                if !is_identifier(value) || predefined.contains(value) || symbols.address(value).is_some() {
+                    continue;
+                }
+                if let Some(address) = self.symbol_table.get_address(value) {
+                    symbols.add(value, SymbolKind::Variable, address);
+                }
+            }
+        }
+        symbols
+    }
+
+    // Reports every bad line at once instead of stopping at the first one
+    pub fn assemble_all(&mut self, contents: &str) -> Result<(), Vec<Error>> {
+        let mut errors = self.preprocess(contents);
         self.resolve_symbols();
 
-        self.binaries = self.commands.iter().filter_map(|command| {
-            match command {
-                AssemblyCommand::AInstruction(value) => {
-                    Some(self.assemble_a_instruction(value))
+        let mut label = None;
+        self.binaries = vec![];
+        self.source_map = vec![];
+        for (i, (command, location)) in self.commands.iter().zip(&self.locations).enumerate() {
+            let binary = match command {
+                AssemblyCommand::AInstruction(value) => self.assemble_a_instruction(value),
+                AssemblyCommand::CInstruction(value) => self.assemble_c_instruction(value),
+                AssemblyCommand::Word(word) => Ok(format!("{:016b}", word)),
+                AssemblyCommand::Label(name) => {
+                    label = Some(name.clone());
+                    continue;
                 }
-                AssemblyCommand::CInstruction(value) => {
-                    Some(self.assemble_c_instruction(value))
+            };
+
+            match binary {
+                Ok(binary) => {
+                    self.binaries.push(binary);
+                    self.source_map.push(SourceLine {
+                        location: location.clone(),
+                        text: self.texts[i].clone(),
+                        label: label.clone(),
+                    });
                 }
-                AssemblyCommand::Label(_) => None,
+                Err(kind) => errors.push((i, match &self.expansions[i] {
+                    Some(expansion) => expansion.error(kind),
+                    None => Error::new(kind, location.clone()),
+                })),
             }
-        }).collect();
+        }
+
+        if !errors.is_empty() {
+            // Stable, so errors from parsing stay ahead of the command they came before
+            errors.sort_by_key(|(i, _)| *i);
+            return Err(errors.into_iter().map(|(_, error)| error).collect());
+        }
 
         self.binaries.push(format!("{:016b}", 0xFFFF)); 
+        Ok(())
+    }
+
+    // Address, binary, hex and source line for every instruction, with labels
+    // on lines of their own and the label each instruction is under after it.
+    // Call after `assemble_all`
+    pub fn listing(&self) -> Vec<String> {
+        let labels = self.labels();
+        let mut lines = vec![];
+        for (address, (binary, source)) in self.binaries.iter().zip(&self.source_map).enumerate() {
+            for (label, _) in labels.iter().filter(|(_, at)| *at as usize == address) {
+                lines.push(format!("{:38}({})", "", label));
+            }
+            let value = u16::from_str_radix(binary, 2).unwrap();
+            let line = format!("{:>5}  {}  {:04X}  {:>5}  {}", address, binary, value, source.location.line, source.text);
+            lines.push(match &source.label {
+                Some(label) => format!("{:<62}// in {}", line, label),
+                None => line,
+            });
+        }
+        lines
+    }
+
+}
+
+
+// The first word of a line and the rest of it
+fn split_word(line: &str) -> (&str, &str) {
+    match line.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim()),
+        None => (line, ""),
     }
+}
 
+// Characters that can make up a symbol, also in constant expressions
+pub(crate) fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "_.$:".contains(c)
+}
+
+// A symbol the assembler would not read as a number
+fn is_identifier(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with(|c: char| c.is_ascii_digit()) && name.chars().all(is_identifier_char)
+}
+
+// Replaces whole symbols in `line`, leaving the rest as it is
+fn substitute(line: &str, names: &HashMap<&str, String>) -> String {
+    let mut result = String::new();
+    let mut symbol = String::new();
+    for c in line.chars() {
+        if is_identifier_char(c) {
+            symbol.push(c);
+            continue;
+        }
+        result.push_str(names.get(symbol.as_str()).unwrap_or(&symbol));
+        symbol.clear();
+        result.push(c);
+    }
+    result.push_str(names.get(symbol.as_str()).unwrap_or(&symbol));
+    result
 }
 
+// Decimal, 0x hex or 0b binary
+fn parse_word(text: &str) -> Option<u16> {
+    if let Some(hex) = text.strip_prefix("0x") {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = text.strip_prefix("0b") {
+        u16::from_str_radix(binary, 2).ok()
+    } else {
+        text.parse().ok()
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -166,7 +624,7 @@ mod tests {
             
         "#;
 
-        asm.parse_source(source);
+        asm.parse_source(source).unwrap();
 
         let expected = vec![
             AssemblyCommand::AInstruction("15".to_string()),
@@ -180,18 +638,22 @@ mod tests {
     }
 
     #[test]
-    #[should_panic(expected = "Invalid assembly instruction")]
-    fn test_parse_lines_invalid_input_panics() {
+    fn test_parse_lines_invalid_input_errors() {
         let mut asm = Assembler::new();
-        let source = "JUMP"; // does not contain '=' or ';'
-
-        asm.parse_source(source);
+        asm.file_name = "Prog.asm".to_string();
+        let source = "@1\n  JUMP"; // does not contain '=' or ';'
+
+        let errors = asm.parse_source(source).unwrap_err();
+        assert_eq!(errors, vec![Error::new(
+            ErrorKind::InvalidInstruction("JUMP".to_string()),
+            Location::new("Prog.asm", 2, 3),
+        )]);
     }
 
     #[test]
     fn test_assemble_a_instruction() {
         let asm = Assembler::new();
-        let bin = asm.assemble_a_instruction("21");
+        let bin = asm.assemble_a_instruction("21").unwrap();
 
         assert_eq!(bin, format!("{}{}{}{}", "0000", "0000", "0001", "0101"));
     }
@@ -199,57 +661,292 @@ mod tests {
     #[test]
     fn test_dest_equals_comp() {
         let asm = Assembler::new();
-        let result = asm.assemble_c_instruction("D=A");
+        let result = asm.assemble_c_instruction("D=A").unwrap();
         assert_eq!(result, "1110110000010000"); // a=0, comp=A, dest=D, jump=null
     }
 
     #[test]
     fn test_comp_semicolon_jump() {
         let asm = Assembler::new();
-        let result = asm.assemble_c_instruction("0;JMP");
+        let result = asm.assemble_c_instruction("0;JMP").unwrap();
         assert_eq!(result, "1110101010000111"); // a=0, comp=0, dest=null, jump=JMP
     }
 
     #[test]
     fn test_dest_equals_comp_jump() {
         let asm = Assembler::new();
-        let result = asm.assemble_c_instruction("D=A+1;JGE");
+        let result = asm.assemble_c_instruction("D=A+1;JGE").unwrap();
         assert_eq!(result, format!("111{}{}{}{}", "0", "110111", "010", "011"));
     }
 
     #[test]
     fn test_full_m_form() {
         let asm = Assembler::new();
-        let result = asm.assemble_c_instruction("AD=D+M;JNE");
+        let result = asm.assemble_c_instruction("AD=D+M;JNE").unwrap();
         assert_eq!(result, format!("111{}{}{}{}", "1", "000010", "110", "101"));
     }
 
     #[test]
     fn test_comp_with_m() {
         let asm = Assembler::new();
-        let result = asm.assemble_c_instruction("D=M");
+        let result = asm.assemble_c_instruction("D=M").unwrap();
         assert_eq!(result, format!("111{}{}{}{}", "1", "110000", "010", "000"));
     }
 
     #[test]
-    #[should_panic(expected = "Invalid comp field")]
-    fn test_invalid_comp_panics() {
+    fn test_invalid_comp_errors() {
+        let asm = Assembler::new();
+        let result = asm.assemble_c_instruction("D=FOO");
+        assert_eq!(result, Err(ErrorKind::InvalidComp("FOO".to_string())));
+    }
+
+    #[test]
+    fn test_invalid_dest_errors() {
+        let asm = Assembler::new();
+        let result = asm.assemble_c_instruction("X=A");
+        assert_eq!(result, Err(ErrorKind::InvalidDest("X".to_string())));
+    }
+
+    #[test]
+    fn test_invalid_jump_errors() {
         let asm = Assembler::new();
-        asm.assemble_c_instruction("D=FOO");
+        let result = asm.assemble_c_instruction("D=A;FLY");
+        assert_eq!(result, Err(ErrorKind::InvalidJump("FLY".to_string())));
     }
 
     #[test]
-    #[should_panic(expected = "Invalid dest field")]
-    fn test_invalid_dest_panics() {
+    fn test_repeated_separators_error() {
         let asm = Assembler::new();
-        asm.assemble_c_instruction("X=A");
+        assert_eq!(asm.assemble_c_instruction("D;JGT;JMP"), Err(ErrorKind::InvalidJump("JGT;JMP".to_string())));
+        assert_eq!(asm.assemble_c_instruction("A=M=D"), Err(ErrorKind::InvalidComp("M=D".to_string())));
     }
 
     #[test]
-    #[should_panic(expected = "Invalid jump field")]
-    fn test_invalid_jump_panics() {
+    fn test_unknown_symbol_errors() {
         let asm = Assembler::new();
-        asm.assemble_c_instruction("D=A;FLY");
+        let result = asm.assemble_a_instruction("nowhere");
+        assert_eq!(result, Err(ErrorKind::UnknownSymbol("nowhere".to_string())));
+    }
+
+    #[test]
+    fn test_assemble_all_reports_every_error() {
+        let source = "@1\nD=FOO\nBAD\n@2\nX=A\n0;JMP";
+
+        let mut asm = Assembler::new();
+        let errors = asm.assemble_all(source).unwrap_err();
+
+        let lines: Vec<usize> = errors.iter().map(|e| e.location.line).collect();
+        assert_eq!(lines, vec![2, 3, 5]);
+        assert_eq!(errors[0].kind, ErrorKind::InvalidComp("FOO".to_string()));
+        assert_eq!(errors[1].kind, ErrorKind::InvalidInstruction("BAD".to_string()));
+        assert_eq!(errors[2].kind, ErrorKind::InvalidDest("X".to_string()));
+    }
+
+    #[test]
+    fn test_source_map() {
+        let source = "// Count down\n@3\nD=A\n(LOOP)\n  D=D-1\n@LOOP\nD;JGT\n";
+
+        let mut asm = Assembler::new();
+        asm.file_name = "Count.asm".to_string();
+        asm.assemble_all(source).unwrap();
+
+        assert_eq!(asm.source_map.len(), asm.binaries.len() - 1);
+        assert_eq!(asm.source_map[2], SourceLine {
+            location: Location::new("Count.asm", 5, 3),
+            text: "D=D-1".to_string(),
+            label: Some("LOOP".to_string()),
+        });
+        assert_eq!(asm.source_map[0].label, None);
+        assert_eq!(asm.source_map[4].to_string(), "Count.asm:7 in LOOP: D;JGT");
+        assert_eq!(asm.source_map[0].to_string(), "Count.asm:2: @3");
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut asm = Assembler::new();
+        asm.assemble_all("@counter\nM=0\n(LOOP)\n@R13\nM=D\n@total\nM=M+1\n@counter\n@LOOP\n0;JMP").unwrap();
+
+        assert_eq!(asm.symbols().to_string(), "LOOP label 2\ncounter variable 16\ntotal variable 17\n");
+    }
+
+    #[test]
+    fn test_listing() {
+        let mut asm = Assembler::new();
+        asm.assemble_all("@3\n(LOOP)\nD=D-1;JGT").unwrap();
+
+        assert_eq!(asm.listing(), [
+            "    0  0000000000000011  0003      1  @3",
+            "                                      (LOOP)",
+            "    1  1110001110010001  E391      3  D=D-1;JGT               // in LOOP",
+        ]);
+    }
+
+    #[test]
+    fn test_word_directive() {
+        let mut asm = Assembler::new();
+        asm.assemble_all("(START)\n.word 0xFFFF\n.word 7\n@START").unwrap();
+        assert_eq!(&asm.binaries[..3], ["1111111111111111", "0000000000000111", "0000000000000000"]);
+
+        let errors = asm.assemble_all(".word\n.word 70000\n.wordy 1").unwrap_err();
+        let lines: Vec<usize> = errors.iter().map(|error| error.location.line).collect();
+        assert_eq!(lines, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_define() {
+        let mut asm = Assembler::new();
+        asm.assemble_all(".define SIZE 0x10\n.define LAST SIZE\n@SIZE\n@LAST\n.word SIZE").unwrap();
+        assert_eq!(&asm.binaries[..3], ["0000000000010000"; 3]);
+        assert_eq!(asm.texts[0], "@SIZE");
+
+        let errors = asm.assemble_all(".define 1X 3\n.define A\n.define B 1\n.define B 2\n.define C nowhere").unwrap_err();
+        let kinds: Vec<ErrorKind> = errors.into_iter().map(|error| error.kind).collect();
+        assert_eq!(kinds, vec![
+            ErrorKind::InvalidDirective(".define 1X 3".to_string()),
+            ErrorKind::InvalidDirective(".define A".to_string()),
+            ErrorKind::DuplicateDefinition("B".to_string()),
+            ErrorKind::InvalidDirective(".define C nowhere".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_macro_expansion() {
+        let source = "\
+.macro COUNTDOWN var, from
+@from
+D=A
+@var
+M=D
+(LOOP)
+@var
+MD=M-1
+@LOOP
+D;JGT
+.endm
+COUNTDOWN i, 3
+  COUNTDOWN j 5
+";
+        let mut asm = Assembler::new();
+        asm.file_name = "Loops.asm".to_string();
+        asm.assemble_all(source).unwrap();
+
+        assert_eq!(asm.binaries.len(), 17);
+        assert_eq!(asm.labels(), [("COUNTDOWN$1.LOOP".to_string(), 4), ("COUNTDOWN$2.LOOP".to_string(), 12)]);
+        assert_eq!(asm.binaries[10], "0000000000010001"); // @j
+        assert_eq!(asm.binaries[14], "0000000000001100"); // @LOOP of the second expansion
+
+        // Expanded lines point at the line that used the macro
+        assert_eq!(asm.source_map[8], SourceLine {
+            location: Location::new("Loops.asm", 13, 3),
+            text: "@5".to_string(),
+            label: Some("COUNTDOWN$1.LOOP".to_string()),
+        });
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        let source = "\
+.macro SET var
+@var
+M=FOO
+.endm
+SET x
+SET
+.macro AGAIN
+AGAIN
+.endm
+AGAIN
+.endm
+.macro OPEN
+@1";
+        let mut asm = Assembler::new();
+        let errors = asm.assemble_all(source).unwrap_err();
+
+        // Errors in a body are at the body line, with the line that used the macro
+        let found: Vec<(usize, ErrorKind, Option<usize>)> = errors.into_iter()
+            .map(|error| (error.location.line, error.kind, error.expansion.map(|expansion| expansion.1)))
+            .collect();
+        assert_eq!(found, vec![
+            (3, ErrorKind::InvalidComp("FOO".to_string()), Some(5)),
+            (6, ErrorKind::MacroArguments("SET".to_string(), 1, 0), None),
+            (8, ErrorKind::RecursiveMacro("AGAIN".to_string()), Some(10)),
+            (11, ErrorKind::InvalidDirective(".endm".to_string()), None),
+            (12, ErrorKind::UnterminatedMacro("OPEN".to_string()), None),
+        ]);
+    }
+
+    #[test]
+    fn test_macro_blowup() {
+        // Recursion is reported once instead of expanding twice per level
+        let mut asm = Assembler::new();
+        let errors = asm.assemble_all(".macro X\nX\nX\n.endm\nX").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].to_string(), "2:1: Macro X expands itself (in expansion of X at line 5)");
+
+        // Every macro doubles the one before without recursing
+        let mut source = ".macro M0\n@0\n.endm\n".to_string();
+        for i in 1..=20 {
+            source += &format!(".macro M{}\nM{}\nM{}\n.endm\n", i, i - 1, i - 1);
+        }
+        source += "M20";
+        let errors = asm.assemble_all(&source).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].kind, ErrorKind::RunawayMacro("M0".to_string(), MAX_EXPANSIONS));
+    }
+
+    #[test]
+    fn test_include() {
+        let dir = std::env::temp_dir().join(format!("rust2tetris_include_{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("lib/Inc.asm"), ".define STEP 2\n.macro INC var\n@STEP\nD=A\n@var\nM=D+M\n.endm\n").unwrap();
+        fs::write(dir.join("lib/Bad.asm"), "@1\nD=BAD\n").unwrap();
+        fs::write(dir.join("Loop.asm"), ".include \"Loop.asm\"\n").unwrap();
+
+        let mut asm = Assembler::new();
+        asm.file_name = dir.join("Main.asm").display().to_string();
+        asm.assemble_all(".include \"lib/Inc.asm\"\nINC total\n").unwrap();
+        assert_eq!(asm.binaries[0], "0000000000000010");
+        assert_eq!(asm.source_map[0].location.line, 2);
+
+        let errors = asm.assemble_all(".include \"lib/Bad.asm\"\n.include \"Missing.asm\"\n.include \"Loop.asm\"\n.include lib/Inc.asm").unwrap_err();
+        assert_eq!(errors[0].location, Location::new(&dir.join("lib/Bad.asm").display().to_string(), 2, 1));
+        assert!(matches!(&errors[1].kind, ErrorKind::IncludeFailed(file, _) if file == "Missing.asm"));
+        assert_eq!(errors[2].kind, ErrorKind::IncludeFailed("Loop.asm".to_string(), "it includes itself".to_string()));
+        assert_eq!(errors[2].location.file, dir.join("Loop.asm").display().to_string());
+        assert_eq!(errors[3].kind, ErrorKind::InvalidDirective(".include lib/Inc.asm".to_string()));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_constant_expressions() {
+        let source = "\
+.define row 2
+@SCREEN+32*row
+@END+2
+@0x4000
+@0b1010
+@'A'
+@counter
+@counter+1
+(END)
+@32767";
+        let mut asm = Assembler::new();
+        asm.assemble_all(source).unwrap();
+
+        let values: Vec<u16> = asm.binaries.iter().map(|binary| u16::from_str_radix(binary, 2).unwrap()).collect();
+        assert_eq!(values, [16448, 9, 16384, 10, 65, 16, 17, 32767, 0xFFFF]);
+        // Expressions don't make new variables
+        assert_eq!(asm.symbols().to_string(), "END label 7\ncounter variable 16\n");
+
+        let errors = asm.assemble_all("@32768\n@SCREEN-SCREEN-1\n@KBD+*2\n@nowhere+1").unwrap_err();
+        let kinds: Vec<ErrorKind> = errors.into_iter().map(|error| error.kind).collect();
+        assert_eq!(kinds, vec![
+            ErrorKind::ConstantOutOfRange("32768".to_string(), 32768),
+            ErrorKind::ConstantOutOfRange("SCREEN-SCREEN-1".to_string(), -1),
+            ErrorKind::InvalidExpression("KBD+*2".to_string()),
+            ErrorKind::UnknownSymbol("nowhere".to_string()),
+        ]);
     }
 
     #[test]
@@ -290,7 +987,7 @@ mod tests {
         "#;
 
         let mut asm = Assembler::new();
-        asm.assemble_all(source);
+        asm.assemble_all(source).unwrap();
 
         assert_eq!(asm.binaries[0], "0000000000010000"); // @i = 16
         assert_eq!(asm.binaries[1], "1110111111001000"); // M=1
@@ -0,0 +1,210 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::parser::assembly::Assembler;
+use crate::parser::symbols::{SymbolKind, Symbols};
+use crate::parser::table::{decode_instruction, SymbolTable};
+
+// First address the assembler gives to variables
+const FIRST_VARIABLE: u16 = 16;
+
+// Turns a whole ROM back into source that assembles to the same words.
+// Jump targets get the labels from `symbols`, or `L<address>` when they have
+// none, memory operands get variable names when reassembling would give them
+// the same address, and words with no mnemonic become `.word` directives
+pub fn disassemble(rom: &[u16], symbols: &Symbols) -> Vec<String> {
+    let asm = Assembler::new();
+    let text: Vec<Option<String>> = rom.iter()
+        .map(|word| {
+            let decoded = decode_instruction(*word);
+            let is_instruction = word & 0x8000 == 0
+                || ((decoded.contains('=') || decoded.contains(';'))
+                    && asm.assemble_c_instruction(&decoded) == Ok(format!("{:016b}", word)));
+            is_instruction.then_some(decoded)
+        })
+        .collect();
+    // The next word, when it is a C-instruction that will come out as one
+    let next_c = |address: usize| match (rom.get(address + 1), text.get(address + 1)) {
+        (Some(next), Some(Some(_))) if next & 0x8000 != 0 => Some(*next),
+        _ => None,
+    };
+
+    let predefined = SymbolTable::new();
+    // Anything the assembler would read as a number or a predefined symbol can't be used
+    let usable = |name: &str| !predefined.contains(name) && !name.starts_with(|c: char| c.is_ascii_digit());
+    let end = rom.len() as u16;
+
+    let mut labels: BTreeMap<u16, Vec<String>> = BTreeMap::new();
+    for symbol in symbols.entries.iter().filter(|symbol| symbol.kind == SymbolKind::Label) {
+        if symbol.address <= end && usable(&symbol.name) {
+            labels.entry(symbol.address).or_default().push(symbol.name.clone());
+        }
+    }
+    for (address, word) in rom.iter().enumerate() {
+        let jumps = next_c(address).is_some_and(|next| next & 0b111 != 0);
+        if word & 0x8000 == 0 && jumps && *word <= end && !labels.contains_key(word) {
+            let mut name = format!("L{}", word);
+            while symbols.address(&name).is_some() || !usable(&name) {
+                name.push('_');
+            }
+            labels.insert(*word, vec![name]);
+        }
+    }
+    let label_names: Vec<&String> = labels.values().flatten().collect();
+
+    // Names only stand in for addresses the assembler would give them again,
+    // which it does in order of first use
+    let mut variables: HashMap<&str, u16> = HashMap::new();
+    let mut next_variable = FIRST_VARIABLE;
+
+    let mut lines = vec![];
+    for (address, word) in rom.iter().enumerate() {
+        for label in labels.get(&(address as u16)).into_iter().flatten() {
+            lines.push(format!("({})", label));
+        }
+
+        let Some(decoded) = &text[address] else {
+            lines.push(format!(".word 0x{:04X}", word));
+            continue;
+        };
+        let name = match next_c(address) {
+            _ if word & 0x8000 != 0 => None,
+            Some(next) if next & 0b111 != 0 => labels.get(word).map(|names| names[0].as_str()),
+            Some(next) if next & 0x1008 != 0 => {
+                let variable = symbols.names_at(*word, SymbolKind::Variable).into_iter()
+                    .filter(|name| usable(name) && !label_names.iter().any(|label| label == name))
+                    .find(|name| match variables.get(name) {
+                        Some(assigned) => assigned == word,
+                        None => *word == next_variable,
+                    });
+                if let Some(name) = variable {
+                    if !variables.contains_key(name) {
+                        variables.insert(name, next_variable);
+                        next_variable += 1;
+                    }
+                }
+                variable
+            }
+            _ => None,
+        };
+
+        match name {
+            Some(name) => lines.push(format!("@{}", name)),
+            None => lines.push(decoded.clone()),
+        }
+    }
+    for label in labels.get(&end).into_iter().flatten() {
+        lines.push(format!("({})", label));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack::stack::{Stack, VmFile};
+
+    fn words(asm: &Assembler) -> Vec<u16> {
+        asm.binaries.iter().map(|binary| u16::from_str_radix(binary, 2).unwrap()).collect()
+    }
+
+    // Disassembles and assembles again, returning the source in between
+    fn round_trip(rom: &[u16], symbols: &Symbols) -> Vec<String> {
+        let source = disassemble(rom, symbols);
+        let mut asm = Assembler::new();
+        asm.assemble_all(&source.join("\n")).unwrap();
+        asm.binaries.pop();
+        assert_eq!(words(&asm), rom, "{}", source.join("\n"));
+        source
+    }
+
+    #[test]
+    fn test_infers_jump_labels() {
+        let mut asm = Assembler::new();
+        asm.assemble_all("@3\nD=A\n(LOOP)\nD=D-1\n@LOOP\nD;JGT\n@7\n0;JMP").unwrap();
+        asm.binaries.pop();
+
+        assert_eq!(round_trip(&words(&asm), &Symbols::new()), [
+            "@3", "D=A", "(L2)", "D=D-1", "@L2", "D;JGT", "@L7", "0;JMP", "(L7)",
+        ]);
+    }
+
+    #[test]
+    fn test_names_from_symbol_file() {
+        let mut asm = Assembler::new();
+        asm.assemble_all("\
+            @total
+            M=0
+            (LOOP)
+            @count
+            D=M
+            @total
+            M=D+M
+            @count
+            MD=M-1
+            @LOOP
+            D;JGT
+        ").unwrap();
+        asm.binaries.pop();
+
+        let source = round_trip(&words(&asm), &asm.symbols());
+        assert_eq!(&source[..5], ["@total", "M=0", "(LOOP)", "@count", "D=M"]);
+        assert_eq!(source[9], "@LOOP");
+    }
+
+    #[test]
+    fn test_keeps_numbers_that_would_move() {
+        // Names in a symbol file that don't match the order variables get allocated in
+        let symbols = Symbols::parse("second variable 17\nfirst variable 16\nR3 variable 18\n").unwrap();
+        let rom = [17, 0xFC10, 16, 0xFC10, 16, 0xE308, 18, 0xE308, 17, 0xE308];
+
+        let source = round_trip(&rom, &symbols);
+        assert_eq!(source, ["@17", "D=M", "@first", "D=M", "@first", "M=D", "@18", "M=D", "@second", "M=D"]);
+    }
+
+    #[test]
+    fn test_data_words() {
+        // HALT, a C-instruction without 11 in bits 13-14, one without dest or jump
+        // and one with an unknown comp
+        let rom = [0xFFFF, 0x8C10, 0xEC00, 0xFA90, 0xEC10];
+
+        let source = round_trip(&rom, &Symbols::new());
+        assert_eq!(source, [".word 0xFFFF", ".word 0x8C10", ".word 0xEC00", ".word 0xFA90", "D=A"]);
+    }
+
+    #[test]
+    fn test_round_trips_translated_vm_code() {
+        let source = "\
+            function Main.fib 0
+            push argument 0
+            push constant 2
+            lt
+            if-goto BASE
+            push argument 0
+            push constant 1
+            sub
+            call Main.fib 1
+            push argument 0
+            push constant 2
+            sub
+            call Main.fib 1
+            add
+            return
+            label BASE
+            push argument 0
+            return
+            function Sys.init 0
+            push constant 6
+            call Main.fib 1
+            label END
+            goto END
+        ";
+        let mut stack = Stack::new();
+        stack.assemble_program(&[VmFile::from_source("Main", source)]).unwrap();
+        let mut asm = Assembler::new();
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
+        asm.binaries.pop();
+
+        round_trip(&words(&asm), &asm.symbols());
+        round_trip(&words(&asm), &Symbols::new());
+    }
+}
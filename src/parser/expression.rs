@@ -0,0 +1,185 @@
+use crate::error::error::ErrorKind;
+use crate::parser::assembly::is_identifier_char;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Symbol(String),
+    Operator(char),
+}
+
+// Evaluates constant expressions like `SCREEN+32*row`, with + - * /,
+// parentheses, unary minus, decimal, 0x hex and 0b binary numbers and 'c'
+// character literals. `lookup` gives the value of a symbol
+pub fn evaluate<F: Fn(&str) -> Option<i64>>(text: &str, lookup: F) -> Result<i64, ErrorKind> {
+    let invalid = || ErrorKind::InvalidExpression(text.to_string());
+    let tokens = tokenize(text).ok_or_else(invalid)?;
+
+    let mut parser = Parser { tokens: &tokens, position: 0, lookup: &lookup };
+    let value = parser.sum()?.ok_or_else(invalid)?;
+    if parser.position != tokens.len() {
+        return Err(invalid());
+    }
+    Ok(value)
+}
+
+fn tokenize(text: &str) -> Option<Vec<Token>> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if "+-*/()".contains(c) {
+            tokens.push(Token::Operator(c));
+            i += 1;
+        } else if c == '\'' {
+            if chars.get(i + 2) != Some(&'\'') {
+                return None;
+            }
+            tokens.push(Token::Number(chars[i + 1] as i64));
+            i += 3;
+        } else if is_identifier_char(c) {
+            let start = i;
+            while i < chars.len() && is_identifier_char(chars[i]) {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            if c.is_ascii_digit() {
+                tokens.push(Token::Number(parse_number(&word)?));
+            } else {
+                tokens.push(Token::Symbol(word));
+            }
+        } else {
+            return None;
+        }
+    }
+    Some(tokens)
+}
+
+fn parse_number(word: &str) -> Option<i64> {
+    if let Some(hex) = word.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = word.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()
+    } else {
+        word.parse().ok()
+    }
+}
+
+// Recursive descent where every rule gives None on a syntax error or an
+// overflow, so the caller can report the whole expression
+struct Parser<'a, F: Fn(&str) -> Option<i64>> {
+    tokens: &'a [Token],
+    position: usize,
+    lookup: &'a F,
+}
+
+impl<F: Fn(&str) -> Option<i64>> Parser<'_, F> {
+    fn next_operator(&mut self, operators: &str) -> Option<char> {
+        match self.tokens.get(self.position) {
+            Some(Token::Operator(c)) if operators.contains(*c) => {
+                self.position += 1;
+                Some(*c)
+            }
+            _ => None,
+        }
+    }
+
+    fn sum(&mut self) -> Result<Option<i64>, ErrorKind> {
+        let Some(mut value) = self.product()? else { return Ok(None) };
+        while let Some(operator) = self.next_operator("+-") {
+            let Some(rhs) = self.product()? else { return Ok(None) };
+            let result = match operator {
+                '+' => value.checked_add(rhs),
+                _ => value.checked_sub(rhs),
+            };
+            match result {
+                Some(result) => value = result,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(value))
+    }
+
+    fn product(&mut self) -> Result<Option<i64>, ErrorKind> {
+        let Some(mut value) = self.unary()? else { return Ok(None) };
+        while let Some(operator) = self.next_operator("*/") {
+            let Some(rhs) = self.unary()? else { return Ok(None) };
+            let result = match operator {
+                '*' => value.checked_mul(rhs),
+                _ => value.checked_div(rhs),
+            };
+            match result {
+                Some(result) => value = result,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(value))
+    }
+
+    fn unary(&mut self) -> Result<Option<i64>, ErrorKind> {
+        if self.next_operator("-").is_some() {
+            return Ok(self.unary()?.map(|value| -value));
+        }
+
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        match token {
+            Some(Token::Number(value)) => Ok(Some(value)),
+            Some(Token::Symbol(name)) => match (self.lookup)(&name) {
+                Some(value) => Ok(Some(value)),
+                None => Err(ErrorKind::UnknownSymbol(name)),
+            },
+            Some(Token::Operator('(')) => {
+                let value = self.sum()?;
+                match self.next_operator(")") {
+                    Some(_) => Ok(value),
+                    None => Ok(None),
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<i64> {
+        match name {
+            "SCREEN" => Some(16384),
+            "row" => Some(3),
+            "Main.loop$1" => Some(40),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_evaluate() {
+        assert_eq!(evaluate("SCREEN+32*row", lookup), Ok(16480));
+        assert_eq!(evaluate("(SCREEN + 32) * 0", lookup), Ok(0));
+        assert_eq!(evaluate("Main.loop$1+2", lookup), Ok(42));
+        assert_eq!(evaluate("0x4000-0b1010", lookup), Ok(16374));
+        assert_eq!(evaluate("'A'+1", lookup), Ok(66));
+        assert_eq!(evaluate("' '", lookup), Ok(32));
+        assert_eq!(evaluate("10-4-3", lookup), Ok(3));
+        assert_eq!(evaluate("-7/2", lookup), Ok(-3));
+    }
+
+    #[test]
+    fn test_evaluate_errors() {
+        let invalid = |text: &str| Err(ErrorKind::InvalidExpression(text.to_string()));
+        assert_eq!(evaluate("1+", lookup), invalid("1+"));
+        assert_eq!(evaluate("(1", lookup), invalid("(1"));
+        assert_eq!(evaluate("1 2", lookup), invalid("1 2"));
+        assert_eq!(evaluate("0xZZ", lookup), invalid("0xZZ"));
+        assert_eq!(evaluate("'A", lookup), invalid("'A"));
+        assert_eq!(evaluate("4/0", lookup), invalid("4/0"));
+        assert_eq!(evaluate("", lookup), invalid(""));
+        assert_eq!(evaluate("row*nowhere", lookup), Err(ErrorKind::UnknownSymbol("nowhere".to_string())));
+    }
+}
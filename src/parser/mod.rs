@@ -1,2 +1,5 @@
 pub mod assembly;
+pub mod disassembler;
+pub mod expression;
+pub mod symbols;
 pub mod table;
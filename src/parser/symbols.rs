@@ -0,0 +1,164 @@
+use std::fmt;
+
+use crate::error::error::{Error, ErrorKind, Location};
+use crate::parser::table::decode_instruction;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolKind {
+    // A ROM address
+    Label,
+    // A RAM address given out by the assembler
+    Variable,
+}
+
+impl fmt::Display for SymbolKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SymbolKind::Label => write!(f, "label"),
+            SymbolKind::Variable => write!(f, "variable"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub address: u16,
+}
+
+// The labels and variables of an assembled program, without the predefined
+// symbols. Saved as .sym files with one `name kind address` entry per line
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbols {
+    pub entries: Vec<Symbol>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Symbols { entries: vec![] }
+    }
+
+    pub fn add(&mut self, name: &str, kind: SymbolKind, address: u16) {
+        self.entries.push(Symbol { name: name.to_string(), kind, address });
+    }
+
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.entries.iter().find(|symbol| symbol.name == name).map(|symbol| symbol.address)
+    }
+
+    // Every symbol of this kind at the address, in program order
+    pub fn names_at(&self, address: u16, kind: SymbolKind) -> Vec<&str> {
+        self.entries.iter()
+            .filter(|symbol| symbol.address == address && symbol.kind == kind)
+            .map(|symbol| symbol.name.as_str())
+            .collect()
+    }
+
+    // `@LOOP` when the next instruction jumps and `@counter` when it uses M,
+    // other A-instructions could be plain constants and keep their number
+    pub fn decode(&self, instruction: u16, next: Option<u16>) -> String {
+        let kind = match next {
+            _ if instruction & 0x8000 != 0 => None,
+            Some(next) if next & 0x8000 != 0 && next & 0b111 != 0 => Some(SymbolKind::Label),
+            Some(next) if next & 0x8000 != 0 && next & 0x1008 != 0 => Some(SymbolKind::Variable),
+            _ => None,
+        };
+        match kind.and_then(|kind| self.names_at(instruction, kind).first().copied()) {
+            Some(name) => format!("@{}", name),
+            None => decode_instruction(instruction),
+        }
+    }
+
+    // `#` starts a comment
+    pub fn parse(contents: &str) -> Result<Self, Vec<Error>> {
+        let mut symbols = Symbols::new();
+        let mut errors = vec![];
+
+        for (i, line) in contents.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(j) => &line[..j],
+                None => line,
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let kind = match fields.get(1) {
+                Some(&"label") => Some(SymbolKind::Label),
+                Some(&"variable") => Some(SymbolKind::Variable),
+                _ => None,
+            };
+
+            match (fields.as_slice(), kind) {
+                ([], _) => {}
+                ([name, _, address], Some(kind)) if address.parse::<u16>().is_ok() => {
+                    symbols.add(name, kind, address.parse().unwrap());
+                }
+                _ => {
+                    let column = line.len() - line.trim_start().len() + 1;
+                    errors.push(Error::new(
+                        ErrorKind::InvalidSymbolEntry(line.trim().to_string()),
+                        Location::new("", i + 1, column),
+                    ));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(symbols)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+impl fmt::Display for Symbols {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for symbol in &self.entries {
+            writeln!(f, "{} {} {}", symbol.name, symbol.kind, symbol.address)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbols() -> Symbols {
+        let mut symbols = Symbols::new();
+        symbols.add("LOOP", SymbolKind::Label, 2);
+        symbols.add("counter", SymbolKind::Variable, 16);
+        symbols.add("END", SymbolKind::Label, 16);
+        symbols
+    }
+
+    #[test]
+    fn test_file_round_trip() {
+        let contents = symbols().to_string();
+        assert_eq!(contents, "LOOP label 2\ncounter variable 16\nEND label 16\n");
+        assert_eq!(Symbols::parse(&contents).unwrap(), symbols());
+        assert_eq!(Symbols::parse("# From Prog.asm\n\n  LOOP label 2  # top\n").unwrap().entries.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let errors = Symbols::parse("LOOP label 2\nLOOP place 2\n  x variable -1\nlonely\n").unwrap_err();
+
+        let positions: Vec<(usize, usize)> = errors.iter()
+            .map(|error| (error.location.line, error.location.column))
+            .collect();
+        assert_eq!(positions, vec![(2, 1), (3, 3), (4, 1)]);
+        assert_eq!(errors[0].kind, ErrorKind::InvalidSymbolEntry("LOOP place 2".to_string()));
+    }
+
+    #[test]
+    fn test_decode_picks_names_by_use() {
+        let symbols = symbols();
+
+        // Followed by a jump, a write to M and a plain D=A
+        assert_eq!(symbols.decode(16, Some(0xEA87)), "@END");
+        assert_eq!(symbols.decode(16, Some(0xEC08)), "@counter");
+        assert_eq!(symbols.decode(16, Some(0xEC10)), "@16");
+        assert_eq!(symbols.decode(2, None), "@2");
+        assert_eq!(symbols.decode(0xEA87, Some(16)), "0;JMP");
+    }
+}
@@ -1,57 +1,229 @@
+use crate::error::error::{column_of, Error, ErrorKind, Location};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VmFile {
+    pub name: String,
+    pub commands: Vec<String>,
+}
+
+impl VmFile {
+    pub fn new(name: &str, commands: Vec<String>) -> Self {
+        VmFile { name: name.to_string(), commands }
+    }
+
+    // Keeps one entry per source line so errors can point back at it
+    pub fn from_source(name: &str, contents: &str) -> Self {
+        let commands = contents
+            .lines()
+            .map(|line| match line.find("//") {
+                Some(i) => &line[..i],
+                None => line,
+            })
+            .map(|line| line.trim_end().to_string())
+            .collect();
+
+        VmFile::new(name, commands)
+    }
+}
+
 pub struct Stack {
     pub assembly: Vec<String>,
     pub commands: Vec<String>,
+    pub file_name: String,
     pub counter_eq: u16,
     pub counter_gt: u16,
     pub counter_lt: u16,
+    pub counter_call: u16,
+    pub current_function: String,
+    // Calls and returns jump to one copy of their code, written after the
+    // translated files. Off by default, for programs linked with the OS
+    pub shared_routines: bool,
 }
 
+const CALL_ROUTINE: &str = "VM$CALL";
+const RETURN_ROUTINE: &str = "VM$RETURN";
+
 impl Stack {
     pub fn new() -> Self {
         Stack {
             assembly: vec![],
             commands: vec![],
+            file_name: "Static".to_string(),
             counter_eq: 0,
             counter_gt: 0,
             counter_lt: 0,
+            counter_call: 0,
+            current_function: String::new(),
+            shared_routines: false,
         }
     }
 
-    pub fn assemble_all(&mut self) {
+    // Translates several files into one program, starting with the bootstrap code
+    pub fn assemble_program(&mut self, files: &[VmFile]) -> Result<(), Vec<Error>> {
+        self.write_bootstrap();
+        self.assemble_files(files)
+    }
+
+    pub fn assemble_files(&mut self, files: &[VmFile]) -> Result<(), Vec<Error>> {
+        let mut errors = vec![];
+        for file in files {
+            self.file_name = file.name.clone();
+            self.current_function.clear();
+            self.commands = file.commands.clone();
+            if let Err(file_errors) = self.assemble_all() {
+                errors.extend(file_errors);
+            }
+        }
+        if self.shared_routines {
+            self.write_shared_routines();
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn write_bootstrap(&mut self) {
+        let asm = vec![
+            "@256".to_string(),
+            "D=A".to_string(),
+            "@SP".to_string(),
+            "M=D".to_string(),
+        ];
+        self.assembly.extend(asm);
+
+        self.write_call("Sys.init", "0")
+            .expect("Bootstrap call is always valid");
+    }
+
+    // One copy of the call and return sequences, so whole programs with an OS
+    // still fit in the ROM. A call site leaves the return address in D, the
+    // offset from SP to the new ARG in R13 and the callee in R14
+    fn write_shared_routines(&mut self) {
+        let asm = vec![
+            format!("({})", CALL_ROUTINE),
+            "@SP".to_string(),
+            "A=M".to_string(),
+            "M=D".to_string(),
+            "@SP".to_string(),
+            "M=M+1".to_string(),
+        ];
+        self.assembly.extend(asm);
+
+        for pointer in ["LCL", "ARG", "THIS", "THAT"] {
+            self.push_pointer(pointer);
+        }
+
+        let asm = vec![
+            // ARG = SP - R13
+            "@SP".to_string(),
+            "D=M".to_string(),
+            "@R13".to_string(),
+            "D=D-M".to_string(),
+            "@ARG".to_string(),
+            "M=D".to_string(),
+
+            // LCL = SP
+            "@SP".to_string(),
+            "D=M".to_string(),
+            "@LCL".to_string(),
+            "M=D".to_string(),
+
+            "@R14".to_string(),
+            "A=M".to_string(),
+            "0;JMP".to_string(),
+
+            format!("({})", RETURN_ROUTINE),
+        ];
+        self.assembly.extend(asm);
+        let asm = return_sequence();
+        self.assembly.extend(asm);
+    }
+
+    // Blank lines and `//` comments are skipped, every bad command is reported
+    pub fn assemble_all(&mut self) -> Result<(), Vec<Error>> {
         let commands = self.commands.clone();
-        for cmd in commands {
+        let file = format!("{}.vm", self.file_name);
+        let mut errors = vec![];
+
+        for (i, raw) in commands.iter().enumerate() {
+            let cmd = match raw.find("//") {
+                Some(j) => &raw[..j],
+                None => raw.as_str(),
+            };
             let parts: Vec<&str> = cmd.split_whitespace().collect();
 
-            match parts.as_slice() {
+            let result = match parts.as_slice() {
+                [] => continue,
                 ["push", segment, index] => self.push_command(segment, index),
                 ["pop", segment, index] => self.pop_command(segment, index),
-                ["add"] => self.add(),
-                ["sub"] => self.sub(),
-                ["neg"] => self.neg(),
-                ["eq"] => self.eq(),
-                ["gt"] => self.gt(),
-                ["lt"] => self.lt(),
-                ["and"] => self.and(),
-                ["or"] => self.or(),
-                ["not"] => self.not(),
-                ["label", label] => self.write_label(label),
-                ["goto", label] => self.write_goto(label),
-                ["if-goto", label] => self.write_if_goto(label),
-                _ => panic!("Invalid command: {:?}", cmd),
+                ["function", name, n_vars] => self.write_function(name, n_vars),
+                ["call", name, n_args] => self.write_call(name, n_args),
+                _ => self.write_simple_command(&parts),
+            };
+
+            if let Err(kind) = result {
+                // Point at the offending token, or the start of the command
+                let token = match &kind {
+                    ErrorKind::InvalidSegment(value) | ErrorKind::InvalidIndex(value) => {
+                        parts.iter().find(|part| *part == value).copied()
+                    }
+                    _ => None,
+                };
+                let column = column_of(raw, token.unwrap_or(parts[0]));
+                errors.push(Error::new(kind, Location::new(&file, i + 1, column)));
             }
         }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    // Commands that cannot fail once they are recognised
+    fn write_simple_command(&mut self, parts: &[&str]) -> Result<(), ErrorKind> {
+        match parts {
+            ["add"] => self.add(),
+            ["sub"] => self.sub(),
+            ["neg"] => self.neg(),
+            ["eq"] => self.eq(),
+            ["gt"] => self.gt(),
+            ["lt"] => self.lt(),
+            ["and"] => self.and(),
+            ["or"] => self.or(),
+            ["not"] => self.not(),
+            ["label", label] => self.write_label(label),
+            ["goto", label] => self.write_goto(label),
+            ["if-goto", label] => self.write_if_goto(label),
+            ["return"] => self.write_return(),
+            _ => return Err(ErrorKind::InvalidCommand(parts.join(" "))),
+        }
+        Ok(())
+    }
+
+    // Labels declared inside a function are scoped as `function$label`
+    fn scoped_label(&self, label: &str) -> String {
+        if self.current_function.is_empty() {
+            label.to_string()
+        } else {
+            format!("{}${}", self.current_function, label)
+        }
     }
 
     pub fn write_label(&mut self, label: &str) {
         let asm = vec![
-            format!("({})", label),
+            format!("({})", self.scoped_label(label)),
         ];
         self.assembly.extend(asm);
     }
 
     pub fn write_goto(&mut self, label: &str) {
         let asm = vec![
-            format!("@{}", label),
+            format!("@{}", self.scoped_label(label)),
             "0;JMP".to_string(),
         ];
         self.assembly.extend(asm);
@@ -63,17 +235,128 @@ impl Stack {
             "M=M-1".to_string(),
             "A=M".to_string(),
             "D=M".to_string(),
-            format!("@{}", label),
+            format!("@{}", self.scoped_label(label)),
             "D;JNE".to_string(),
         ];
         self.assembly.extend(asm);
     }
 
-    pub fn push_command(&mut self, segment: &str, number: &str)  {
-        let index: u16 = number.parse().expect("Expected a number");
+    pub fn write_function(&mut self, name: &str, number: &str) -> Result<(), ErrorKind> {
+        let n_vars = parse_index(number)?;
+        self.current_function = name.to_string();
+
+        let asm = vec![
+            format!("({})", name),
+        ];
+        self.assembly.extend(asm);
+
+        // Locals start out as zero
+        for _ in 0..n_vars {
+            self.push_value(0);
+        }
+        Ok(())
+    }
+
+    pub fn write_call(&mut self, name: &str, number: &str) -> Result<(), ErrorKind> {
+        let n_args = parse_index(number)?;
+        let return_label = self.scoped_label(&format!("ret.{}", self.counter_call));
+        self.counter_call += 1;
+
+        if self.shared_routines {
+            let asm = vec![
+                format!("@{}", 5 + n_args),
+                "D=A".to_string(),
+                "@R13".to_string(),
+                "M=D".to_string(),
+                format!("@{}", name),
+                "D=A".to_string(),
+                "@R14".to_string(),
+                "M=D".to_string(),
+                format!("@{}", return_label),
+                "D=A".to_string(),
+                format!("@{}", CALL_ROUTINE),
+                "0;JMP".to_string(),
+                format!("({})", return_label),
+            ];
+            self.assembly.extend(asm);
+            return Ok(());
+        }
+
+        // ** Push return address
+        let asm = vec![
+            format!("@{}", return_label),
+            "D=A".to_string(),
+            "@SP".to_string(),
+            "A=M".to_string(),
+            "M=D".to_string(),
+            "@SP".to_string(),
+            "M=M+1".to_string(),
+        ];
+        self.assembly.extend(asm);
+
+        // ** Save the caller's frame
+        for pointer in ["LCL", "ARG", "THIS", "THAT"] {
+            self.push_pointer(pointer);
+        }
+
+        let asm = vec![
+            // ARG = SP - 5 - n_args
+            "@SP".to_string(),
+            "D=M".to_string(),
+            format!("@{}", 5 + n_args),
+            "D=D-A".to_string(),
+            "@ARG".to_string(),
+            "M=D".to_string(),
+
+            // LCL = SP
+            "@SP".to_string(),
+            "D=M".to_string(),
+            "@LCL".to_string(),
+            "M=D".to_string(),
+
+            // ** Jump to the callee and come back here
+            format!("@{}", name),
+            "0;JMP".to_string(),
+            format!("({})", return_label),
+        ];
+        self.assembly.extend(asm);
+        Ok(())
+    }
+
+    pub fn write_return(&mut self) {
+        let asm = if self.shared_routines {
+            vec![
+                format!("@{}", RETURN_ROUTINE),
+                "0;JMP".to_string(),
+            ]
+        } else {
+            return_sequence()
+        };
+        self.assembly.extend(asm);
+    }
+
+    fn push_pointer(&mut self, pointer: &str) {
+        let asm = vec![
+            format!("@{}", pointer),
+            "D=M".to_string(),
+            "@SP".to_string(),
+            "A=M".to_string(),
+            "M=D".to_string(),
+            "@SP".to_string(),
+            "M=M+1".to_string(),
+        ];
+        self.assembly.extend(asm);
+    }
+
+    pub fn push_command(&mut self, segment: &str, number: &str) -> Result<(), ErrorKind> {
+        let index = parse_index(number)?;
 
         match segment {
             "constant" => {
+                // Constants have to fit in an A-instruction
+                if index > 0x7FFF {
+                    return Err(ErrorKind::InvalidIndex(number.to_string()));
+                }
                 self.push_value(index);
             }
 
@@ -94,7 +377,7 @@ impl Stack {
             }
 
             "temp" => {
-                let addr = 5 + index;
+                let addr = 5 + temp_index(index, number)?;
                 let asm = vec![
                     format!("@{}", addr),
                     "D=M".to_string(),
@@ -111,7 +394,7 @@ impl Stack {
                 let addr = match index {
                     0 => "THIS",
                     1 => "THAT",
-                    _ => panic!("Invalid pointer index: {}", index),
+                    _ => return Err(ErrorKind::InvalidIndex(number.to_string())),
                 };
                 let asm = vec![
                     format!("@{}", addr),
@@ -126,7 +409,7 @@ impl Stack {
             }
 
             "static" => {
-                let label = format!("Static.{}", index); // later you can prefix with filename
+                let label = format!("{}.{}", self.file_name, index);
                 let asm = vec![
                     format!("@{}", label),
                     "D=M".to_string(),
@@ -139,9 +422,10 @@ impl Stack {
                 self.assembly.extend(asm);
             }
 
-            _ => panic!("Unknown segment: {}", segment),
+            _ => return Err(ErrorKind::InvalidSegment(segment.to_string())),
 
         }
+        Ok(())
     }
 
     pub fn push_from_pointer(&mut self, pointer: &str, index: u16) {
@@ -176,8 +460,8 @@ impl Stack {
         self.assembly.extend(new_commands);
     }
 
-    pub fn pop_command(&mut self, segment: &str, number: &str) {
-        let index: u16 = number.parse().expect("Expected a number");
+    pub fn pop_command(&mut self, segment: &str, number: &str) -> Result<(), ErrorKind> {
+        let index = parse_index(number)?;
 
         match segment {
             "local" => {
@@ -197,7 +481,7 @@ impl Stack {
             }
 
             "temp" => {
-                let addr = 5 + index;
+                let addr = 5 + temp_index(index, number)?;
                 let asm = vec![
                     "@SP".to_string(),
                     "M=M-1".to_string(),
@@ -213,7 +497,7 @@ impl Stack {
                 let addr = match index {
                     0 => "THIS",
                     1 => "THAT",
-                    _ => panic!("Invalid pointer index: {}", index),
+                    _ => return Err(ErrorKind::InvalidIndex(number.to_string())),
                 };
                 let asm = vec![
                     "@SP".to_string(),
@@ -227,7 +511,7 @@ impl Stack {
             }
 
             "static" => {
-                let label = format!("Static.{}", index); // you may prefix with filename
+                let label = format!("{}.{}", self.file_name, index);
                 let asm = vec![
                     "@SP".to_string(),
                     "M=M-1".to_string(),
@@ -239,12 +523,10 @@ impl Stack {
                 self.assembly.extend(asm);
             }
 
-            "constant" => {
-                panic!("Cannot pop to constant segment — it's not a memory region.");
-            }
-
-            _ => panic!("Unknown segment: {}", segment),
+            // Not a memory region, so there is nothing to pop into
+            _ => return Err(ErrorKind::InvalidSegment(segment.to_string())),
         }
+        Ok(())
     }
 
     pub fn pop_to_pointer(&mut self, pointer: &str, index: u16) {
@@ -459,8 +741,8 @@ impl Stack {
 
 
     pub fn lt(&mut self) {
-        let true_label = format!("GT_TRUE_{}", self.counter_lt);
-        let end_label = format!("GT_END_{}", self.counter_lt);
+        let true_label = format!("LT_TRUE_{}", self.counter_lt);
+        let end_label = format!("LT_END_{}", self.counter_lt);
         self.counter_lt += 1;
         self.setup_x_y();
 
@@ -469,7 +751,7 @@ impl Stack {
             // D = Y
             "D=M-D".to_string(),
             format!("@{}", true_label),
-            "D;JLT".to_string(), // If D > 0, jump to true, else go forward
+            "D;JLT".to_string(), // If D < 0, jump to true, else go forward
 
             // ** Push FALSE and Jump to END
             "@SP".to_string(),
@@ -496,10 +778,76 @@ impl Stack {
     }
 }
 
+// Leaves the return value where the arguments started and goes back to the caller
+fn return_sequence() -> Vec<String> {
+    let mut asm = vec![
+        // R13 = FRAME = LCL
+        "@LCL".to_string(),
+        "D=M".to_string(),
+        "@R13".to_string(),
+        "M=D".to_string(),
+
+        // R14 = return address = *(FRAME - 5)
+        "@5".to_string(),
+        "A=D-A".to_string(),
+        "D=M".to_string(),
+        "@R14".to_string(),
+        "M=D".to_string(),
+
+        // *ARG = pop()
+        "@SP".to_string(),
+        "AM=M-1".to_string(),
+        "D=M".to_string(),
+        "@ARG".to_string(),
+        "A=M".to_string(),
+        "M=D".to_string(),
+
+        // SP = ARG + 1
+        "@ARG".to_string(),
+        "D=M+1".to_string(),
+        "@SP".to_string(),
+        "M=D".to_string(),
+    ];
+
+    // ** Restore THAT, THIS, ARG, LCL from *(FRAME - 1) .. *(FRAME - 4)
+    for pointer in ["THAT", "THIS", "ARG", "LCL"] {
+        asm.extend(vec![
+            "@R13".to_string(),
+            "AM=M-1".to_string(),
+            "D=M".to_string(),
+            format!("@{}", pointer),
+            "M=D".to_string(),
+        ]);
+    }
+
+    // ** Jump to the return address
+    asm.extend(vec![
+        "@R14".to_string(),
+        "A=M".to_string(),
+        "0;JMP".to_string(),
+    ]);
+
+    asm
+}
+
+fn parse_index(number: &str) -> Result<u16, ErrorKind> {
+    number.parse().map_err(|_| ErrorKind::InvalidIndex(number.to_string()))
+}
+
+// The temp segment is RAM[5..13]
+fn temp_index(index: u16, number: &str) -> Result<u16, ErrorKind> {
+    if index < 8 {
+        Ok(index)
+    } else {
+        Err(ErrorKind::InvalidIndex(number.to_string()))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::hardware::cpu::Cpu;
+    use crate::hardware::machine::{Machine, RunOptions};
     use crate::parser::assembly::Assembler;
 
     #[test]
@@ -523,10 +871,10 @@ mod tests {
         let mut stack = Stack::new();
         stack.push_value(7);
 
-        asm.assemble_all(&stack.assembly.join("\n"));
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
 
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
 
         assert_eq!(256, cpu.get_data(0));
         for _ in 0..no_of_instructions {
@@ -549,10 +897,10 @@ mod tests {
         stack.push_value(val1);
         stack.push_value(val2);
         stack.add();
-        asm.assemble_all(&stack.assembly.join("\n"));
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
 
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -573,10 +921,10 @@ mod tests {
         stack.push_value(val1);
         stack.push_value(val2);
         stack.sub();
-        asm.assemble_all(&stack.assembly.join("\n"));
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
 
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -597,10 +945,10 @@ mod tests {
         stack.push_value(val1);
         stack.push_value(val2);
         stack.and();
-        asm.assemble_all(&stack.assembly.join("\n"));
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
 
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -621,10 +969,10 @@ mod tests {
         stack.push_value(val1);
         stack.push_value(val2);
         stack.or();
-        asm.assemble_all(&stack.assembly.join("\n"));
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
 
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -643,10 +991,10 @@ mod tests {
 
         stack.push_value(val);
         stack.not();
-        asm.assemble_all(&stack.assembly.join("\n"));
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
 
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -665,10 +1013,10 @@ mod tests {
 
         stack.push_value(val);
         stack.neg();
-        asm.assemble_all(&stack.assembly.join("\n"));
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
 
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -688,10 +1036,10 @@ mod tests {
         stack.push_value(val1);
         stack.push_value(val1);
         stack.eq();
-        asm.assemble_all(&stack.assembly.join("\n"));
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
 
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -712,10 +1060,10 @@ mod tests {
         stack.push_value(val1);
         stack.push_value(val2);
         stack.eq();
-        asm.assemble_all(&stack.assembly.join("\n"));
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
 
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -738,10 +1086,10 @@ mod tests {
         stack.push_value(val);
         stack.push_value(val);
         stack.eq();
-        asm.assemble_all(&stack.assembly.join("\n"));
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
 
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -763,10 +1111,10 @@ mod tests {
         stack.push_value(val1 + 1);
         stack.push_value(val1);
         stack.gt();
-        asm.assemble_all(&stack.assembly.join("\n"));
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
 
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -787,10 +1135,10 @@ mod tests {
         stack.push_value(val1);
         stack.push_value(val2);
         stack.gt();
-        asm.assemble_all(&stack.assembly.join("\n"));
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
 
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -813,10 +1161,10 @@ mod tests {
         stack.push_value(val);
         stack.push_value(val);
         stack.gt();
-        asm.assemble_all(&stack.assembly.join("\n"));
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
 
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -838,10 +1186,10 @@ mod tests {
         stack.push_value(val1 - 1);
         stack.push_value(val1);
         stack.lt();
-        asm.assemble_all(&stack.assembly.join("\n"));
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
 
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -861,10 +1209,10 @@ mod tests {
         stack.push_value(val1 + 1);
         stack.push_value(val1);
         stack.lt();
-        asm.assemble_all(&stack.assembly.join("\n"));
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
 
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -887,10 +1235,10 @@ mod tests {
         stack.push_value(val);
         stack.push_value(val);
         stack.lt();
-        asm.assemble_all(&stack.assembly.join("\n"));
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
 
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -903,7 +1251,7 @@ mod tests {
     #[test]
     fn test_push_constant_assembly() {
         let mut stack = Stack::new();
-        stack.push_command("constant", "7");
+        stack.push_command("constant", "7").unwrap();
 
         let expected = [
             "@7", "D=A", "@SP", "A=M", "M=D", "@SP", "M=M+1"
@@ -918,11 +1266,11 @@ mod tests {
         let mut asm = Assembler::new();
         let mut stack = Stack::new();
 
-        stack.push_command("constant", "7");
-        asm.assemble_all(&stack.assembly.join("\n"));
+        stack.push_command("constant", "7").unwrap();
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
 
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -937,12 +1285,12 @@ mod tests {
         let mut asm = Assembler::new();
         let mut stack = Stack::new();
 
-        stack.push_command("constant", "7");
-        stack.push_command("constant", "15");
-        asm.assemble_all(&stack.assembly.join("\n"));
+        stack.push_command("constant", "7").unwrap();
+        stack.push_command("constant", "15").unwrap();
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
 
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -958,11 +1306,11 @@ mod tests {
         let mut asm = Assembler::new();
         let mut stack = Stack::new();
 
-        stack.push_command("constant", "25");
-        stack.pop_command("local", "0");
-        asm.assemble_all(&stack.assembly.join("\n"));
+        stack.push_command("constant", "25").unwrap();
+        stack.pop_command("local", "0").unwrap();
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -977,13 +1325,13 @@ mod tests {
         let mut asm = Assembler::new();
         let mut stack = Stack::new();
 
-        stack.push_command("constant", "25");
-        stack.push_command("constant", "18");
-        stack.pop_command("local", "1");
-        stack.pop_command("local", "10");
-        asm.assemble_all(&stack.assembly.join("\n"));
+        stack.push_command("constant", "25").unwrap();
+        stack.push_command("constant", "18").unwrap();
+        stack.pop_command("local", "1").unwrap();
+        stack.pop_command("local", "10").unwrap();
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -999,11 +1347,11 @@ mod tests {
         let mut asm = Assembler::new();
         let mut stack = Stack::new();
 
-        stack.push_command("constant", "25");
-        stack.pop_command("argument", "0");
-        asm.assemble_all(&stack.assembly.join("\n"));
+        stack.push_command("constant", "25").unwrap();
+        stack.pop_command("argument", "0").unwrap();
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -1018,13 +1366,13 @@ mod tests {
         let mut asm = Assembler::new();
         let mut stack = Stack::new();
 
-        stack.push_command("constant", "25");
-        stack.push_command("constant", "18");
-        stack.pop_command("argument", "1");
-        stack.pop_command("argument", "10");
-        asm.assemble_all(&stack.assembly.join("\n"));
+        stack.push_command("constant", "25").unwrap();
+        stack.push_command("constant", "18").unwrap();
+        stack.pop_command("argument", "1").unwrap();
+        stack.pop_command("argument", "10").unwrap();
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -1041,11 +1389,11 @@ mod tests {
         let mut asm = Assembler::new();
         let mut stack = Stack::new();
 
-        stack.push_command("constant", "25");
-        stack.pop_command("this", "0");
-        asm.assemble_all(&stack.assembly.join("\n"));
+        stack.push_command("constant", "25").unwrap();
+        stack.pop_command("this", "0").unwrap();
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -1060,13 +1408,13 @@ mod tests {
         let mut asm = Assembler::new();
         let mut stack = Stack::new();
 
-        stack.push_command("constant", "25");
-        stack.push_command("constant", "18");
-        stack.pop_command("this", "1");
-        stack.pop_command("this", "10");
-        asm.assemble_all(&stack.assembly.join("\n"));
+        stack.push_command("constant", "25").unwrap();
+        stack.push_command("constant", "18").unwrap();
+        stack.pop_command("this", "1").unwrap();
+        stack.pop_command("this", "10").unwrap();
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -1083,11 +1431,11 @@ mod tests {
         let mut asm = Assembler::new();
         let mut stack = Stack::new();
 
-        stack.push_command("constant", "25");
-        stack.pop_command("that", "0");
-        asm.assemble_all(&stack.assembly.join("\n"));
+        stack.push_command("constant", "25").unwrap();
+        stack.pop_command("that", "0").unwrap();
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -1102,13 +1450,13 @@ mod tests {
         let mut asm = Assembler::new();
         let mut stack = Stack::new();
 
-        stack.push_command("constant", "25");
-        stack.push_command("constant", "18");
-        stack.pop_command("that", "1");
-        stack.pop_command("that", "10");
-        asm.assemble_all(&stack.assembly.join("\n"));
+        stack.push_command("constant", "25").unwrap();
+        stack.push_command("constant", "18").unwrap();
+        stack.pop_command("that", "1").unwrap();
+        stack.pop_command("that", "10").unwrap();
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -1125,11 +1473,11 @@ mod tests {
         let mut asm = Assembler::new();
         let mut stack = Stack::new();
 
-        stack.push_command("constant", "25");
-        stack.pop_command("temp", "0");
-        asm.assemble_all(&stack.assembly.join("\n"));
+        stack.push_command("constant", "25").unwrap();
+        stack.pop_command("temp", "0").unwrap();
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -1144,13 +1492,13 @@ mod tests {
         let mut asm = Assembler::new();
         let mut stack = Stack::new();
 
-        stack.push_command("constant", "25");
-        stack.push_command("constant", "18");
-        stack.pop_command("temp", "1");
-        stack.pop_command("temp", "3");
-        asm.assemble_all(&stack.assembly.join("\n"));
+        stack.push_command("constant", "25").unwrap();
+        stack.push_command("constant", "18").unwrap();
+        stack.pop_command("temp", "1").unwrap();
+        stack.pop_command("temp", "3").unwrap();
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -1166,13 +1514,13 @@ mod tests {
         let mut asm = Assembler::new();
         let mut stack = Stack::new();
 
-        stack.push_command("constant", "25");
-        stack.push_command("constant", "15");
-        stack.pop_command("pointer", "0");
-        stack.pop_command("pointer", "1");
-        asm.assemble_all(&stack.assembly.join("\n"));
+        stack.push_command("constant", "25").unwrap();
+        stack.push_command("constant", "15").unwrap();
+        stack.pop_command("pointer", "0").unwrap();
+        stack.pop_command("pointer", "1").unwrap();
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -1189,14 +1537,14 @@ mod tests {
         let mut stack = Stack::new();
 
         // Push 42 and 99 onto the stack, then pop into Static.0 and Static.2
-        stack.push_command("constant", "42");
-        stack.push_command("constant", "99");
-        stack.pop_command("static", "0");  // Static.0 = 99
-        stack.pop_command("static", "2");  // Static.2 = 42
+        stack.push_command("constant", "42").unwrap();
+        stack.push_command("constant", "99").unwrap();
+        stack.pop_command("static", "0").unwrap();  // Static.0 = 99
+        stack.pop_command("static", "2").unwrap();  // Static.2 = 42
 
-        asm.assemble_all(&stack.assembly.join("\n"));
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -1214,37 +1562,37 @@ mod tests {
         let mut asm = Assembler::new();
         let mut stack = Stack::new();
 
-        stack.push_command("constant", "1");
-        stack.pop_command("local", "0");
-        stack.push_command("local", "0");
+        stack.push_command("constant", "1").unwrap();
+        stack.pop_command("local", "0").unwrap();
+        stack.push_command("local", "0").unwrap();
 
-        stack.push_command("constant", "2");
-        stack.pop_command("argument", "0");
-        stack.push_command("argument", "0");
+        stack.push_command("constant", "2").unwrap();
+        stack.pop_command("argument", "0").unwrap();
+        stack.push_command("argument", "0").unwrap();
 
-        stack.push_command("constant", "3");
-        stack.pop_command("this", "0");
-        stack.push_command("this", "0");
+        stack.push_command("constant", "3").unwrap();
+        stack.pop_command("this", "0").unwrap();
+        stack.push_command("this", "0").unwrap();
 
-        stack.push_command("constant", "4");
-        stack.pop_command("that", "0");
-        stack.push_command("that", "0");
+        stack.push_command("constant", "4").unwrap();
+        stack.pop_command("that", "0").unwrap();
+        stack.push_command("that", "0").unwrap();
 
-        stack.push_command("constant", "5");
-        stack.pop_command("temp", "0");
-        stack.push_command("temp", "0");
+        stack.push_command("constant", "5").unwrap();
+        stack.pop_command("temp", "0").unwrap();
+        stack.push_command("temp", "0").unwrap();
 
-        stack.push_command("constant", "6");
-        stack.pop_command("pointer", "0");
-        stack.push_command("pointer", "0");
+        stack.push_command("constant", "6").unwrap();
+        stack.pop_command("pointer", "0").unwrap();
+        stack.push_command("pointer", "0").unwrap();
 
-        stack.push_command("constant", "7");
-        stack.pop_command("static", "7");
-        stack.push_command("static", "7");
+        stack.push_command("constant", "7").unwrap();
+        stack.pop_command("static", "7").unwrap();
+        stack.push_command("static", "7").unwrap();
 
-        asm.assemble_all(&stack.assembly.join("\n"));
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
         let no_of_instructions = asm.binaries.len();
-        cpu.load_from_string(&asm.binaries.join("\n"));
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
         for _ in 0..no_of_instructions {
             cpu.clock();
         }
@@ -1269,13 +1617,13 @@ mod tests {
         let mut stack = Stack::new();
 
         stack.write_goto("SKIP");
-        stack.push_command("constant", "999");
+        stack.push_command("constant", "999").unwrap();
         stack.write_label("SKIP");
-        stack.push_command("constant", "42");
+        stack.push_command("constant", "42").unwrap();
 
-        asm.assemble_all(&stack.assembly.join("\n"));
-        cpu.load_from_string(&asm.binaries.join("\n"));
-        cpu.run();
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
+        cpu.run(&RunOptions::new());
 
         assert_eq!(257, cpu.get_data(0));
         assert_eq!(42, cpu.get_data(256));
@@ -1294,10 +1642,10 @@ mod tests {
             "push constant 42".into(),
         ];
 
-        stack.assemble_all();
-        asm.assemble_all(&stack.assembly.join("\n"));
-        cpu.load_from_string(&asm.binaries.join("\n"));
-        cpu.run();
+        stack.assemble_all().unwrap();
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
+        cpu.run(&RunOptions::new());
 
         assert_eq!(257, cpu.get_data(0));
         assert_eq!(42, cpu.get_data(256));
@@ -1309,16 +1657,16 @@ mod tests {
         let mut asm = Assembler::new();
         let mut stack = Stack::new();
 
-        stack.push_command("constant", "1");
+        stack.push_command("constant", "1").unwrap();
         stack.write_if_goto("SHOULD_JUMP");
-        stack.push_command("constant", "999");
+        stack.push_command("constant", "999").unwrap();
         stack.write_label("SHOULD_JUMP");
-        stack.push_command("constant", "42");
+        stack.push_command("constant", "42").unwrap();
         
 
-        asm.assemble_all(&stack.assembly.join("\n"));
-        cpu.load_from_string(&asm.binaries.join("\n"));
-        cpu.run();
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
+        cpu.run(&RunOptions::new());
 
         assert_eq!(257, cpu.get_data(0));
         assert_eq!(42, cpu.get_data(256));
@@ -1338,14 +1686,356 @@ mod tests {
             "push constant 42".into(),
         ];
 
-        stack.assemble_all();
-        asm.assemble_all(&stack.assembly.join("\n"));
-        cpu.load_from_string(&asm.binaries.join("\n"));
-        cpu.run();
+        stack.assemble_all().unwrap();
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
+        cpu.run(&RunOptions::new());
 
         assert_eq!(257, cpu.get_data(0));
         assert_eq!(42, cpu.get_data(256));
 
     }
 
+    fn run_to_halt(stack: &mut Stack) -> Cpu {
+        let mut cpu = Cpu::new();
+        let mut asm = Assembler::new();
+
+        stack.assemble_all().unwrap();
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
+        cpu.run(&RunOptions::new());
+        cpu
+    }
+
+    // Runs the translated program until the PC reaches `label`
+    fn run_to_label(stack: &mut Stack, label: &str) -> Cpu {
+        let mut cpu = Cpu::new();
+        let mut asm = Assembler::new();
+
+        stack.assemble_all().unwrap();
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
+
+        let end = asm.symbol_table.get_address(label).unwrap();
+        while cpu.get_pc() != end {
+            assert!(cpu.clock(), "Program halted before reaching {}", label);
+        }
+        cpu
+    }
+
+    #[test]
+    fn test_stack_call_simple() {
+        let mut stack = Stack::new();
+
+        stack.commands = vec![
+            "push constant 3".into(),
+            "push constant 4".into(),
+            "call Math.add 2".into(),
+            "label END".into(),
+            "goto END".into(),
+            "function Math.add 0".into(),
+            "push argument 0".into(),
+            "push argument 1".into(),
+            "add".into(),
+            "return".into(),
+        ];
+
+        let cpu = run_to_label(&mut stack, "END");
+
+        assert_eq!(257, cpu.get_data(0));
+        assert_eq!(7, cpu.get_data(256));
+        assert_eq!(300, cpu.get_data(1));
+        assert_eq!(400, cpu.get_data(2));
+        assert_eq!(3000, cpu.get_data(3));
+        assert_eq!(3010, cpu.get_data(4));
+    }
+
+    #[test]
+    fn test_stack_function_zeroes_locals() {
+        let mut stack = Stack::new();
+
+        // Leave garbage on the stack where the callee's locals will live
+        stack.commands = vec![
+            "push constant 99".into(),
+            "push constant 99".into(),
+            "push constant 99".into(),
+            "push constant 99".into(),
+            "push constant 99".into(),
+            "push constant 99".into(),
+            "push constant 99".into(),
+            "pop temp 0".into(),
+            "pop temp 0".into(),
+            "pop temp 0".into(),
+            "pop temp 0".into(),
+            "pop temp 0".into(),
+            "pop temp 0".into(),
+            "pop temp 0".into(),
+            "call Main.locals 0".into(),
+            "label END".into(),
+            "goto END".into(),
+            "function Main.locals 2".into(),
+            "push local 0".into(),
+            "push local 1".into(),
+            "or".into(),
+            "return".into(),
+        ];
+
+        let cpu = run_to_label(&mut stack, "END");
+
+        assert_eq!(257, cpu.get_data(0));
+        assert_eq!(0, cpu.get_data(256));
+    }
+
+    #[test]
+    fn test_stack_call_nested() {
+        let mut stack = Stack::new();
+
+        stack.commands = vec![
+            "push constant 5".into(),
+            "call Main.quadruple 1".into(),
+            "push constant 1".into(),
+            "add".into(),
+            "label END".into(),
+            "goto END".into(),
+
+            "function Main.quadruple 1".into(),
+            "push argument 0".into(),
+            "call Main.double 1".into(),
+            "pop local 0".into(),
+            "push local 0".into(),
+            "call Main.double 1".into(),
+            "return".into(),
+
+            "function Main.double 0".into(),
+            "push argument 0".into(),
+            "push argument 0".into(),
+            "add".into(),
+            "return".into(),
+        ];
+
+        let cpu = run_to_label(&mut stack, "END");
+
+        assert_eq!(257, cpu.get_data(0));
+        assert_eq!(21, cpu.get_data(256));
+        assert_eq!(300, cpu.get_data(1));
+        assert_eq!(400, cpu.get_data(2));
+    }
+
+    #[test]
+    fn test_stack_call_recursive_fibonacci() {
+        let mut stack = Stack::new();
+
+        stack.commands = vec![
+            "push constant 7".into(),
+            "call Main.fibonacci 1".into(),
+            "label END".into(),
+            "goto END".into(),
+
+            "function Main.fibonacci 0".into(),
+            "push argument 0".into(),
+            "push constant 2".into(),
+            "lt".into(),
+            "if-goto IF_TRUE".into(),
+            "goto IF_FALSE".into(),
+            "label IF_TRUE".into(),
+            "push argument 0".into(),
+            "return".into(),
+            "label IF_FALSE".into(),
+            "push argument 0".into(),
+            "push constant 2".into(),
+            "sub".into(),
+            "call Main.fibonacci 1".into(),
+            "push argument 0".into(),
+            "push constant 1".into(),
+            "sub".into(),
+            "call Main.fibonacci 1".into(),
+            "add".into(),
+            "return".into(),
+        ];
+
+        let cpu = run_to_label(&mut stack, "END");
+
+        assert_eq!(257, cpu.get_data(0));
+        assert_eq!(13, cpu.get_data(256));
+        assert_eq!(300, cpu.get_data(1));
+        assert_eq!(400, cpu.get_data(2));
+    }
+
+    #[test]
+    fn test_stack_function_labels_are_scoped() {
+        let mut stack = Stack::new();
+
+        stack.commands = vec![
+            "function Main.main 0".into(),
+            "label LOOP".into(),
+            "goto LOOP".into(),
+            "call Main.main 0".into(),
+        ];
+        stack.assemble_all().unwrap();
+
+        assert!(stack.assembly.contains(&"(Main.main$LOOP)".to_string()));
+        assert!(stack.assembly.contains(&"@Main.main$LOOP".to_string()));
+        assert!(stack.assembly.contains(&"(Main.main$ret.0)".to_string()));
+    }
+
+    #[test]
+    fn test_stack_gt_and_lt_labels_distinct() {
+        let mut stack = Stack::new();
+
+        stack.commands = vec![
+            "push constant 2".into(),
+            "push constant 1".into(),
+            "gt".into(),
+            "push constant 2".into(),
+            "push constant 1".into(),
+            "lt".into(),
+        ];
+
+        let cpu = run_to_halt(&mut stack);
+
+        assert_eq!(258, cpu.get_data(0));
+        assert_eq!(0xFFFF, cpu.get_data(256));
+        assert_eq!(0x0000, cpu.get_data(257));
+    }
+
+    #[test]
+    fn test_vm_file_from_source() {
+        let source = "\
+// Adds two numbers
+push constant 1   // first
+
+push constant 2
+add
+";
+        let file = VmFile::from_source("Main", source);
+
+        assert_eq!(file.name, "Main");
+        assert_eq!(file.commands, vec!["", "push constant 1", "", "push constant 2", "add"]);
+    }
+
+    #[test]
+    fn test_stack_reports_every_error() {
+        let mut stack = Stack::new();
+        stack.file_name = "Main".to_string();
+
+        stack.commands = vec![
+            "push constant 1".into(),
+            "  pop constant 0".into(),
+            "push local x".into(),
+            "push temp 8".into(),
+            "pop pointer 2   // only 0 and 1".into(),
+            "jump around".into(),
+            "push constant 32768".into(),
+        ];
+
+        let errors = stack.assemble_all().unwrap_err();
+        let expected = vec![
+            Error::new(ErrorKind::InvalidSegment("constant".into()), Location::new("Main.vm", 2, 7)),
+            Error::new(ErrorKind::InvalidIndex("x".into()), Location::new("Main.vm", 3, 12)),
+            Error::new(ErrorKind::InvalidIndex("8".into()), Location::new("Main.vm", 4, 11)),
+            Error::new(ErrorKind::InvalidIndex("2".into()), Location::new("Main.vm", 5, 13)),
+            Error::new(ErrorKind::InvalidCommand("jump around".into()), Location::new("Main.vm", 6, 1)),
+            Error::new(ErrorKind::InvalidIndex("32768".into()), Location::new("Main.vm", 7, 15)),
+        ];
+        assert_eq!(errors, expected);
+    }
+
+    #[test]
+    fn test_stack_program_bootstrap_and_statics() {
+        let mut cpu = Cpu::new();
+        let mut asm = Assembler::new();
+        let mut stack = Stack::new();
+
+        let sys = VmFile::from_source("Sys", "\
+function Sys.init 0
+push constant 11
+pop static 0
+call Main.main 0
+pop temp 0
+label END
+goto END
+");
+        let main = VmFile::from_source("Main", "\
+function Main.main 0
+push constant 22
+pop static 0
+push static 0
+return
+");
+
+        stack.assemble_program(&[sys, main]).unwrap();
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
+
+        let end = asm.symbol_table.get_address("Sys.init$END").unwrap();
+        while cpu.get_pc() != end {
+            assert!(cpu.clock());
+        }
+
+        let sys_static = asm.symbol_table.get_address("Sys.0").unwrap() as usize;
+        let main_static = asm.symbol_table.get_address("Main.0").unwrap() as usize;
+        assert_ne!(sys_static, main_static);
+        assert_eq!(11, cpu.get_data(sys_static));
+        assert_eq!(22, cpu.get_data(main_static));
+        assert_eq!(22, cpu.get_data(5));
+
+        // Sys.init's frame sits right above the bootstrap's saved frame
+        assert_eq!(261, cpu.get_data(0));
+        assert_eq!(261, cpu.get_data(1));
+    }
+
+    #[test]
+    fn test_stack_program_shares_call_and_return() {
+        let mut cpu = Cpu::new();
+        let mut asm = Assembler::new();
+        let mut stack = Stack::new();
+
+        let sys = VmFile::from_source("Sys", "\
+function Sys.init 0
+push constant 7
+call Sys.fibonacci 1
+pop static 0
+label END
+goto END
+function Sys.fibonacci 0
+push argument 0
+push constant 2
+lt
+if-goto BASE
+push argument 0
+push constant 2
+sub
+call Sys.fibonacci 1
+push argument 0
+push constant 1
+sub
+call Sys.fibonacci 1
+add
+return
+label BASE
+push argument 0
+return
+");
+
+        // Only when asked for, so other programs translate as before
+        let mut plain = Stack::new();
+        plain.assemble_program(std::slice::from_ref(&sys)).unwrap();
+        assert!(!plain.assembly.iter().any(|line| line.starts_with("(VM$")));
+
+        stack.shared_routines = true;
+        stack.assemble_program(&[sys]).unwrap();
+        let routines = stack.assembly.iter().filter(|line| line.starts_with("(VM$")).count();
+        assert_eq!(routines, 2);
+        assert_eq!(stack.assembly.iter().filter(|line| *line == "@VM$RETURN").count(), 2);
+
+        asm.assemble_all(&stack.assembly.join("\n")).unwrap();
+        cpu.load_from_string(&asm.binaries.join("\n")).unwrap();
+        let end = asm.symbol_table.get_address("Sys.init$END").unwrap();
+        while cpu.get_pc() != end {
+            assert!(cpu.clock());
+        }
+
+        assert_eq!(13, cpu.get_data(asm.symbol_table.get_address("Sys.0").unwrap() as usize));
+        assert_eq!(261, cpu.get_data(0));
+    }
 }